@@ -0,0 +1,164 @@
+use super::sdt::{SdtHeader, GenericAddress};
+
+pub const FADT_SIGNATURE: [u8; 4] = *b"FACP";
+
+pub const PM1_CNT_SCI_EN: u16 = 0x1;
+pub const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+pub const PM1_CNT_SLP_EN: u16 = 0x2000;
+
+pub const FADT_FLAGS_RESET_REG_SUP: u32 = 0x400;
+
+// fixed ACPI description table
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct Fadt
+{
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt: u32,
+    reserved0: u8,
+    preferred_pm_profile: u8,
+    sci_int: u16,
+    smi_cmd: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_cnt: u8,
+    pm1a_evt_blk: u32,
+    pm1b_evt_blk: u32,
+    pm1a_cnt_blk: u32,
+    pm1b_cnt_blk: u32,
+    pm2_cnt_blk: u32,
+    pm_tmr_blk: u32,
+    gpe0_blk: u32,
+    gpe1_blk: u32,
+    pm1_evt_len: u8,
+    pm1_cnt_len: u8,
+    pm2_cnt_len: u8,
+    pm_tmr_len: u8,
+    gpe0_blk_len: u8,
+    gpe1_blk_len: u8,
+    gpe1_base: u8,
+    cst_cnt: u8,
+    p_lvl2_lat: u16,
+    p_lvl3_lat: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alrm: u8,
+    mon_alrm: u8,
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved1: u8,
+    flags: u32,
+    // ACPI 2.0+
+    reset_reg: GenericAddress,
+    reset_value: u8,
+    arm_boot_arch: u16,
+    fadt_minor_version: u8,
+    x_firmware_ctrl: u64,
+    x_dsdt: u64,
+    x_pm1a_evt_blk: GenericAddress,
+    x_pm1b_evt_blk: GenericAddress,
+    x_pm1a_cnt_blk: GenericAddress,
+    x_pm1b_cnt_blk: GenericAddress,
+    x_pm2_cnt_blk: GenericAddress,
+    x_pm_tmr_blk: GenericAddress,
+    x_gpe0_blk: GenericAddress,
+    x_gpe1_blk: GenericAddress,
+    sleep_ctrl_reg: GenericAddress,
+    sleep_status_reg: GenericAddress,
+    hypervisor_vendor_id: u64
+}
+
+impl Fadt
+{
+    pub fn get_dsdt_addr(&self) -> u64
+    {
+        let x_dsdt = self.x_dsdt;
+
+        if x_dsdt != 0
+        {
+            return x_dsdt;
+        }
+
+        return self.dsdt as u64;
+    }
+
+    pub fn get_sci_int(&self) -> u16
+    {
+        return self.sci_int;
+    }
+
+    pub fn get_smi_cmd_port(&self) -> u32
+    {
+        return self.smi_cmd;
+    }
+
+    pub fn get_acpi_enable(&self) -> u8
+    {
+        return self.acpi_enable;
+    }
+
+    pub fn get_acpi_disable(&self) -> u8
+    {
+        return self.acpi_disable;
+    }
+
+    pub fn get_pm1a_cnt_port(&self) -> u32
+    {
+        let x_pm1a_cnt_blk = self.x_pm1a_cnt_blk;
+
+        if x_pm1a_cnt_blk.is_system_io() && x_pm1a_cnt_blk.get_addr() != 0
+        {
+            return x_pm1a_cnt_blk.get_addr() as u32;
+        }
+
+        return self.pm1a_cnt_blk;
+    }
+
+    pub fn get_pm1b_cnt_port(&self) -> u32
+    {
+        let x_pm1b_cnt_blk = self.x_pm1b_cnt_blk;
+
+        if x_pm1b_cnt_blk.is_system_io() && x_pm1b_cnt_blk.get_addr() != 0
+        {
+            return x_pm1b_cnt_blk.get_addr() as u32;
+        }
+
+        return self.pm1b_cnt_blk;
+    }
+
+    pub fn get_pm_tmr_port(&self) -> u32
+    {
+        return self.pm_tmr_blk;
+    }
+
+    pub fn get_century_reg(&self) -> u8
+    {
+        return self.century;
+    }
+
+    pub fn get_iapc_boot_arch(&self) -> u16
+    {
+        return self.iapc_boot_arch;
+    }
+
+    pub fn get_flags(&self) -> u32
+    {
+        return self.flags;
+    }
+
+    pub fn get_reset_reg(&self) -> Option<(GenericAddress, u8)>
+    {
+        let header = self.header;
+
+        if header.get_revision() < 2 || self.flags & FADT_FLAGS_RESET_REG_SUP == 0
+        {
+            return None;
+        }
+
+        return Some((self.reset_reg, self.reset_value));
+    }
+}
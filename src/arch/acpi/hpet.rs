@@ -0,0 +1,45 @@
+use super::sdt::{SdtHeader, GenericAddress};
+
+pub const HPET_SIGNATURE: [u8; 4] = *b"HPET";
+
+// high precision event timer description table
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct Hpet
+{
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_addr: GenericAddress,
+    hpet_num: u8,
+    min_clock_tick: u16,
+    page_protection: u8
+}
+
+impl Hpet
+{
+    pub fn get_base_addr(&self) -> u64
+    {
+        let base_addr = self.base_addr;
+        return base_addr.get_addr();
+    }
+
+    pub fn get_hpet_num(&self) -> u8
+    {
+        return self.hpet_num;
+    }
+
+    pub fn get_min_clock_tick(&self) -> u16
+    {
+        return self.min_clock_tick;
+    }
+
+    pub fn get_comparators_cnt(&self) -> u8
+    {
+        return ((self.event_timer_block_id >> 8) & 0x1f) as u8 + 1;
+    }
+
+    pub fn get_vendor_id(&self) -> u16
+    {
+        return (self.event_timer_block_id >> 16) as u16;
+    }
+}
@@ -0,0 +1,187 @@
+use core::ptr::read_unaligned;
+
+use alloc::vec::Vec;
+
+use super::sdt::{SdtHeader, SDT_HEADER_SIZE};
+
+pub const MADT_SIGNATURE: [u8; 4] = *b"APIC";
+
+pub const MADT_FLAGS_PCAT_COMPAT: u32 = 0x1;
+pub const MADT_LAPIC_FLAGS_ENABLED: u32 = 0x1;
+pub const MADT_LAPIC_FLAGS_ONLINE_CAPABLE: u32 = 0x2;
+
+// MPS INTI flags
+pub const MADT_INTI_POLARITY_MASK: u16 = 0x3;
+pub const MADT_INTI_POLARITY_ACTIVE_LOW: u16 = 0x3;
+pub const MADT_INTI_TRIGGER_MASK: u16 = 0xc;
+pub const MADT_INTI_TRIGGER_LEVEL: u16 = 0xc;
+
+const ENTRY_TYPE_LOCAL_APIC: u8 = 0;
+const ENTRY_TYPE_IO_APIC: u8 = 1;
+const ENTRY_TYPE_INT_SRC_OVERRIDE: u8 = 2;
+const ENTRY_TYPE_NMI_SRC: u8 = 3;
+const ENTRY_TYPE_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_TYPE_LOCAL_APIC_ADDR_OVERRIDE: u8 = 5;
+const ENTRY_TYPE_LOCAL_X2APIC: u8 = 9;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MadtEntry
+{
+    LocalApic { acpi_processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { io_apic_id: u8, io_apic_addr: u32, gsi_base: u32 },
+    IntSrcOverride { bus: u8, src: u8, gsi: u32, flags: u16 },
+    NmiSrc { flags: u16, gsi: u32 },
+    LocalApicNmi { acpi_processor_id: u8, flags: u16, lint: u8 },
+    LocalApicAddrOverride { local_apic_addr: u64 },
+    LocalX2Apic { x2apic_id: u32, flags: u32, acpi_processor_uid: u32 },
+    Unknown { entry_type: u8 }
+}
+
+// multiple APIC description table
+#[derive(Debug)]
+pub struct Madt
+{
+    pub local_apic_addr: u64,
+    pub flags: u32,
+    pub entries: Vec<MadtEntry>
+}
+
+impl Madt
+{
+    pub fn read(base_addr: u32) -> Madt
+    {
+        let header = SdtHeader::read(base_addr);
+
+        if header.get_length() < SDT_HEADER_SIZE as u32 + 8
+        {
+            return Madt { local_apic_addr: 0, flags: 0, entries: Vec::new() };
+        }
+
+        let mut local_apic_addr = read_u32(base_addr, SDT_HEADER_SIZE as u32) as u64;
+        let flags = read_u32(base_addr, SDT_HEADER_SIZE as u32 + 4);
+        let mut entries = Vec::new();
+
+        let mut offset = SDT_HEADER_SIZE as u32 + 8;
+
+        while offset + 2 <= header.get_length()
+        {
+            let entry_type = read_u8(base_addr, offset);
+            let entry_len = read_u8(base_addr, offset + 1) as u32;
+
+            // entry must be in the table
+            if entry_len < 2 || offset + entry_len > header.get_length()
+            {
+                break;
+            }
+
+            if entry_len < get_min_entry_len(entry_type)
+            {
+                offset += entry_len;
+                continue;
+            }
+
+            let entry = match entry_type
+            {
+                ENTRY_TYPE_LOCAL_APIC => MadtEntry::LocalApic
+                {
+                    acpi_processor_id: read_u8(base_addr, offset + 2),
+                    apic_id: read_u8(base_addr, offset + 3),
+                    flags: read_u32(base_addr, offset + 4)
+                },
+                ENTRY_TYPE_IO_APIC => MadtEntry::IoApic
+                {
+                    io_apic_id: read_u8(base_addr, offset + 2),
+                    io_apic_addr: read_u32(base_addr, offset + 4),
+                    gsi_base: read_u32(base_addr, offset + 8)
+                },
+                ENTRY_TYPE_INT_SRC_OVERRIDE => MadtEntry::IntSrcOverride
+                {
+                    bus: read_u8(base_addr, offset + 2),
+                    src: read_u8(base_addr, offset + 3),
+                    gsi: read_u32(base_addr, offset + 4),
+                    flags: read_u16(base_addr, offset + 8)
+                },
+                ENTRY_TYPE_NMI_SRC => MadtEntry::NmiSrc
+                {
+                    flags: read_u16(base_addr, offset + 2),
+                    gsi: read_u32(base_addr, offset + 4)
+                },
+                ENTRY_TYPE_LOCAL_APIC_NMI => MadtEntry::LocalApicNmi
+                {
+                    acpi_processor_id: read_u8(base_addr, offset + 2),
+                    flags: read_u16(base_addr, offset + 3),
+                    lint: read_u8(base_addr, offset + 5)
+                },
+                ENTRY_TYPE_LOCAL_APIC_ADDR_OVERRIDE =>
+                {
+                    let addr = read_u32(base_addr, offset + 4) as u64 | (read_u32(base_addr, offset + 8) as u64) << 32;
+                    local_apic_addr = addr;
+                    MadtEntry::LocalApicAddrOverride { local_apic_addr: addr }
+                },
+                ENTRY_TYPE_LOCAL_X2APIC => MadtEntry::LocalX2Apic
+                {
+                    x2apic_id: read_u32(base_addr, offset + 4),
+                    flags: read_u32(base_addr, offset + 8),
+                    acpi_processor_uid: read_u32(base_addr, offset + 12)
+                },
+                _ => MadtEntry::Unknown { entry_type }
+            };
+
+            entries.push(entry);
+            offset += entry_len;
+        }
+
+        return Madt { local_apic_addr, flags, entries };
+    }
+
+    pub fn has_legacy_pic(&self) -> bool
+    {
+        return self.flags & MADT_FLAGS_PCAT_COMPAT != 0;
+    }
+
+    pub fn get_local_apics(&self) -> impl Iterator<Item = &MadtEntry>
+    {
+        return self.entries.iter().filter(|e| matches!(e, MadtEntry::LocalApic { .. }));
+    }
+
+    pub fn get_io_apics(&self) -> impl Iterator<Item = &MadtEntry>
+    {
+        return self.entries.iter().filter(|e| matches!(e, MadtEntry::IoApic { .. }));
+    }
+
+    pub fn get_int_src_overrides(&self) -> impl Iterator<Item = &MadtEntry>
+    {
+        return self.entries.iter().filter(|e| matches!(e, MadtEntry::IntSrcOverride { .. }));
+    }
+}
+
+// fixed fields read by Madt::read
+fn get_min_entry_len(entry_type: u8) -> u32
+{
+    match entry_type
+    {
+        ENTRY_TYPE_LOCAL_APIC => return 8,
+        ENTRY_TYPE_IO_APIC => return 12,
+        ENTRY_TYPE_INT_SRC_OVERRIDE => return 10,
+        ENTRY_TYPE_NMI_SRC => return 8,
+        ENTRY_TYPE_LOCAL_APIC_NMI => return 6,
+        ENTRY_TYPE_LOCAL_APIC_ADDR_OVERRIDE => return 12,
+        ENTRY_TYPE_LOCAL_X2APIC => return 16,
+        _ => return 2
+    }
+}
+
+fn read_u8(base_addr: u32, offset: u32) -> u8
+{
+    return unsafe { read_unaligned((base_addr + offset) as *const u8) };
+}
+
+fn read_u16(base_addr: u32, offset: u32) -> u16
+{
+    return unsafe { read_unaligned((base_addr + offset) as *const u16) };
+}
+
+fn read_u32(base_addr: u32, offset: u32) -> u32
+{
+    return unsafe { read_unaligned((base_addr + offset) as *const u32) };
+}
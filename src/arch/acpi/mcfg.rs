@@ -0,0 +1,59 @@
+use core::ptr::read_unaligned;
+
+use alloc::vec::Vec;
+
+use super::sdt::{SdtHeader, SDT_HEADER_SIZE};
+
+pub const MCFG_SIGNATURE: [u8; 4] = *b"MCFG";
+
+// PCI express memory mapped configuration space base address allocation
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct McfgEntry
+{
+    base_addr: u64,
+    pci_segment_group: u16,
+    start_bus_num: u8,
+    end_bus_num: u8,
+    reserved: u32
+}
+
+impl McfgEntry
+{
+    pub fn get_base_addr(&self) -> u64
+    {
+        return self.base_addr;
+    }
+
+    pub fn get_pci_segment_group(&self) -> u16
+    {
+        return self.pci_segment_group;
+    }
+
+    pub fn get_start_bus_num(&self) -> u8
+    {
+        return self.start_bus_num;
+    }
+
+    pub fn get_end_bus_num(&self) -> u8
+    {
+        return self.end_bus_num;
+    }
+}
+
+// entries start after 8 reserved bytes
+pub fn read_mcfg_entries(base_addr: u32) -> Vec<McfgEntry>
+{
+    let header = SdtHeader::read(base_addr);
+    let mut entries = Vec::new();
+    let mut offset = SDT_HEADER_SIZE as u32 + 8;
+
+    while offset + core::mem::size_of::<McfgEntry>() as u32 <= header.get_length()
+    {
+        let entry = unsafe { read_unaligned((base_addr + offset) as *const McfgEntry) };
+        entries.push(entry);
+        offset += core::mem::size_of::<McfgEntry>() as u32;
+    }
+
+    return entries;
+}
@@ -0,0 +1,402 @@
+// advanced configuration and power interface
+
+use core::{mem::size_of, ptr::read_unaligned};
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use multiboot2::BootInformation;
+use spin::Mutex;
+
+use crate::{mem::phys_to_virt, println, util::{boot_info, logger::*}};
+
+use self::{fadt::{Fadt, FADT_SIGNATURE}, hpet::{Hpet, HPET_SIGNATURE}, madt::{Madt, MadtEntry, MADT_SIGNATURE}, mcfg::{McfgEntry, MCFG_SIGNATURE}, sdt::SdtHeader};
+
+pub mod sdt;
+pub mod fadt;
+pub mod madt;
+pub mod mcfg;
+pub mod hpet;
+
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+const RSDP_V1_SIZE: u32 = 20;
+const RSDT_SIGNATURE: [u8; 4] = *b"RSDT";
+const XSDT_SIGNATURE: [u8; 4] = *b"XSDT";
+
+// BIOS data area, segment of extended BIOS data area
+const BDA_EBDA_SEGMENT_ADDR: u32 = 0x40e;
+const EBDA_SEARCH_SIZE: u32 = 0x400;
+const BIOS_AREA_START_ADDR: u32 = 0xe0000;
+const BIOS_AREA_END_ADDR: u32 = 0x100000;
+const RSDP_ALIGN: u32 = 16;
+
+lazy_static!
+{
+    pub static ref ACPI: Mutex<Acpi> = Mutex::new(Acpi::new());
+}
+
+// root system description pointer
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp
+{
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_addr: u32,
+    // revision 2 or later
+    length: u32,
+    xsdt_addr: u64,
+    ext_checksum: u8,
+    reserved: [u8; 3]
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiTable
+{
+    pub header: SdtHeader,
+    pub addr: u32
+}
+
+#[derive(Debug)]
+pub struct Acpi
+{
+    is_init: bool,
+    rsdp_addr: Option<u32>,
+    root_sdt_addr: u32,
+    is_xsdt: bool,
+    tables: Vec<AcpiTable>,
+    fadt: Option<Fadt>,
+    madt: Option<Madt>,
+    mcfg_entries: Vec<McfgEntry>,
+    hpet: Option<Hpet>
+}
+
+impl Acpi
+{
+    pub fn new() -> Acpi
+    {
+        return Acpi
+        {
+            is_init: false,
+            rsdp_addr: None,
+            root_sdt_addr: 0,
+            is_xsdt: false,
+            tables: Vec::new(),
+            fadt: None,
+            madt: None,
+            mcfg_entries: Vec::new(),
+            hpet: None
+        };
+    }
+
+    pub fn init(&mut self, boot_info: &BootInformation)
+    {
+        // prefer tags passed by multiboot2 loader
+        if let Some(xsdt_addr) = boot_info::get_xsdt_addr(boot_info)
+        {
+            if xsdt_addr != 0 && xsdt_addr <= u32::MAX as u64
+            {
+                self.root_sdt_addr = xsdt_addr as u32;
+                self.is_xsdt = true;
+            }
+        }
+
+        if self.root_sdt_addr == 0
+        {
+            if let Some(rsdt_addr) = boot_info::get_rsdt_addr(boot_info)
+            {
+                self.root_sdt_addr = rsdt_addr;
+                self.is_xsdt = false;
+            }
+        }
+
+        if self.root_sdt_addr == 0
+        {
+            match find_rsdp()
+            {
+                Some(rsdp_addr) =>
+                {
+                    let rsdp = unsafe { read_unaligned(phys_to_virt(rsdp_addr) as *const Rsdp) };
+                    let xsdt_addr = rsdp.xsdt_addr;
+
+                    self.rsdp_addr = Some(rsdp_addr);
+
+                    if rsdp.revision >= 2 && xsdt_addr != 0 && xsdt_addr <= u32::MAX as u64
+                    {
+                        self.root_sdt_addr = xsdt_addr as u32;
+                        self.is_xsdt = true;
+                    }
+                    else
+                    {
+                        self.root_sdt_addr = rsdp.rsdt_addr;
+                        self.is_xsdt = false;
+                    }
+                },
+                None =>
+                {
+                    log_warn("ACPI: RSDP was not found");
+                    return;
+                }
+            }
+        }
+
+        // tables can be beyond direct map
+        let root_sdt = match sdt::map_table(self.root_sdt_addr)
+        {
+            Ok(table) => table,
+            Err(msg) =>
+            {
+                log_warn(msg);
+                return;
+            }
+        };
+
+        let root_sdt_addr = root_sdt.get_virt_addr();
+        let root_sdt_header = SdtHeader::read(root_sdt_addr);
+        let root_sdt_sign = if self.is_xsdt { XSDT_SIGNATURE } else { RSDT_SIGNATURE };
+
+        if root_sdt_header.get_signature() != root_sdt_sign ||
+           !sdt::is_valid_checksum(root_sdt_addr, root_sdt_header.get_length())
+        {
+            log_warn("ACPI: Invalid RSDT/XSDT");
+            return;
+        }
+
+        // RSDT has 32bit entries, XSDT has 64bit entries
+        let entry_size = if self.is_xsdt { 8 } else { 4 };
+        let entries_cnt = match root_sdt_header.get_length().checked_sub(sdt::SDT_HEADER_SIZE as u32)
+        {
+            Some(len) => len / entry_size,
+            None =>
+            {
+                log_warn("ACPI: Invalid RSDT/XSDT length");
+                return;
+            }
+        };
+
+        for i in 0..entries_cnt
+        {
+            let entry_addr = root_sdt_addr + sdt::SDT_HEADER_SIZE as u32 + i * entry_size;
+            let table_addr = if self.is_xsdt
+            {
+                unsafe { read_unaligned(entry_addr as *const u64) }
+            }
+            else
+            {
+                unsafe { read_unaligned(entry_addr as *const u32) as u64 }
+            };
+
+            // tables above 4GiB are not reachable
+            if table_addr == 0 || table_addr > u32::MAX as u64
+            {
+                continue;
+            }
+
+            let table_addr = table_addr as u32;
+            let table = match sdt::map_table(table_addr)
+            {
+                Ok(table) => table,
+                Err(msg) =>
+                {
+                    log_warn(msg);
+                    continue;
+                }
+            };
+
+            let header = SdtHeader::read(table.get_virt_addr());
+
+            if !sdt::is_valid_checksum(table.get_virt_addr(), header.get_length())
+            {
+                log_warn("ACPI: Invalid table checksum, skipped");
+                continue;
+            }
+
+            self.tables.push(AcpiTable { header, addr: table_addr });
+        }
+
+        if let Some(addr) = self.find_table(FADT_SIGNATURE)
+        {
+            self.fadt = read_mapped_table(addr, sdt::read_table::<Fadt>);
+        }
+
+        if let Some(addr) = self.find_table(MADT_SIGNATURE)
+        {
+            self.madt = read_mapped_table(addr, Madt::read);
+        }
+
+        if let Some(addr) = self.find_table(MCFG_SIGNATURE)
+        {
+            self.mcfg_entries = read_mapped_table(addr, mcfg::read_mcfg_entries).unwrap_or_default();
+        }
+
+        if let Some(addr) = self.find_table(HPET_SIGNATURE)
+        {
+            self.hpet = read_mapped_table(addr, sdt::read_table::<Hpet>);
+        }
+
+        self.is_init = true;
+    }
+
+    pub fn is_init(&self) -> bool
+    {
+        return self.is_init;
+    }
+
+    /// returns physical address of the first table with the signature
+    pub fn find_table(&self, signature: [u8; 4]) -> Option<u32>
+    {
+        return self.tables.iter().find(|t| t.header.get_signature() == signature).map(|t| t.addr);
+    }
+
+    pub fn get_tables(&self) -> &Vec<AcpiTable>
+    {
+        return &self.tables;
+    }
+
+    pub fn get_fadt(&self) -> Option<Fadt>
+    {
+        return self.fadt;
+    }
+
+    pub fn get_madt(&self) -> Option<&Madt>
+    {
+        return self.madt.as_ref();
+    }
+
+    pub fn get_mcfg_entries(&self) -> &Vec<McfgEntry>
+    {
+        return &self.mcfg_entries;
+    }
+
+    pub fn get_hpet(&self) -> Option<Hpet>
+    {
+        return self.hpet;
+    }
+
+    pub fn acpi_info(&self)
+    {
+        if !self.is_init
+        {
+            println!("ACPI was not initialized");
+            return;
+        }
+
+        if let Some(rsdp_addr) = self.rsdp_addr
+        {
+            println!("RSDP at 0x{:08x}", rsdp_addr);
+        }
+
+        println!("{} at 0x{:08x}", if self.is_xsdt { "XSDT" } else { "RSDT" }, self.root_sdt_addr);
+
+        for table in self.tables.iter()
+        {
+            let header = table.header;
+            println!("{} 0x{:08x} len: {} rev: {} oem: {} {}",
+                header.get_signature_str(),
+                table.addr,
+                header.get_length(),
+                header.get_revision(),
+                header.get_oem_id_str(),
+                header.get_oem_table_id_str());
+        }
+
+        if let Some(madt) = &self.madt
+        {
+            println!("LAPIC: 0x{:08x}, CPU: {}, IOAPIC: {}",
+                madt.local_apic_addr,
+                madt.get_local_apics().count(),
+                madt.get_io_apics().count());
+
+            for entry in madt.get_int_src_overrides()
+            {
+                if let MadtEntry::IntSrcOverride { src, gsi, flags, .. } = entry
+                {
+                    println!("IRQ{} -> GSI{} (flags: 0x{:x})", src, gsi, flags);
+                }
+            }
+        }
+
+        for entry in self.mcfg_entries.iter()
+        {
+            println!("ECAM: 0x{:08x} segment: {} bus: {}-{}",
+                entry.get_base_addr(),
+                entry.get_pci_segment_group(),
+                entry.get_start_bus_num(),
+                entry.get_end_bus_num());
+        }
+
+        if let Some(hpet) = self.hpet
+        {
+            println!("HPET: 0x{:08x} comparators: {}", hpet.get_base_addr(), hpet.get_comparators_cnt());
+        }
+    }
+}
+
+pub fn init(boot_info: &BootInformation)
+{
+    ACPI.lock().init(boot_info);
+
+    if ACPI.lock().is_init()
+    {
+        log_info("ACPI initialized");
+    }
+    else
+    {
+        log_warn("Failed to initialize ACPI");
+    }
+}
+
+// map the table at physical address while read parses it
+fn read_mapped_table<T>(phys_addr: u32, read: fn(u32) -> T) -> Option<T>
+{
+    match sdt::map_table(phys_addr)
+    {
+        Ok(table) => return Some(read(table.get_virt_addr())),
+        Err(msg) =>
+        {
+            log_warn(msg);
+            return None;
+        }
+    }
+}
+
+// returns physical address, BIOS areas are in direct map
+fn find_rsdp() -> Option<u32>
+{
+    let ebda_addr = (unsafe { read_unaligned(phys_to_virt(BDA_EBDA_SEGMENT_ADDR) as *const u16) } as u32) << 4;
+
+    if ebda_addr != 0
+    {
+        if let Some(addr) = search_rsdp(ebda_addr, ebda_addr + EBDA_SEARCH_SIZE)
+        {
+            return Some(addr);
+        }
+    }
+
+    return search_rsdp(BIOS_AREA_START_ADDR, BIOS_AREA_END_ADDR);
+}
+
+fn search_rsdp(start_addr: u32, end_addr: u32) -> Option<u32>
+{
+    let mut addr = start_addr;
+
+    while addr + size_of::<Rsdp>() as u32 <= end_addr
+    {
+        let rsdp = unsafe { read_unaligned(phys_to_virt(addr) as *const Rsdp) };
+
+        if rsdp.signature == RSDP_SIGNATURE && sdt::is_valid_checksum(phys_to_virt(addr), RSDP_V1_SIZE)
+        {
+            let length = rsdp.length;
+
+            if rsdp.revision < 2 || sdt::is_valid_checksum(phys_to_virt(addr), length)
+            {
+                return Some(addr);
+            }
+        }
+
+        addr += RSDP_ALIGN;
+    }
+
+    return None;
+}
\ No newline at end of file
@@ -0,0 +1,165 @@
+use core::{mem::size_of, ptr::read_unaligned};
+
+use alloc::string::String;
+
+use crate::mem::{mmio::{self, Mmio}, phys_to_virt, DIRECT_MAP_SIZE};
+
+pub const SDT_HEADER_SIZE: usize = size_of::<SdtHeader>();
+
+// system description table header
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct SdtHeader
+{
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32
+}
+
+impl SdtHeader
+{
+    pub fn read(base_addr: u32) -> SdtHeader
+    {
+        return unsafe { read_unaligned(base_addr as *const SdtHeader) };
+    }
+
+    pub fn get_signature(&self) -> [u8; 4]
+    {
+        return self.signature;
+    }
+
+    pub fn get_signature_str(&self) -> String
+    {
+        return self.signature.iter().map(|c| *c as char).collect();
+    }
+
+    pub fn get_length(&self) -> u32
+    {
+        return self.length;
+    }
+
+    pub fn get_revision(&self) -> u8
+    {
+        return self.revision;
+    }
+
+    pub fn get_oem_id_str(&self) -> String
+    {
+        return self.oem_id.iter().map(|c| *c as char).collect();
+    }
+
+    pub fn get_oem_table_id_str(&self) -> String
+    {
+        return self.oem_table_id.iter().map(|c| *c as char).collect();
+    }
+}
+
+// generic address structure
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct GenericAddress
+{
+    pub addr_space_id: u8,
+    pub reg_bit_width: u8,
+    pub reg_bit_offset: u8,
+    pub access_size: u8,
+    pub addr: u64
+}
+
+impl GenericAddress
+{
+    pub fn get_addr(&self) -> u64
+    {
+        return self.addr;
+    }
+
+    pub fn is_system_io(&self) -> bool
+    {
+        return self.addr_space_id == 1;
+    }
+
+    pub fn is_system_mem(&self) -> bool
+    {
+        return self.addr_space_id == 0;
+    }
+}
+
+// table in direct map (cached like other RAM) or remapped by ioremap
+pub enum MappedTable
+{
+    Direct(u32),
+    Remapped(Mmio)
+}
+
+impl MappedTable
+{
+    pub fn get_virt_addr(&self) -> u32
+    {
+        match self
+        {
+            MappedTable::Direct(virt_addr) => return *virt_addr,
+            MappedTable::Remapped(mmio) => return mmio.get_virt_addr()
+        }
+    }
+}
+
+/// map whole table at physical address, readers take virtual address of the mapping
+/// ioremap (uncached) is used only for tables beyond direct map
+pub fn map_table(phys_addr: u32) -> Result<MappedTable, &'static str>
+{
+    if is_in_direct_map(phys_addr, SDT_HEADER_SIZE as u32)
+    {
+        let len = SdtHeader::read(phys_to_virt(phys_addr)).get_length().max(SDT_HEADER_SIZE as u32);
+
+        if is_in_direct_map(phys_addr, len)
+        {
+            return Ok(MappedTable::Direct(phys_to_virt(phys_addr)));
+        }
+    }
+
+    let len = mmio::ioremap(phys_addr, SDT_HEADER_SIZE as u32)?.read::<SdtHeader>(0).get_length();
+    return Ok(MappedTable::Remapped(mmio::ioremap(phys_addr, len.max(SDT_HEADER_SIZE as u32))?));
+}
+
+fn is_in_direct_map(phys_addr: u32, len: u32) -> bool
+{
+    return phys_addr as u64 + len as u64 <= DIRECT_MAP_SIZE as u64;
+}
+
+pub fn is_valid_checksum(base_addr: u32, len: u32) -> bool
+{
+    let mut sum: u8 = 0;
+
+    for i in 0..len
+    {
+        sum = sum.wrapping_add(unsafe { read_unaligned((base_addr + i) as *const u8) });
+    }
+
+    return sum == 0;
+}
+
+/// copy a table into T, fields beyond the table length are zeroed (older revisions are shorter)
+pub fn read_table<T: Copy + Default>(base_addr: u32) -> T
+{
+    let header = SdtHeader::read(base_addr);
+    let len = (header.get_length() as usize).min(size_of::<T>());
+    let mut table = T::default();
+
+    unsafe
+    {
+        let dst = &mut table as *mut T as *mut u8;
+
+        for i in 0..len
+        {
+            *dst.add(i) = read_unaligned((base_addr as usize + i) as *const u8);
+        }
+    }
+
+    return table;
+}
@@ -0,0 +1,430 @@
+// local APIC and I/O APIC
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{mem::mmio::{self, Mmio}, println};
+
+use super::{acpi::{ACPI, madt::{MadtEntry, MADT_INTI_POLARITY_MASK, MADT_INTI_POLARITY_ACTIVE_LOW, MADT_INTI_TRIGGER_MASK, MADT_INTI_TRIGGER_LEVEL}}, asm, cpuid::{self, CpuFeature}, int::{IRQ_CNT, INT_VECTOR_IRQ0}};
+
+const MSR_IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 0x800;
+
+const LAPIC_MMIO_SIZE: u32 = 0x1000;
+const LAPIC_REG_ID: u32 = 0x20;
+const LAPIC_REG_VERSION: u32 = 0x30;
+const LAPIC_REG_TPR: u32 = 0x80;
+const LAPIC_REG_EOI: u32 = 0xb0;
+const LAPIC_REG_SVR: u32 = 0xf0;
+const LAPIC_REG_ISR: u32 = 0x100; // 8 registers of 32 vectors, 0x10 apart
+const LAPIC_REG_ESR: u32 = 0x280;
+const LAPIC_REG_ICR_LOW: u32 = 0x300;
+const LAPIC_REG_ICR_HIGH: u32 = 0x310;
+const LAPIC_REG_LVT_TIMER: u32 = 0x320;
+const LAPIC_REG_LVT_LINT0: u32 = 0x350;
+const LAPIC_REG_LVT_LINT1: u32 = 0x360;
+const LAPIC_REG_LVT_ERROR: u32 = 0x370;
+const LAPIC_SVR_ENABLE: u32 = 0x100;
+const LAPIC_LVT_MASKED: u32 = 0x10000;
+const LAPIC_LVT_DELIVERY_NMI: u32 = 0x400;
+const LAPIC_ICR_DELIVERY_INIT: u32 = 0x500;
+const LAPIC_ICR_DELIVERY_STARTUP: u32 = 0x600;
+const LAPIC_ICR_DELIVERY_PENDING: u32 = 0x1000;
+const LAPIC_ICR_LEVEL_ASSERT: u32 = 0x4000;
+const LAPIC_ICR_DEST_ALL_EXCLUDING_SELF: u32 = 0xc0000;
+
+pub const APIC_SPURIOUS_VECTOR: u32 = 0xff;
+
+const IOAPIC_MMIO_SIZE: u32 = 0x20;
+const IOAPIC_REG_SEL: u32 = 0x00;
+const IOAPIC_REG_WIN: u32 = 0x10;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_REDIR_TABLE: u32 = 0x10;
+const IOAPIC_REDIR_POLARITY_LOW: u32 = 0x2000;
+const IOAPIC_REDIR_TRIGGER_LEVEL: u32 = 0x8000;
+const IOAPIC_REDIR_MASKED: u32 = 0x10000;
+
+lazy_static!
+{
+    pub static ref APIC: Mutex<Apic> = Mutex::new(Apic::new());
+}
+
+#[derive(Debug)]
+struct IoApic
+{
+    id: u8,
+    base_addr: u32,
+    mmio: Mmio,
+    gsi_base: u32,
+    redir_entries: u32
+}
+
+impl IoApic
+{
+    fn read(&self, reg: u32) -> u32
+    {
+        self.mmio.write32(IOAPIC_REG_SEL, reg);
+        return self.mmio.read32(IOAPIC_REG_WIN);
+    }
+
+    fn write(&self, reg: u32, data: u32)
+    {
+        self.mmio.write32(IOAPIC_REG_SEL, reg);
+        self.mmio.write32(IOAPIC_REG_WIN, data);
+    }
+
+    fn has_gsi(&self, gsi: u32) -> bool
+    {
+        return gsi >= self.gsi_base && gsi < self.gsi_base + self.redir_entries;
+    }
+
+    fn read_redir(&self, gsi: u32) -> u32
+    {
+        return self.read(IOAPIC_REG_REDIR_TABLE + (gsi - self.gsi_base) * 2);
+    }
+
+    fn write_redir(&self, gsi: u32, low: u32, high: u32)
+    {
+        let reg = IOAPIC_REG_REDIR_TABLE + (gsi - self.gsi_base) * 2;
+
+        // keep masked while updating
+        self.write(reg, IOAPIC_REDIR_MASKED);
+        self.write(reg + 1, high);
+        self.write(reg, low);
+    }
+}
+
+// ISA IRQ to global system interrupt
+#[derive(Debug, Clone, Copy)]
+struct IrqRoute
+{
+    gsi: u32,
+    is_active_low: bool,
+    is_level_triggered: bool
+}
+
+#[derive(Debug)]
+pub struct Apic
+{
+    is_init: bool,
+    local_apic_addr: u32,
+    local_apic: Option<Mmio>,
+    local_apic_id: u8,
+    io_apics: Vec<IoApic>,
+    irq_routes: [IrqRoute; IRQ_CNT]
+}
+
+impl Apic
+{
+    pub fn new() -> Apic
+    {
+        let mut irq_routes = [IrqRoute { gsi: 0, is_active_low: false, is_level_triggered: false }; IRQ_CNT];
+
+        for i in 0..IRQ_CNT
+        {
+            irq_routes[i].gsi = i as u32;
+        }
+
+        return Apic
+        {
+            is_init: false,
+            local_apic_addr: 0,
+            local_apic: None,
+            local_apic_id: 0,
+            io_apics: Vec::new(),
+            irq_routes
+        };
+    }
+
+    pub fn init(&mut self) -> Result<(), &'static str>
+    {
+        if !cpuid::has_feature(CpuFeature::Apic)
+        {
+            return Err("APIC is not supported");
+        }
+
+        let acpi = ACPI.lock();
+        let madt = match acpi.get_madt()
+        {
+            Some(madt) => madt,
+            None => return Err("MADT was not found")
+        };
+
+        if madt.local_apic_addr > u32::MAX as u64
+        {
+            return Err("Local APIC is not reachable");
+        }
+
+        self.local_apic_addr = madt.local_apic_addr as u32;
+        let mut io_apic_addrs = Vec::new();
+
+        for entry in madt.entries.iter()
+        {
+            match *entry
+            {
+                MadtEntry::IoApic { io_apic_id, io_apic_addr, gsi_base } =>
+                {
+                    io_apic_addrs.push((io_apic_id, io_apic_addr, gsi_base));
+                },
+                MadtEntry::IntSrcOverride { bus, src, gsi, flags } =>
+                {
+                    // bus 0 is ISA
+                    if bus != 0 || src as usize >= IRQ_CNT
+                    {
+                        continue;
+                    }
+
+                    self.irq_routes[src as usize] = IrqRoute
+                    {
+                        gsi,
+                        is_active_low: flags & MADT_INTI_POLARITY_MASK == MADT_INTI_POLARITY_ACTIVE_LOW,
+                        is_level_triggered: flags & MADT_INTI_TRIGGER_MASK == MADT_INTI_TRIGGER_LEVEL
+                    };
+                },
+                _ => ()
+            }
+        }
+
+        drop(acpi);
+
+        if io_apic_addrs.len() == 0
+        {
+            return Err("I/O APIC was not found");
+        }
+
+        self.local_apic = Some(mmio::ioremap(self.local_apic_addr, LAPIC_MMIO_SIZE)?);
+
+        for (id, base_addr, gsi_base) in io_apic_addrs
+        {
+            let mut io_apic = IoApic { id, base_addr, mmio: mmio::ioremap(base_addr, IOAPIC_MMIO_SIZE)?, gsi_base, redir_entries: 0 };
+            io_apic.redir_entries = ((io_apic.read(IOAPIC_REG_VERSION) >> 16) & 0xff) + 1;
+            self.io_apics.push(io_apic);
+        }
+
+        self.init_local_apic();
+        self.local_apic_id = self.read_local_apic_id();
+
+        // mask all redirection entries
+        for io_apic in self.io_apics.iter()
+        {
+            for i in 0..io_apic.redir_entries
+            {
+                io_apic.write_redir(io_apic.gsi_base + i, IOAPIC_REDIR_MASKED, 0);
+            }
+        }
+
+        // route ISA IRQs to the same vectors as PIC, masked
+        for irq in 0..IRQ_CNT as u8
+        {
+            self.set_irq_route(irq, true);
+        }
+
+        self.is_init = true;
+
+        return Ok(());
+    }
+
+    pub fn is_init(&self) -> bool
+    {
+        return self.is_init;
+    }
+
+    /// local APIC ID of BSP
+    pub fn get_local_apic_id(&self) -> u8
+    {
+        return self.local_apic_id;
+    }
+
+    /// local APIC ID of the current CPU
+    pub fn read_local_apic_id(&self) -> u8
+    {
+        return (self.read_local_apic(LAPIC_REG_ID) >> 24) as u8;
+    }
+
+    /// enable local APIC of application processor
+    pub fn init_ap(&self)
+    {
+        self.init_local_apic();
+    }
+
+    pub fn get_local_apic_addr(&self) -> u32
+    {
+        return self.local_apic_addr;
+    }
+
+    pub fn mask_irq(&self, irq: u8)
+    {
+        self.set_irq_route(irq, true);
+    }
+
+    pub fn unmask_irq(&self, irq: u8)
+    {
+        self.set_irq_route(irq, false);
+    }
+
+    pub fn send_eoi(&self)
+    {
+        self.write_local_apic(LAPIC_REG_EOI, 0);
+    }
+
+    /// vector was delivered by local APIC and is not acknowledged yet (vectors from 8259 aren't set)
+    pub fn is_in_service(&self, vector: u32) -> bool
+    {
+        let isr = self.read_local_apic(LAPIC_REG_ISR + (vector / 32) * 0x10);
+        return isr & (1 << (vector % 32)) != 0;
+    }
+
+    pub fn send_ipi(&self, apic_id: u8, vector: u32)
+    {
+        self.write_icr(apic_id, vector & 0xff);
+    }
+
+    pub fn send_ipi_all_excluding_self(&self, vector: u32)
+    {
+        self.write_icr(0, LAPIC_ICR_DEST_ALL_EXCLUDING_SELF | (vector & 0xff));
+    }
+
+    pub fn send_init_ipi(&self, apic_id: u8)
+    {
+        self.write_icr(apic_id, LAPIC_ICR_DELIVERY_INIT | LAPIC_ICR_LEVEL_ASSERT);
+    }
+
+    /// start executing at vector * 0x1000 in real mode
+    pub fn send_startup_ipi(&self, apic_id: u8, vector: u8)
+    {
+        self.write_icr(apic_id, LAPIC_ICR_DELIVERY_STARTUP | LAPIC_ICR_LEVEL_ASSERT | vector as u32);
+    }
+
+    pub fn apic_info(&self)
+    {
+        if !self.is_init
+        {
+            println!("APIC was not initialized");
+            return;
+        }
+
+        println!("LAPIC: 0x{:08x} id: {} version: 0x{:x}",
+            self.local_apic_addr,
+            self.local_apic_id,
+            self.read_local_apic(LAPIC_REG_VERSION) & 0xff);
+
+        for io_apic in self.io_apics.iter()
+        {
+            println!("IOAPIC: 0x{:08x} id: {} GSI: {}-{}",
+                io_apic.base_addr,
+                io_apic.id,
+                io_apic.gsi_base,
+                io_apic.gsi_base + io_apic.redir_entries - 1);
+        }
+
+        for irq in 0..IRQ_CNT
+        {
+            let route = self.irq_routes[irq];
+
+            if let Some(io_apic) = self.find_io_apic(route.gsi)
+            {
+                let is_masked = io_apic.read_redir(route.gsi) & IOAPIC_REDIR_MASKED != 0;
+                println!("IRQ{} -> GSI{}{}{}{}",
+                    irq,
+                    route.gsi,
+                    if route.is_active_low { " low" } else { "" },
+                    if route.is_level_triggered { " level" } else { "" },
+                    if is_masked { " masked" } else { "" });
+            }
+        }
+    }
+
+    fn init_local_apic(&self)
+    {
+        let apic_base = asm::rdmsr(MSR_IA32_APIC_BASE);
+        asm::wrmsr(MSR_IA32_APIC_BASE, apic_base | APIC_BASE_ENABLE);
+
+        // accept all interrupts
+        self.write_local_apic(LAPIC_REG_TPR, 0);
+
+        self.write_local_apic(LAPIC_REG_LVT_TIMER, LAPIC_LVT_MASKED);
+        self.write_local_apic(LAPIC_REG_LVT_LINT0, LAPIC_LVT_MASKED);
+        self.write_local_apic(LAPIC_REG_LVT_LINT1, LAPIC_LVT_DELIVERY_NMI);
+        self.write_local_apic(LAPIC_REG_LVT_ERROR, LAPIC_LVT_MASKED);
+
+        // clear error status (write before read)
+        self.write_local_apic(LAPIC_REG_ESR, 0);
+        self.read_local_apic(LAPIC_REG_ESR);
+
+        self.write_local_apic(LAPIC_REG_SVR, LAPIC_SVR_ENABLE | APIC_SPURIOUS_VECTOR);
+        self.send_eoi();
+    }
+
+    fn write_icr(&self, apic_id: u8, low: u32)
+    {
+        self.write_local_apic(LAPIC_REG_ICR_HIGH, (apic_id as u32) << 24);
+        self.write_local_apic(LAPIC_REG_ICR_LOW, low);
+
+        while self.read_local_apic(LAPIC_REG_ICR_LOW) & LAPIC_ICR_DELIVERY_PENDING != 0 {}
+    }
+
+    fn set_irq_route(&self, irq: u8, is_masked: bool)
+    {
+        if irq as usize >= IRQ_CNT
+        {
+            return;
+        }
+
+        let route = self.irq_routes[irq as usize];
+
+        let io_apic = match self.find_io_apic(route.gsi)
+        {
+            Some(io_apic) => io_apic,
+            None => return
+        };
+
+        let mut low = INT_VECTOR_IRQ0 + irq as u32;
+
+        if route.is_active_low
+        {
+            low |= IOAPIC_REDIR_POLARITY_LOW;
+        }
+
+        if route.is_level_triggered
+        {
+            low |= IOAPIC_REDIR_TRIGGER_LEVEL;
+        }
+
+        if is_masked
+        {
+            low |= IOAPIC_REDIR_MASKED;
+        }
+
+        // fixed delivery, physical destination
+        io_apic.write_redir(route.gsi, low, (self.local_apic_id as u32) << 24);
+    }
+
+    fn find_io_apic(&self, gsi: u32) -> Option<&IoApic>
+    {
+        return self.io_apics.iter().find(|io_apic| io_apic.has_gsi(gsi));
+    }
+
+    fn get_local_apic(&self) -> &Mmio
+    {
+        return self.local_apic.as_ref().expect("Local APIC is not mapped");
+    }
+
+    fn read_local_apic(&self, reg: u32) -> u32
+    {
+        return self.get_local_apic().read32(reg);
+    }
+
+    fn write_local_apic(&self, reg: u32, data: u32)
+    {
+        self.get_local_apic().write32(reg, data);
+    }
+}
+
+pub fn info()
+{
+    // EOI from interrupt handlers needs APIC lock
+    let is_int_enabled = asm::disable_int();
+    APIC.lock().apic_info();
+    asm::restore_int(is_int_enabled);
+}
\ No newline at end of file
@@ -6,6 +6,8 @@ extern
     fn init_sgm_reg();
 }
 
+pub const EFLAGS_IF: u32 = 0x200;
+
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
 struct Dtr
@@ -29,6 +31,40 @@ pub fn sti()
     unsafe { asm!("sti"); }
 }
 
+pub fn int3()
+{
+    unsafe { asm!("int3"); }
+}
+
+pub fn get_eflags() -> u32
+{
+    let mut eflags: u32;
+    unsafe { asm!("pushfd", "pop {}", out(reg) eflags); }
+    return eflags;
+}
+
+pub fn set_eflags(eflags: u32)
+{
+    unsafe { asm!("push {}", "popfd", in(reg) eflags); }
+}
+
+/// disable interrupts and return whether they were enabled
+pub fn disable_int() -> bool
+{
+    let is_enabled = get_eflags() & EFLAGS_IF != 0;
+    cli();
+    return is_enabled;
+}
+
+/// restore the interrupt flag returned by disable_int()
+pub fn restore_int(is_enabled: bool)
+{
+    if is_enabled
+    {
+        sti();
+    }
+}
+
 pub fn test()
 {
     unsafe { asm!("int 0x10"); }
@@ -48,6 +84,24 @@ pub fn load_gdtr(limit: i32, addr: i32)
     }
 }
 
+pub fn load_tr(selector: u16)
+{
+    unsafe { asm!("ltr {:x}", in(reg) selector); }
+}
+
+pub fn set_gs(selector: u16)
+{
+    unsafe { asm!("mov gs, {:x}", in(reg) selector); }
+}
+
+/// read 32bit value at gs:offset
+pub fn read_gs(offset: u32) -> u32
+{
+    let mut data: u32;
+    unsafe { asm!("mov {}, gs:[{}]", out(reg) data, in(reg) offset); }
+    return data;
+}
+
 pub fn set_cr3(cr3: u32)
 {
     unsafe { asm!("mov cr3, {}", in(reg) cr3); }
@@ -60,11 +114,112 @@ pub fn get_cr3() -> u32
     return cr3;
 }
 
+pub fn get_cr2() -> u32
+{
+    let mut cr2 = 0;
+    unsafe { asm!("mov {}, cr2", out(reg) cr2); }
+    return cr2;
+}
+
 pub fn invlpg(virt_addr: u32)
 {
-    cli();
-    unsafe { asm!("invlpg {}", in(reg) virt_addr); }
-    sti();
+    let is_int_enabled = disable_int();
+    unsafe { asm!("invlpg [{}]", in(reg) virt_addr); }
+    restore_int(is_int_enabled);
+}
+
+/// flush all non-global TLB entries
+pub fn flush_tlb()
+{
+    set_cr3(get_cr3());
+}
+
+pub fn get_cr0() -> u32
+{
+    let mut cr0 = 0;
+    unsafe { asm!("mov {}, cr0", out(reg) cr0); }
+    return cr0;
+}
+
+pub fn set_cr0(cr0: u32)
+{
+    unsafe { asm!("mov cr0, {}", in(reg) cr0); }
+}
+
+pub fn get_cr4() -> u32
+{
+    let mut cr4 = 0;
+    unsafe { asm!("mov {}, cr4", out(reg) cr4); }
+    return cr4;
+}
+
+pub fn set_cr4(cr4: u32)
+{
+    unsafe { asm!("mov cr4, {}", in(reg) cr4); }
+}
+
+/// clear CR0.TS
+pub fn clts()
+{
+    unsafe { asm!("clts"); }
+}
+
+pub fn fninit()
+{
+    unsafe { asm!("fninit"); }
+}
+
+pub fn fnclex()
+{
+    unsafe { asm!("fnclex"); }
+}
+
+pub fn fnstsw() -> u16
+{
+    let mut fsw: u16;
+    unsafe { asm!("fnstsw ax", out("ax") fsw); }
+    return fsw;
+}
+
+pub fn fnstcw() -> u16
+{
+    let mut fcw: u16 = 0;
+    unsafe { asm!("fnstcw [{}]", in(reg) &mut fcw as *mut u16); }
+    return fcw;
+}
+
+/// save x87 state (108 bytes) and reinitialize FPU
+pub fn fnsave(addr: u32)
+{
+    unsafe { asm!("fnsave [{}]", in(reg) addr); }
+}
+
+pub fn frstor(addr: u32)
+{
+    unsafe { asm!("frstor [{}]", in(reg) addr); }
+}
+
+/// save x87/SSE state (512 bytes), addr must be 16 bytes aligned
+pub fn fxsave(addr: u32)
+{
+    unsafe { asm!("fxsave [{}]", in(reg) addr); }
+}
+
+pub fn fxrstor(addr: u32)
+{
+    unsafe { asm!("fxrstor [{}]", in(reg) addr); }
+}
+
+pub fn stmxcsr() -> u32
+{
+    let mut mxcsr: u32 = 0;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr as *mut u32); }
+    return mxcsr;
+}
+
+pub fn ldmxcsr(mxcsr: u32)
+{
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &mxcsr as *const u32); }
 }
 
 pub fn enable_paging()
@@ -81,6 +236,18 @@ pub fn enable_paging()
     sti();
 }
 
+pub fn rdmsr(msr: u32) -> u64
+{
+    let (low, high): (u32, u32);
+    unsafe { asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high); }
+    return (high as u64) << 32 | low as u64;
+}
+
+pub fn wrmsr(msr: u32, data: u64)
+{
+    unsafe { asm!("wrmsr", in("ecx") msr, in("eax") data as u32, in("edx") (data >> 32) as u32); }
+}
+
 pub fn out8(port: u32, data: u8)
 {
     unsafe { asm!("out dx, al", in("edx") port, in("al") data); }
@@ -134,33 +301,3 @@ pub fn get_vesa_bios_info() -> u16
 
     return ax;
 }
-
-#[macro_export]
-macro_rules! handler
-{
-    ($name: ident) =>
-    {{
-        pub extern "C" fn wrapper() -> !
-        {
-            unsafe
-            {
-                asm!("push es");
-                asm!("push ds");
-                asm!("pushad");
-                asm!("mov eax, esp");
-                asm!("push eax");
-                asm!("mov ax, ss");
-                asm!("mov ds, ax");
-                asm!("mov es, ax");
-                asm!("call {}", in(reg) $name as extern "C" fn());
-                asm!("pop eax");
-                asm!("popad");
-                asm!("pop ds");
-                asm!("pop es");
-                asm!("iretd");
-                ::core::intrinsics::unreachable();
-            }
-        }
-        wrapper
-    }}
-}
\ No newline at end of file
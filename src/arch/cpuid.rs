@@ -0,0 +1,304 @@
+// processor identification
+
+use core::arch::x86::{__cpuid_count, CpuidResult};
+
+use alloc::{string::String, vec::Vec};
+use lazy_static::lazy_static;
+
+use crate::println;
+
+use super::asm;
+
+const EFLAGS_ID: u32 = 0x200000;
+
+const CPUID_LEAF_VENDOR: u32 = 0x0;
+const CPUID_LEAF_FEATURES: u32 = 0x1;
+const CPUID_LEAF_EXT_FEATURES: u32 = 0x7;
+const CPUID_LEAF_EXT_MAX: u32 = 0x80000000;
+const CPUID_LEAF_EXT_INFO: u32 = 0x80000001;
+const CPUID_LEAF_BRAND_STRING: u32 = 0x80000002;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CpuFeature
+{
+    // leaf 1 edx
+    Fpu,
+    Pse,
+    Tsc,
+    Msr,
+    Pae,
+    Apic,
+    Mtrr,
+    Pge,
+    Pat,
+    Fxsr,
+    Sse,
+    Sse2,
+    // leaf 1 ecx
+    Sse3,
+    Ssse3,
+    Sse41,
+    Sse42,
+    X2Apic,
+    Xsave,
+    Avx,
+    Rdrand,
+    Hypervisor,
+    // leaf 7 ebx
+    Smep,
+    Smap,
+    // leaf 0x80000001 edx
+    Nx,
+    LongMode
+}
+
+// feature and its name in cpuinfo
+const FEATURES: [(CpuFeature, &str); 25] =
+[
+    (CpuFeature::Fpu, "fpu"),
+    (CpuFeature::Pse, "pse"),
+    (CpuFeature::Tsc, "tsc"),
+    (CpuFeature::Msr, "msr"),
+    (CpuFeature::Pae, "pae"),
+    (CpuFeature::Apic, "apic"),
+    (CpuFeature::Mtrr, "mtrr"),
+    (CpuFeature::Pge, "pge"),
+    (CpuFeature::Pat, "pat"),
+    (CpuFeature::Fxsr, "fxsr"),
+    (CpuFeature::Sse, "sse"),
+    (CpuFeature::Sse2, "sse2"),
+    (CpuFeature::Sse3, "sse3"),
+    (CpuFeature::Ssse3, "ssse3"),
+    (CpuFeature::Sse41, "sse4.1"),
+    (CpuFeature::Sse42, "sse4.2"),
+    (CpuFeature::X2Apic, "x2apic"),
+    (CpuFeature::Xsave, "xsave"),
+    (CpuFeature::Avx, "avx"),
+    (CpuFeature::Rdrand, "rdrand"),
+    (CpuFeature::Hypervisor, "hypervisor"),
+    (CpuFeature::Smep, "smep"),
+    (CpuFeature::Smap, "smap"),
+    (CpuFeature::Nx, "nx"),
+    (CpuFeature::LongMode, "lm")
+];
+
+lazy_static!
+{
+    pub static ref CPU_INFO: CpuInfo = CpuInfo::read();
+}
+
+#[derive(Debug, Clone)]
+pub struct CpuInfo
+{
+    vendor: String,
+    brand: String,
+    family: u32,
+    model: u32,
+    stepping: u32,
+    max_leaf: u32,
+    max_ext_leaf: u32,
+    features_edx: u32,
+    features_ecx: u32,
+    ext_features_ebx: u32,
+    ext_info_edx: u32
+}
+
+impl CpuInfo
+{
+    fn read() -> CpuInfo
+    {
+        let mut info = CpuInfo
+        {
+            vendor: String::new(),
+            brand: String::new(),
+            family: 0,
+            model: 0,
+            stepping: 0,
+            max_leaf: 0,
+            max_ext_leaf: 0,
+            features_edx: 0,
+            features_ecx: 0,
+            ext_features_ebx: 0,
+            ext_info_edx: 0
+        };
+
+        if !is_supported()
+        {
+            return info;
+        }
+
+        let leaf = cpuid(CPUID_LEAF_VENDOR, 0);
+        info.max_leaf = leaf.eax;
+        info.vendor = regs_to_string(&[leaf.ebx, leaf.edx, leaf.ecx]);
+
+        if info.max_leaf >= CPUID_LEAF_FEATURES
+        {
+            let leaf = cpuid(CPUID_LEAF_FEATURES, 0);
+            let base_family = (leaf.eax >> 8) & 0xf;
+            let base_model = (leaf.eax >> 4) & 0xf;
+
+            info.stepping = leaf.eax & 0xf;
+            info.family = base_family;
+            info.model = base_model;
+
+            if base_family == 0xf
+            {
+                info.family += (leaf.eax >> 20) & 0xff;
+            }
+
+            if base_family == 0x6 || base_family == 0xf
+            {
+                info.model += ((leaf.eax >> 16) & 0xf) << 4;
+            }
+
+            info.features_edx = leaf.edx;
+            info.features_ecx = leaf.ecx;
+        }
+
+        if info.max_leaf >= CPUID_LEAF_EXT_FEATURES
+        {
+            info.ext_features_ebx = cpuid(CPUID_LEAF_EXT_FEATURES, 0).ebx;
+        }
+
+        info.max_ext_leaf = cpuid(CPUID_LEAF_EXT_MAX, 0).eax;
+
+        if info.max_ext_leaf >= CPUID_LEAF_EXT_INFO
+        {
+            info.ext_info_edx = cpuid(CPUID_LEAF_EXT_INFO, 0).edx;
+        }
+
+        if info.max_ext_leaf >= CPUID_LEAF_BRAND_STRING + 2
+        {
+            let mut regs = Vec::new();
+
+            for i in 0..3
+            {
+                let leaf = cpuid(CPUID_LEAF_BRAND_STRING + i, 0);
+                regs.extend_from_slice(&[leaf.eax, leaf.ebx, leaf.ecx, leaf.edx]);
+            }
+
+            info.brand = String::from(regs_to_string(&regs).trim());
+        }
+
+        return info;
+    }
+
+    pub fn get_vendor(&self) -> &str
+    {
+        return &self.vendor;
+    }
+
+    pub fn get_brand(&self) -> &str
+    {
+        return &self.brand;
+    }
+
+    pub fn get_family(&self) -> u32
+    {
+        return self.family;
+    }
+
+    pub fn get_model(&self) -> u32
+    {
+        return self.model;
+    }
+
+    pub fn get_stepping(&self) -> u32
+    {
+        return self.stepping;
+    }
+
+    pub fn has_feature(&self, feature: CpuFeature) -> bool
+    {
+        let (reg, bit) = match feature
+        {
+            CpuFeature::Fpu => (self.features_edx, 0),
+            CpuFeature::Pse => (self.features_edx, 3),
+            CpuFeature::Tsc => (self.features_edx, 4),
+            CpuFeature::Msr => (self.features_edx, 5),
+            CpuFeature::Pae => (self.features_edx, 6),
+            CpuFeature::Apic => (self.features_edx, 9),
+            CpuFeature::Mtrr => (self.features_edx, 12),
+            CpuFeature::Pge => (self.features_edx, 13),
+            CpuFeature::Pat => (self.features_edx, 16),
+            CpuFeature::Fxsr => (self.features_edx, 24),
+            CpuFeature::Sse => (self.features_edx, 25),
+            CpuFeature::Sse2 => (self.features_edx, 26),
+            CpuFeature::Sse3 => (self.features_ecx, 0),
+            CpuFeature::Ssse3 => (self.features_ecx, 9),
+            CpuFeature::Sse41 => (self.features_ecx, 19),
+            CpuFeature::Sse42 => (self.features_ecx, 20),
+            CpuFeature::X2Apic => (self.features_ecx, 21),
+            CpuFeature::Xsave => (self.features_ecx, 26),
+            CpuFeature::Avx => (self.features_ecx, 28),
+            CpuFeature::Rdrand => (self.features_ecx, 30),
+            CpuFeature::Hypervisor => (self.features_ecx, 31),
+            CpuFeature::Smep => (self.ext_features_ebx, 7),
+            CpuFeature::Smap => (self.ext_features_ebx, 20),
+            CpuFeature::Nx => (self.ext_info_edx, 20),
+            CpuFeature::LongMode => (self.ext_info_edx, 29)
+        };
+
+        return reg & (1 << bit) != 0;
+    }
+
+    pub fn cpu_info(&self)
+    {
+        if self.max_leaf == 0
+        {
+            println!("CPUID is not supported");
+            return;
+        }
+
+        println!("Vendor: {}", self.vendor);
+
+        if self.brand.len() != 0
+        {
+            println!("Brand: {}", self.brand);
+        }
+
+        println!("Family: 0x{:x} Model: 0x{:x} Stepping: 0x{:x}", self.family, self.model, self.stepping);
+
+        let features: Vec<&str> = FEATURES.iter().filter(|(f, _)| self.has_feature(*f)).map(|(_, name)| *name).collect();
+        println!("Features: {}", features.join(" "));
+    }
+}
+
+pub fn has_feature(feature: CpuFeature) -> bool
+{
+    return CPU_INFO.has_feature(feature);
+}
+
+// CPUID is available if EFLAGS.ID can be toggled
+fn is_supported() -> bool
+{
+    let eflags = asm::get_eflags();
+    asm::set_eflags(eflags ^ EFLAGS_ID);
+    let toggled = asm::get_eflags();
+    asm::set_eflags(eflags);
+
+    return (eflags ^ toggled) & EFLAGS_ID != 0;
+}
+
+fn cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult
+{
+    return unsafe { __cpuid_count(leaf, sub_leaf) };
+}
+
+fn regs_to_string(regs: &[u32]) -> String
+{
+    let mut s = String::new();
+
+    for reg in regs
+    {
+        for byte in reg.to_le_bytes().iter()
+        {
+            if *byte != 0
+            {
+                s.push(*byte as char);
+            }
+        }
+    }
+
+    return s;
+}
\ No newline at end of file
@@ -1,8 +1,8 @@
 use core::panic;
 
-use crate::util::logger::log_warn;
+use crate::{debug::backtrace, mem::vm::{self, PageFaultError, PageFaultReport}, println};
 
-use super::asm;
+use super::{asm, isr::InterruptFrame, smp, usermode, vga::VGA_SCREEN};
 
 pub const EX_INT_DIVIDED_BY_ZERO: u32 = 0x0;
 pub const EX_INT_SINGLE_STEP: u32 = 0x1;
@@ -19,37 +19,130 @@ pub const EX_INT_SGM_NOT_PRESENT: u32 = 0xb;
 pub const EX_INT_STACK_SGM_FAULT: u32 = 0xc;
 pub const EX_INT_GENERAL_PROTECTION_FAULT: u32 = 0xd;
 pub const EX_INT_PAGE_FAULT: u32 = 0xe;
+pub const EX_INT_RESERVED_0F: u32 = 0xf;
 pub const EX_INT_FLOATING_POINT: u32 = 0x10;
 pub const EX_INT_ALIGN_CHECK: u32 = 0x11;
 pub const EX_INT_MACHINE_CHECK: u32 = 0x12;
 pub const EX_INT_SIMD_FLOATING_POINT: u32 = 0x13;
+pub const EX_INT_VIRTUALIZATION: u32 = 0x14;
+pub const EX_INT_CONTROL_PROTECTION: u32 = 0x15;
+pub const EX_INT_HYPERVISOR_INJECTION: u32 = 0x1c;
+pub const EX_INT_VMM_COMMUNICATION: u32 = 0x1d;
+pub const EX_INT_SECURITY: u32 = 0x1e;
+pub const EX_INT_MAX: u32 = 0x1f;
 
-/// divided by zero exception
-pub extern "C" fn ex_divided_by_zero()
+pub fn get_ex_name(vector: u32) -> &'static str
 {
-    panic!("Throw divided by zero exception.");
+    match vector
+    {
+        EX_INT_DIVIDED_BY_ZERO => return "Divided by zero",
+        EX_INT_SINGLE_STEP => return "Single step",
+        EX_INT_NMI => return "Non-maskable interrupt",
+        EX_INT_BREAKPOINT => return "Breakpoint",
+        EX_INT_OVERFLOW => return "Overflow",
+        EX_INT_BOUND_RANGE_EXCEEDED => return "Bound range exceeded",
+        EX_INT_INVALID_OPCODE => return "Invalid opcode",
+        EX_INT_CPROC_NOT_AVAILABLE => return "Coprocessor not available",
+        EX_INT_DOUBLE_FAULT => return "Double fault",
+        EX_INT_CPROC_SGM_OVERRUN => return "Coprocessor segment overrun",
+        EX_INT_INVALID_TSS => return "Invalid TSS",
+        EX_INT_SGM_NOT_PRESENT => return "Segment not present",
+        EX_INT_STACK_SGM_FAULT => return "Stack segment fault",
+        EX_INT_GENERAL_PROTECTION_FAULT => return "General protection fault",
+        EX_INT_PAGE_FAULT => return "Page fault",
+        EX_INT_FLOATING_POINT => return "x87 floating point",
+        EX_INT_ALIGN_CHECK => return "Alignment check",
+        EX_INT_MACHINE_CHECK => return "Machine check",
+        EX_INT_SIMD_FLOATING_POINT => return "SIMD floating point",
+        EX_INT_VIRTUALIZATION => return "Virtualization",
+        EX_INT_CONTROL_PROTECTION => return "Control protection",
+        EX_INT_HYPERVISOR_INJECTION => return "Hypervisor injection",
+        EX_INT_VMM_COMMUNICATION => return "VMM communication",
+        EX_INT_SECURITY => return "Security",
+        _ => return "Reserved"
+    }
 }
 
-/// double fault
-pub extern "C" fn ex_double_fault()
+/// returns true if the CPU pushes an error code for this vector
+pub fn has_err_code(vector: u32) -> bool
 {
-    panic!("Throw double fault exception.");
+    match vector
+    {
+        EX_INT_DOUBLE_FAULT |
+        EX_INT_INVALID_TSS |
+        EX_INT_SGM_NOT_PRESENT |
+        EX_INT_STACK_SGM_FAULT |
+        EX_INT_GENERAL_PROTECTION_FAULT |
+        EX_INT_PAGE_FAULT |
+        EX_INT_ALIGN_CHECK |
+        EX_INT_CONTROL_PROTECTION |
+        EX_INT_VMM_COMMUNICATION |
+        EX_INT_SECURITY => return true,
+        _ => return false
+    }
 }
 
-/// general protection fault
-pub extern "C" fn ex_general_protection_fault()
+pub fn throw(frame: &InterruptFrame) -> !
 {
-    panic!("Throw general protection fault exception.");
+    // exceptions in user mode only kill the user program
+    if frame.is_from_user()
+    {
+        usermode::kill(frame);
+    }
+
+    backtrace::set_fault_context(frame.eip, frame.ebp);
+    panic!("Throw {} exception (0x{:x})\n{}", get_ex_name(frame.vector), frame.vector, frame);
 }
 
-/// page fault
-pub extern "C" fn ex_page_fault()
+/// page fault, pages of registered regions are mapped on demand
+pub fn ex_page_fault(frame: &mut InterruptFrame)
 {
-    panic!("Throw page fault exception.");
+    // read before anything else can fault
+    let fault_addr = asm::get_cr2();
+    let err = PageFaultError(frame.err_code);
+
+    let reason = match vm::handle_page_fault(fault_addr, err)
+    {
+        Ok(()) => return,
+        Err(msg) => msg
+    };
+
+    let report = PageFaultReport::new(fault_addr, err, reason);
+
+    if frame.is_from_user()
+    {
+        println!("Page fault at 0x{:08x}\n{}", frame.eip, report);
+        usermode::kill(frame);
+    }
+
+    backtrace::set_fault_context(frame.eip, frame.ebp);
+    panic!("Throw {} exception (0x{:x})\n{}\n{}", get_ex_name(frame.vector), frame.vector, report, frame);
 }
 
-/// breakpoint
-pub extern "C" fn ex_breakpoint()
+/// double fault, runs as a separate task on its own stack (see sgm::enable_double_fault_task)
+/// registers of the faulted task are saved in the TSS of the current CPU
+#[no_mangle]
+pub extern "C" fn double_fault_task(err_code: u32) -> !
 {
-    panic!("Throw breakpoint exception.");
-}
\ No newline at end of file
+    // faulted task may hold the screen lock
+    unsafe { VGA_SCREEN.force_unlock(); }
+
+    let cpu = match smp::current_cpu()
+    {
+        Some(cpu) => cpu,
+        None => panic!("Throw {} exception (0x{:x})", get_ex_name(EX_INT_DOUBLE_FAULT), EX_INT_DOUBLE_FAULT)
+    };
+
+    let prev_task_link = cpu.get_df_tss().prev_task_link;
+    let (eip, ebp) = (cpu.get_tss().eip, cpu.get_tss().ebp);
+    backtrace::set_fault_context(eip, ebp);
+
+    panic!("Throw {} exception (0x{:x}) on CPU{}\nERR: 0x{:08x} CR2: 0x{:08x} TSS: 0x{:04x}\n{}",
+        get_ex_name(EX_INT_DOUBLE_FAULT),
+        EX_INT_DOUBLE_FAULT,
+        cpu.get_cpu_index(),
+        err_code,
+        asm::get_cr2(),
+        prev_task_link as u16,
+        cpu.get_tss());
+}
@@ -0,0 +1,254 @@
+// x87 FPU and SSE
+// state is saved lazily: switching context only sets CR0.TS,
+// first FPU instruction raises #NM and the state is swapped there
+
+use core::ptr::null_mut;
+
+use alloc::format;
+
+use crate::{debug::backtrace, util::logger::*};
+
+use super::{asm, cpuid::{self, CpuFeature}, ex_int::{self, EX_INT_CPROC_NOT_AVAILABLE, EX_INT_FLOATING_POINT, EX_INT_SIMD_FLOATING_POINT}, isr::{self, InterruptFrame}, smp, usermode};
+
+const CR0_MP: u32 = 0x2;
+const CR0_EM: u32 = 0x4;
+const CR0_TS: u32 = 0x8;
+const CR0_NE: u32 = 0x20;
+const CR4_OSFXSR: u32 = 0x200;
+const CR4_OSXMMEXCPT: u32 = 0x400;
+
+// all exceptions masked, round to nearest
+const MXCSR_DEFAULT: u32 = 0x1f80;
+const MXCSR_EXCEPTION_FLAGS: u32 = 0x3f;
+
+pub const FPU_STATE_SIZE: usize = 512;
+const FPU_STATE_ALIGN: usize = 16;
+
+/// saved FPU state of a task, must not be moved after first use
+#[derive(Debug)]
+pub struct FpuContext
+{
+    // fxsave area is aligned inside, heap doesn't guarantee alignment
+    area: [u8; FPU_STATE_SIZE + FPU_STATE_ALIGN],
+    // false until the task uses FPU
+    is_used: bool
+}
+
+impl FpuContext
+{
+    pub fn new() -> FpuContext
+    {
+        return FpuContext { area: [0; FPU_STATE_SIZE + FPU_STATE_ALIGN], is_used: false };
+    }
+
+    fn get_area_addr(&self) -> u32
+    {
+        let addr = self.area.as_ptr() as u32;
+        return (addr + FPU_STATE_ALIGN as u32 - 1) & !(FPU_STATE_ALIGN as u32 - 1);
+    }
+
+    fn save(&mut self)
+    {
+        let addr = self.get_area_addr();
+
+        if cpuid::has_feature(CpuFeature::Fxsr)
+        {
+            asm::fxsave(addr);
+        }
+        else
+        {
+            asm::fnsave(addr);
+        }
+    }
+
+    fn restore(&mut self)
+    {
+        if !self.is_used
+        {
+            asm::fninit();
+
+            if cpuid::has_feature(CpuFeature::Sse)
+            {
+                asm::ldmxcsr(MXCSR_DEFAULT);
+            }
+
+            self.is_used = true;
+            return;
+        }
+
+        let addr = self.get_area_addr();
+
+        if cpuid::has_feature(CpuFeature::Fxsr)
+        {
+            asm::fxrstor(addr);
+        }
+        else
+        {
+            asm::frstor(addr);
+        }
+    }
+}
+
+/// enable FPU and SSE on the current CPU
+pub fn init()
+{
+    if !cpuid::has_feature(CpuFeature::Fpu)
+    {
+        log_warn("FPU is not available");
+        return;
+    }
+
+    // native #MF instead of IRQ13, WAIT/FWAIT respects TS
+    let cr0 = asm::get_cr0();
+    asm::set_cr0((cr0 & !(CR0_EM | CR0_TS)) | CR0_MP | CR0_NE);
+    asm::fninit();
+
+    let mut cr4 = asm::get_cr4();
+
+    if cpuid::has_feature(CpuFeature::Fxsr)
+    {
+        cr4 |= CR4_OSFXSR;
+    }
+
+    if cpuid::has_feature(CpuFeature::Sse)
+    {
+        cr4 |= CR4_OSXMMEXCPT;
+    }
+
+    asm::set_cr4(cr4);
+
+    if cpuid::has_feature(CpuFeature::Sse)
+    {
+        asm::ldmxcsr(MXCSR_DEFAULT);
+    }
+
+    isr::register_handler(EX_INT_CPROC_NOT_AVAILABLE, ex_fpu_not_available);
+    isr::register_handler(EX_INT_FLOATING_POINT, ex_fpu_error);
+    isr::register_handler(EX_INT_SIMD_FLOATING_POINT, ex_simd_error);
+
+    // state in FPU belongs to kernel
+    if let Some(cpu) = smp::current_cpu()
+    {
+        let kernel_fpu = cpu.get_kernel_fpu_ptr();
+        unsafe { (*kernel_fpu).is_used = true; }
+        cpu.set_fpu_current(kernel_fpu);
+        cpu.set_fpu_owner(kernel_fpu);
+    }
+}
+
+pub fn is_enabled() -> bool
+{
+    return asm::get_cr0() & CR0_EM == 0 && cpuid::has_feature(CpuFeature::Fpu);
+}
+
+/// switch FPU context of the current CPU, state is swapped on next FPU use
+/// must be called with interrupts disabled
+pub fn switch_to(ctx: *mut FpuContext)
+{
+    let cpu = match smp::current_cpu()
+    {
+        Some(cpu) => cpu,
+        None => return
+    };
+
+    cpu.set_fpu_current(ctx);
+
+    if cpu.get_fpu_owner() == ctx
+    {
+        asm::clts();
+    }
+    else
+    {
+        asm::set_cr0(asm::get_cr0() | CR0_TS);
+    }
+}
+
+/// switch back to kernel context
+pub fn switch_to_kernel()
+{
+    if let Some(cpu) = smp::current_cpu()
+    {
+        switch_to(cpu.get_kernel_fpu_ptr());
+    }
+}
+
+/// forget the context, its state in FPU is discarded
+/// must be called with interrupts disabled
+pub fn release(ctx: *mut FpuContext)
+{
+    if let Some(cpu) = smp::current_cpu()
+    {
+        if cpu.get_fpu_owner() == ctx
+        {
+            cpu.set_fpu_owner(null_mut());
+        }
+    }
+}
+
+/// device not available (#NM), swap FPU state
+fn ex_fpu_not_available(frame: &mut InterruptFrame)
+{
+    if !is_enabled()
+    {
+        ex_int::throw(frame);
+    }
+
+    let cpu = match smp::current_cpu()
+    {
+        Some(cpu) => cpu,
+        None => ex_int::throw(frame)
+    };
+
+    asm::clts();
+
+    let owner = cpu.get_fpu_owner();
+    let current = cpu.get_fpu_current();
+
+    if owner == current
+    {
+        return;
+    }
+
+    unsafe
+    {
+        if !owner.is_null()
+        {
+            (*owner).save();
+        }
+
+        (*current).restore();
+    }
+
+    cpu.set_fpu_owner(current);
+}
+
+/// x87 floating point error (#MF)
+fn ex_fpu_error(frame: &mut InterruptFrame)
+{
+    let fsw = asm::fnstsw();
+    let fcw = asm::fnstcw();
+    asm::fnclex();
+
+    report(frame, &format!("FSW: 0x{:04x} FCW: 0x{:04x}", fsw, fcw));
+}
+
+/// SIMD floating point error (#XM)
+fn ex_simd_error(frame: &mut InterruptFrame)
+{
+    let mxcsr = asm::stmxcsr();
+    asm::ldmxcsr(mxcsr & !MXCSR_EXCEPTION_FLAGS);
+
+    report(frame, &format!("MXCSR: 0x{:08x}", mxcsr));
+}
+
+fn report(frame: &InterruptFrame, state: &str) -> !
+{
+    if frame.is_from_user()
+    {
+        log_warn(&format!("{} exception in user mode, {}", ex_int::get_ex_name(frame.vector), state));
+        usermode::kill(frame);
+    }
+
+    backtrace::set_fault_context(frame.eip, frame.ebp);
+    panic!("Throw {} exception (0x{:x})\n{}\n{}", ex_int::get_ex_name(frame.vector), frame.vector, state, frame);
+}
@@ -1,10 +1,10 @@
-// intel 8259A interrupt controller on PC/AT
+// intel 8259A interrupt controller on PC/AT, or I/O APIC if available
 
 use crate::{data::fifo::Fifo, util::logger::*};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use super::asm;
+use super::{apic::{APIC, APIC_SPURIOUS_VECTOR}, asm, isr::{self, InterruptFrame}};
 
 lazy_static!
 {
@@ -22,6 +22,7 @@ const DISALLOW_ALL_INTERRUPTS: u8 = 0xff;
 const EDGE_TRIGGER_MODE: u8 = 0x11;
 const NONE_BUFFER_MODE: u8 = 0x01;
 const EOI_COMMAND: u8 = 0x20;
+const READ_ISR_COMMAND: u8 = 0x0b;
 
 // master pic
 pub const INT_VECTOR_IRQ0: u32 = 0x20;    // system timer
@@ -43,6 +44,32 @@ pub const INT_VECTOR_IRQ13: u32 = 0x2d;   // coprocessor
 pub const INT_VECTOR_IRQ14: u32 = 0x2e;   // HDD conteroller
 pub const INT_VECTOR_IRQ15: u32 = 0x2f;   // HDD controller
 
+pub const IRQ_CNT: usize = 16;
+pub const IRQ_SYSTEM_TIMER: u8 = 0;
+pub const IRQ_KEYBOARD: u8 = 1;
+pub const IRQ_CASCADE: u8 = 2;
+pub const IRQ_COM2: u8 = 3;
+pub const IRQ_COM1: u8 = 4;
+pub const IRQ_LPT1: u8 = 7;
+pub const IRQ_RTC: u8 = 8;
+pub const IRQ_MOUSE: u8 = 12;
+pub const IRQ_SECONDARY_ATA: u8 = 15;
+
+pub type IrqHandler = fn(&mut InterruptFrame);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum InterruptController
+{
+    Pic,
+    Apic
+}
+
+lazy_static!
+{
+    static ref IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_CNT]> = Mutex::new([None; IRQ_CNT]);
+    static ref INT_CONTROLLER: Mutex<InterruptController> = Mutex::new(InterruptController::Pic);
+}
+
 // mouse
 const PORT_KEYDAT: u32 = 0x0060;
 const PORT_KEYCMD: u32 = 0x0064;
@@ -70,19 +97,69 @@ pub fn init_pic()
     asm::out8(SLAVE_PIC_ADDR + 1, 2);
     asm::out8(SLAVE_PIC_ADDR + 1, NONE_BUFFER_MODE);
 
-    // mask all
-    asm::out8(MASTER_PIC_ADDR + 1, 0xfb);
-    asm::out8(SLAVE_PIC_ADDR + 1, 0xff);
+    // mask all except cascade
+    asm::out8(MASTER_PIC_ADDR + 1, !(1 << IRQ_CASCADE));
+    asm::out8(SLAVE_PIC_ADDR + 1, DISALLOW_ALL_INTERRUPTS);
 
-    // allow interrupts
-    asm::out8(MASTER_PIC_ADDR + 1, 0xf9);   // allow IRQ0-7
-    asm::out8(SLAVE_PIC_ADDR + 1, 0xef);    // allow IRQ8-15
+    // route all IRQ vectors to the dispatcher
+    for i in 0..IRQ_CNT as u32
+    {
+        isr::register_handler(INT_VECTOR_IRQ0 + i, irq_int);
+    }
 
     init_keyboard();
 
+    if let Err(msg) = register_irq_handler(IRQ_KEYBOARD, keyboard_int)
+    {
+        log_error(msg);
+    }
+
     log_info("PIC initialized");
 }
 
+/// switch IRQ routing from PIC to I/O APIC, keeps PIC on failure
+pub fn enable_apic()
+{
+    let is_int_enabled = asm::disable_int();
+
+    if let Err(msg) = APIC.lock().init()
+    {
+        asm::restore_int(is_int_enabled);
+        log_warn(msg);
+        return;
+    }
+
+    // mask PIC, spurious PIC interrupts are still raised and dropped by irq_int
+    asm::out8(MASTER_PIC_ADDR + 1, DISALLOW_ALL_INTERRUPTS);
+    asm::out8(SLAVE_PIC_ADDR + 1, DISALLOW_ALL_INTERRUPTS);
+
+    isr::register_handler(APIC_SPURIOUS_VECTOR, apic_spurious_int);
+    *INT_CONTROLLER.lock() = InterruptController::Apic;
+
+    // move registered IRQs to I/O APIC
+    let handlers = *IRQ_HANDLERS.lock();
+
+    for irq in 0..IRQ_CNT as u8
+    {
+        if handlers[irq as usize].is_some()
+        {
+            unmask_irq(irq);
+        }
+    }
+
+    asm::restore_int(is_int_enabled);
+    log_info("APIC initialized");
+}
+
+pub fn get_int_controller() -> InterruptController
+{
+    let is_int_enabled = asm::disable_int();
+    let controller = *INT_CONTROLLER.lock();
+    asm::restore_int(is_int_enabled);
+
+    return controller;
+}
+
 fn init_keyboard()
 {
     wait_kbc_send_ready();
@@ -97,29 +174,171 @@ pub fn enable_mouse()
     asm::out8(PORT_KEYCMD, KEYCMD_SENDTO_MOUSE);
     wait_kbc_send_ready();
     asm::out8(PORT_KEYDAT, MOUSECMD_ENABLE);
+
+    if let Err(msg) = register_irq_handler(IRQ_MOUSE, mouse_int)
+    {
+        log_error(msg);
+    }
+}
+
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Result<(), &'static str>
+{
+    if irq as usize >= IRQ_CNT || irq == IRQ_CASCADE
+    {
+        return Err("Invalid IRQ number");
+    }
+
+    let is_int_enabled = asm::disable_int();
+    let mut handlers = IRQ_HANDLERS.lock();
+
+    if handlers[irq as usize].is_some()
+    {
+        drop(handlers);
+        asm::restore_int(is_int_enabled);
+        return Err("IRQ handler is already registered");
+    }
+
+    handlers[irq as usize] = Some(handler);
+    drop(handlers);
+
+    unmask_irq(irq);
+    asm::restore_int(is_int_enabled);
+
+    return Ok(());
+}
+
+pub fn unregister_irq_handler(irq: u8) -> Result<(), &'static str>
+{
+    if irq as usize >= IRQ_CNT || irq == IRQ_CASCADE
+    {
+        return Err("Invalid IRQ number");
+    }
+
+    let is_int_enabled = asm::disable_int();
+    mask_irq(irq);
+    IRQ_HANDLERS.lock()[irq as usize] = None;
+    asm::restore_int(is_int_enabled);
+
+    return Ok(());
+}
+
+pub fn mask_irq(irq: u8)
+{
+    if get_int_controller() == InterruptController::Apic
+    {
+        APIC.lock().mask_irq(irq);
+    }
+    else if irq < 8
+    {
+        let mask = asm::in8(MASTER_PIC_ADDR + 1);
+        asm::out8(MASTER_PIC_ADDR + 1, mask | (1 << irq));
+    }
+    else
+    {
+        let mask = asm::in8(SLAVE_PIC_ADDR + 1);
+        asm::out8(SLAVE_PIC_ADDR + 1, mask | (1 << (irq - 8)));
+    }
+}
+
+pub fn unmask_irq(irq: u8)
+{
+    if get_int_controller() == InterruptController::Apic
+    {
+        APIC.lock().unmask_irq(irq);
+    }
+    else if irq < 8
+    {
+        let mask = asm::in8(MASTER_PIC_ADDR + 1);
+        asm::out8(MASTER_PIC_ADDR + 1, mask & !(1 << irq));
+    }
+    else
+    {
+        let mask = asm::in8(SLAVE_PIC_ADDR + 1);
+        asm::out8(SLAVE_PIC_ADDR + 1, mask & !(1 << (irq - 8)));
+    }
 }
 
 /// PS/2 keyboard interrupt
-pub extern "C" fn keyboard_int()
+fn keyboard_int(_frame: &mut InterruptFrame)
 {
-    let data = asm::in8(0x60);
+    let data = asm::in8(PORT_KEYDAT);
     KEYBUF.lock().put(data).unwrap();
-    done_int();
 }
 
 /// PS/2 mouse interrupt
-pub extern "C" fn mouse_int()
+fn mouse_int(_frame: &mut InterruptFrame)
 {
     let data = asm::in8(PORT_KEYDAT);
     MOUSEBUF.lock().put(data).unwrap();
-    done_int();
 }
 
-fn done_int()
+/// dispatcher for INT_VECTOR_IRQ0 - INT_VECTOR_IRQ15
+fn irq_int(frame: &mut InterruptFrame)
+{
+    let irq = (frame.vector - INT_VECTOR_IRQ0) as u8;
+    let controller = get_int_controller();
+
+    // PIC and I/O APIC use the same vectors, masked PIC can still raise spurious IRQ7 and IRQ15
+    let is_from_pic = controller == InterruptController::Pic || !APIC.lock().is_in_service(frame.vector);
+
+    if is_from_pic && is_spurious_irq(irq)
+    {
+        // master PIC doesn't know that IRQ15 was spurious
+        if irq == IRQ_SECONDARY_ATA
+        {
+            asm::out8(MASTER_PIC_ADDR, EOI_COMMAND);
+        }
+
+        return;
+    }
+
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+
+    if let Some(handler) = handler
+    {
+        handler(frame);
+    }
+
+    if controller == InterruptController::Apic
+    {
+        APIC.lock().send_eoi();
+    }
+    else
+    {
+        send_eoi(irq);
+    }
+}
+
+/// APIC spurious interrupt, must not send EOI
+fn apic_spurious_int(_frame: &mut InterruptFrame) {}
+
+// IRQ7 and IRQ15 may be raised without their ISR bit set
+fn is_spurious_irq(irq: u8) -> bool
+{
+    if irq == IRQ_LPT1
+    {
+        asm::out8(MASTER_PIC_ADDR, READ_ISR_COMMAND);
+        return asm::in8(MASTER_PIC_ADDR) & (1 << 7) == 0;
+    }
+
+    if irq == IRQ_SECONDARY_ATA
+    {
+        asm::out8(SLAVE_PIC_ADDR, READ_ISR_COMMAND);
+        return asm::in8(SLAVE_PIC_ADDR) & (1 << 7) == 0;
+    }
+
+    return false;
+}
+
+fn send_eoi(irq: u8)
 {
     // write EOI command to PIC
+    if irq >= 8
+    {
+        asm::out8(SLAVE_PIC_ADDR, EOI_COMMAND);
+    }
+
     asm::out8(MASTER_PIC_ADDR, EOI_COMMAND);
-    asm::out8(SLAVE_PIC_ADDR, EOI_COMMAND);
 }
 
 fn wait_kbc_send_ready()
@@ -0,0 +1,139 @@
+// interrupt entry stubs are in x86/isr.asm
+
+use core::fmt;
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::util::logger::*;
+
+use super::{asm, ex_int::{self, EX_INT_MAX}};
+
+pub const IDT_ENTRIES: usize = 256;
+
+pub type InterruptHandler = fn(&mut InterruptFrame);
+
+extern
+{
+    static isr_stub_table: [u32; IDT_ENTRIES];
+}
+
+lazy_static!
+{
+    static ref INT_HANDLERS: Mutex<[Option<InterruptHandler>; IDT_ENTRIES]> = Mutex::new([None; IDT_ENTRIES]);
+}
+
+/// registers saved by the entry stubs
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct InterruptFrame
+{
+    // pushed by isr_common
+    pub gs: u32,
+    pub fs: u32,
+    pub es: u32,
+    pub ds: u32,
+    // pushad
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    pub esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    // pushed by stub
+    pub vector: u32,
+    // pushed by CPU, or dummy 0
+    pub err_code: u32,
+    // pushed by CPU
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+    // pushed by CPU only on privilege change from ring 3
+    pub user_esp: u32,
+    pub user_ss: u32
+}
+
+impl InterruptFrame
+{
+    pub fn is_from_user(&self) -> bool
+    {
+        return self.cs & 0x3 == 3;
+    }
+}
+
+impl fmt::Display for InterruptFrame
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "EIP: 0x{:08x} CS: 0x{:04x} EFLAGS: 0x{:08x}", self.eip, self.cs, self.eflags)?;
+
+        if ex_int::has_err_code(self.vector)
+        {
+            write!(f, " ERR: 0x{:08x}", self.err_code)?;
+        }
+
+        writeln!(f, "")?;
+        writeln!(f, "EAX: 0x{:08x} EBX: 0x{:08x} ECX: 0x{:08x} EDX: 0x{:08x}", self.eax, self.ebx, self.ecx, self.edx)?;
+        writeln!(f, "ESI: 0x{:08x} EDI: 0x{:08x} EBP: 0x{:08x} ESP: 0x{:08x}", self.esi, self.edi, self.ebp, self.esp)?;
+        write!(f, "DS: 0x{:04x} ES: 0x{:04x} FS: 0x{:04x} GS: 0x{:04x}", self.ds as u16, self.es as u16, self.fs as u16, self.gs as u16)?;
+
+        if self.is_from_user()
+        {
+            write!(f, "\nUSER ESP: 0x{:08x} USER SS: 0x{:04x}", self.user_esp, self.user_ss as u16)?;
+        }
+
+        return Ok(());
+    }
+}
+
+pub fn get_stub_addr(vector: u32) -> u32
+{
+    return unsafe { isr_stub_table[vector as usize] };
+}
+
+pub fn register_handler(vector: u32, handler: InterruptHandler)
+{
+    if vector as usize >= IDT_ENTRIES
+    {
+        return;
+    }
+
+    let is_int_enabled = asm::disable_int();
+    INT_HANDLERS.lock()[vector as usize] = Some(handler);
+    asm::restore_int(is_int_enabled);
+}
+
+pub fn unregister_handler(vector: u32)
+{
+    if vector as usize >= IDT_ENTRIES
+    {
+        return;
+    }
+
+    let is_int_enabled = asm::disable_int();
+    INT_HANDLERS.lock()[vector as usize] = None;
+    asm::restore_int(is_int_enabled);
+}
+
+/// called by isr_common
+#[no_mangle]
+pub extern "C" fn isr_handler(frame: &mut InterruptFrame)
+{
+    let handler = INT_HANDLERS.lock()[frame.vector as usize];
+
+    match handler
+    {
+        Some(handler) => handler(frame),
+        None =>
+        {
+            if frame.vector <= EX_INT_MAX
+            {
+                ex_int::throw(frame);
+            }
+
+            log_warn("Unhandled interrupt");
+        }
+    }
+}
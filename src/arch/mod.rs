@@ -2,4 +2,14 @@ pub mod vga;
 pub mod asm;
 pub mod sgm;
 pub mod int;
-pub mod ex_int;
\ No newline at end of file
+pub mod ex_int;
+pub mod isr;
+pub mod pit;
+pub mod rtc;
+pub mod acpi;
+pub mod power;
+pub mod apic;
+pub mod smp;
+pub mod cpuid;
+pub mod usermode;
+pub mod fpu;
\ No newline at end of file
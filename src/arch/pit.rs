@@ -0,0 +1,252 @@
+// intel 8253/8254 programmable interval timer
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::util::logger::*;
+
+use super::{asm, int::{self, IRQ_SYSTEM_TIMER}, isr::InterruptFrame};
+
+const PIT_BASE_FREQ: u32 = 1193182;
+const PORT_CH0_DATA: u32 = 0x0040;
+const PORT_CMD: u32 = 0x0043;
+// channel 0, lobyte/hibyte access, mode 2 (rate generator), binary
+const CMD_CH0_RATE_GENERATOR: u8 = 0x34;
+
+// unused POST code port, a write takes about 1us on ISA bus
+const PORT_IO_DELAY: u32 = 0x0080;
+const IO_DELAYS_PER_MS: u64 = 1000;
+
+pub const DEFAULT_FREQ: u32 = 1000; // 1 tick = 1ms
+const MAX_ONESHOT_TIMERS: usize = 32;
+
+pub type TimerCallback = fn();
+
+#[derive(Debug, Clone, Copy)]
+struct OneshotTimer
+{
+    expire_tick: u64,
+    callback: TimerCallback
+}
+
+/// timeout of polling loop, it also expires with interrupts disabled or before PIT is initialized
+/// each check waits about 1us, ticks end it earlier if timer interrupt is delivered
+#[derive(Debug)]
+pub struct Timeout
+{
+    expire_tick: Option<u64>,
+    remaining_delays: u64
+}
+
+impl Timeout
+{
+    pub fn new(ms: u64) -> Timeout
+    {
+        let expire_tick = if is_init() { Some(get_ticks() + ms_to_ticks(ms)) } else { None };
+        return Timeout { expire_tick, remaining_delays: ms * IO_DELAYS_PER_MS };
+    }
+
+    /// check once per loop iteration
+    pub fn is_expired(&mut self) -> bool
+    {
+        if self.remaining_delays == 0
+        {
+            return true;
+        }
+
+        if let Some(expire_tick) = self.expire_tick
+        {
+            if get_ticks() >= expire_tick
+            {
+                return true;
+            }
+        }
+
+        io_delay();
+        self.remaining_delays -= 1;
+
+        return false;
+    }
+}
+
+lazy_static!
+{
+    static ref TICKS: Mutex<u64> = Mutex::new(0);
+    static ref FREQ: Mutex<u32> = Mutex::new(0);
+    static ref ONESHOT_TIMERS: Mutex<[Option<OneshotTimer>; MAX_ONESHOT_TIMERS]> = Mutex::new([None; MAX_ONESHOT_TIMERS]);
+}
+
+pub fn init(freq: u32)
+{
+    if freq == 0 || freq > PIT_BASE_FREQ
+    {
+        log_error("Invalid PIT frequency");
+        return;
+    }
+
+    // divisor 0 means 65536
+    let divisor = (PIT_BASE_FREQ / freq).clamp(1, 0x10000);
+    *FREQ.lock() = PIT_BASE_FREQ / divisor;
+
+    asm::out8(PORT_CMD, CMD_CH0_RATE_GENERATOR);
+    asm::out8(PORT_CH0_DATA, divisor as u8);
+    asm::out8(PORT_CH0_DATA, (divisor >> 8) as u8);
+
+    if let Err(msg) = int::register_irq_handler(IRQ_SYSTEM_TIMER, timer_int)
+    {
+        log_error(msg);
+        return;
+    }
+
+    log_info("PIT initialized");
+}
+
+pub fn is_init() -> bool
+{
+    return get_freq() != 0;
+}
+
+/// actual frequency (Hz)
+pub fn get_freq() -> u32
+{
+    return *FREQ.lock();
+}
+
+pub fn get_ticks() -> u64
+{
+    let is_int_enabled = asm::disable_int();
+    let ticks = *TICKS.lock();
+    asm::restore_int(is_int_enabled);
+
+    return ticks;
+}
+
+pub fn ms_to_ticks(ms: u64) -> u64
+{
+    let freq = get_freq() as u64;
+    return (ms * freq + 999) / 1000;
+}
+
+/// milliseconds since PIT was initialized
+pub fn uptime() -> u64
+{
+    let freq = get_freq() as u64;
+
+    if freq == 0
+    {
+        return 0;
+    }
+
+    return get_ticks() * 1000 / freq;
+}
+
+/// wait about 1us without timer interrupt
+pub fn io_delay()
+{
+    asm::out8(PORT_IO_DELAY, 0);
+}
+
+/// busy wait, usable with interrupts disabled or before PIT is initialized
+pub fn delay_ms(ms: u64)
+{
+    let mut timeout = Timeout::new(ms);
+    while !timeout.is_expired() {}
+}
+
+pub fn sleep_ms(ms: u64)
+{
+    if !is_init()
+    {
+        return;
+    }
+
+    let is_int_enabled = asm::get_eflags() & asm::EFLAGS_IF != 0;
+    let expire_tick = get_ticks() + ms_to_ticks(ms);
+
+    while get_ticks() < expire_tick
+    {
+        asm::sti();
+        asm::hlt();
+    }
+
+    if !is_int_enabled
+    {
+        asm::cli();
+    }
+}
+
+/// callback is called in interrupt context, returns timer id
+pub fn add_oneshot_timer(ms: u64, callback: TimerCallback) -> Result<usize, &'static str>
+{
+    if !is_init()
+    {
+        return Err("PIT wasn't initialized");
+    }
+
+    let expire_tick = get_ticks() + ms_to_ticks(ms);
+
+    let is_int_enabled = asm::disable_int();
+    let mut timers = ONESHOT_TIMERS.lock();
+    let mut result = Err("Oneshot timer is full");
+
+    for i in 0..MAX_ONESHOT_TIMERS
+    {
+        if timers[i].is_none()
+        {
+            timers[i] = Some(OneshotTimer { expire_tick, callback });
+            result = Ok(i);
+            break;
+        }
+    }
+
+    drop(timers);
+    asm::restore_int(is_int_enabled);
+
+    return result;
+}
+
+pub fn cancel_oneshot_timer(id: usize)
+{
+    if id >= MAX_ONESHOT_TIMERS
+    {
+        return;
+    }
+
+    let is_int_enabled = asm::disable_int();
+    ONESHOT_TIMERS.lock()[id] = None;
+    asm::restore_int(is_int_enabled);
+}
+
+/// system timer interrupt
+fn timer_int(_frame: &mut InterruptFrame)
+{
+    let ticks;
+
+    {
+        let mut t = TICKS.lock();
+        *t += 1;
+        ticks = *t;
+    }
+
+    let mut expired: [Option<TimerCallback>; MAX_ONESHOT_TIMERS] = [None; MAX_ONESHOT_TIMERS];
+
+    if let Some(mut timers) = ONESHOT_TIMERS.try_lock()
+    {
+        for i in 0..MAX_ONESHOT_TIMERS
+        {
+            if let Some(timer) = timers[i]
+            {
+                if timer.expire_tick <= ticks
+                {
+                    expired[i] = Some(timer.callback);
+                    timers[i] = None;
+                }
+            }
+        }
+    }
+
+    for callback in expired.iter().flatten()
+    {
+        callback();
+    }
+}
@@ -0,0 +1,229 @@
+// reboot and shutdown
+
+use core::ptr::read_unaligned;
+
+use crate::{fs::vfs::VFS, mem::mmio, util::logger::*};
+
+use super::{acpi::{ACPI, fadt::{PM1_CNT_SCI_EN, PM1_CNT_SLP_TYP_SHIFT, PM1_CNT_SLP_EN}, sdt::{self, SdtHeader, SDT_HEADER_SIZE}}, asm, pit, vga::VGA_SCREEN};
+
+// 8042 keyboard controller
+const PORT_KBC_STATUS: u32 = 0x0064;
+const PORT_KBC_CMD: u32 = 0x0064;
+const KBC_STATUS_INPUT_FULL: u8 = 0x02;
+const KBC_CMD_PULSE_RESET: u8 = 0xfe;
+
+// port and value: newer QEMU, Bochs and older QEMU, VirtualBox
+const EMULATOR_SHUTDOWN_PORTS: [(u32, u16); 3] = [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+
+const ACPI_ENABLE_TIMEOUT_MS: u64 = 3000;
+
+// AML opcodes used to find \_S5_ package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+pub fn reboot() -> !
+{
+    prepare();
+    log_info("Rebooting...");
+
+    // pulse reset line of the 8042
+    for _ in 0..0x10000
+    {
+        if asm::in8(PORT_KBC_STATUS) & KBC_STATUS_INPUT_FULL == 0
+        {
+            break;
+        }
+    }
+
+    asm::out8(PORT_KBC_CMD, KBC_CMD_PULSE_RESET);
+    wait();
+
+    // ACPI reset register
+    if let Some(fadt) = ACPI.lock().get_fadt()
+    {
+        if let Some((reset_reg, reset_value)) = fadt.get_reset_reg()
+        {
+            if reset_reg.is_system_io()
+            {
+                asm::out8(reset_reg.get_addr() as u32, reset_value);
+            }
+            else if reset_reg.is_system_mem()
+            {
+                if let Ok(reg) = mmio::ioremap(reset_reg.get_addr() as u32, 1)
+                {
+                    reg.write::<u8>(0, reset_value);
+                }
+            }
+        }
+    }
+
+    wait();
+
+    // triple fault with empty IDT
+    asm::load_idtr(0, 0);
+    asm::int3();
+
+    loop
+    {
+        asm::hlt();
+    }
+}
+
+pub fn shutdown() -> !
+{
+    prepare();
+    log_info("Shutting down...");
+
+    if let Err(msg) = acpi_shutdown()
+    {
+        log_warn(msg);
+    }
+
+    for (port, value) in EMULATOR_SHUTDOWN_PORTS.iter()
+    {
+        asm::out16(*port, *value);
+    }
+
+    log_error("Failed to shutdown, it is now safe to turn off");
+
+    loop
+    {
+        asm::cli();
+        asm::hlt();
+    }
+}
+
+// flush pending state before power off or reset
+fn prepare()
+{
+    VFS.lock().flush();
+    VGA_SCREEN.lock().flush();
+}
+
+fn wait()
+{
+    if pit::is_init()
+    {
+        pit::sleep_ms(100);
+    }
+}
+
+fn acpi_shutdown() -> Result<(), &'static str>
+{
+    let fadt = match ACPI.lock().get_fadt()
+    {
+        Some(fadt) => fadt,
+        None => return Err("ACPI: FADT was not found")
+    };
+
+    let (slp_typ_a, slp_typ_b) = find_s5(fadt.get_dsdt_addr())?;
+    let pm1a_cnt_port = fadt.get_pm1a_cnt_port();
+    let pm1b_cnt_port = fadt.get_pm1b_cnt_port();
+
+    if pm1a_cnt_port == 0
+    {
+        return Err("ACPI: PM1a control block was not found");
+    }
+
+    // switch to ACPI mode
+    let smi_cmd_port = fadt.get_smi_cmd_port();
+
+    if asm::in16(pm1a_cnt_port) & PM1_CNT_SCI_EN == 0 && smi_cmd_port != 0 && fadt.get_acpi_enable() != 0
+    {
+        asm::out8(smi_cmd_port, fadt.get_acpi_enable());
+
+        // console commands run with interrupts disabled
+        let mut timeout = pit::Timeout::new(ACPI_ENABLE_TIMEOUT_MS);
+
+        while asm::in16(pm1a_cnt_port) & PM1_CNT_SCI_EN == 0
+        {
+            if timeout.is_expired()
+            {
+                return Err("ACPI: Enable ACPI mode timed out");
+            }
+        }
+    }
+
+    asm::cli();
+    asm::out16(pm1a_cnt_port, (slp_typ_a as u16) << PM1_CNT_SLP_TYP_SHIFT | PM1_CNT_SLP_EN);
+
+    if pm1b_cnt_port != 0
+    {
+        asm::out16(pm1b_cnt_port, (slp_typ_b as u16) << PM1_CNT_SLP_TYP_SHIFT | PM1_CNT_SLP_EN);
+    }
+
+    wait();
+
+    return Err("ACPI: Failed to enter S5");
+}
+
+// find SLP_TYPa and SLP_TYPb of \_S5_ in DSDT
+fn find_s5(dsdt_addr: u64) -> Result<(u8, u8), &'static str>
+{
+    if dsdt_addr == 0 || dsdt_addr > u32::MAX as u64
+    {
+        return Err("ACPI: DSDT was not found");
+    }
+
+    let dsdt = sdt::map_table(dsdt_addr as u32)?;
+    let dsdt_addr = dsdt.get_virt_addr();
+    let dsdt_len = SdtHeader::read(dsdt_addr).get_length();
+    let read = |offset: u32| -> u8 { return unsafe { read_unaligned((dsdt_addr + offset) as *const u8) }; };
+
+    let mut offset = SDT_HEADER_SIZE as u32;
+
+    while offset + 4 < dsdt_len
+    {
+        if read(offset) != b'_' || read(offset + 1) != b'S' || read(offset + 2) != b'5' || read(offset + 3) != b'_'
+        {
+            offset += 1;
+            continue;
+        }
+
+        // NameOp _S5_ or NameOp \_S5_
+        let is_name = (offset >= 1 && read(offset - 1) == AML_NAME_OP) ||
+                      (offset >= 2 && read(offset - 2) == AML_NAME_OP && read(offset - 1) == AML_ROOT_CHAR);
+
+        if !is_name || read(offset + 4) != AML_PACKAGE_OP
+        {
+            offset += 1;
+            continue;
+        }
+
+        // skip PackageOp, PkgLength and NumElements
+        offset += 5;
+        offset += ((read(offset) >> 6) & 0x3) as u32 + 1;
+        offset += 1;
+
+        let mut read_slp_typ = || -> u8
+        {
+            let op = read(offset);
+
+            if op == AML_BYTE_PREFIX
+            {
+                offset += 2;
+                return read(offset - 1);
+            }
+
+            offset += 1;
+
+            match op
+            {
+                AML_ZERO_OP => return 0,
+                AML_ONE_OP => return 1,
+                _ => return op
+            }
+        };
+
+        let slp_typ_a = read_slp_typ();
+        let slp_typ_b = read_slp_typ();
+
+        return Ok((slp_typ_a, slp_typ_b));
+    }
+
+    return Err("ACPI: \\_S5_ was not found in DSDT");
+}
\ No newline at end of file
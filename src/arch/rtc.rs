@@ -0,0 +1,200 @@
+// MC146818 real-time clock on CMOS
+
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::util::{date_time::DateTime, logger::*};
+
+use super::{asm, int::{self, IRQ_RTC}, isr::InterruptFrame};
+
+const PORT_CMOS_ADDR: u32 = 0x0070;
+const PORT_CMOS_DATA: u32 = 0x0071;
+const CMOS_NMI_DISABLE: u8 = 0x80;
+
+const RTC_REG_SEC: u8 = 0x00;
+const RTC_REG_MIN: u8 = 0x02;
+const RTC_REG_HOUR: u8 = 0x04;
+const RTC_REG_DAY: u8 = 0x07;
+const RTC_REG_MONTH: u8 = 0x08;
+const RTC_REG_YEAR: u8 = 0x09;
+const RTC_REG_STATUS_A: u8 = 0x0a;
+const RTC_REG_STATUS_B: u8 = 0x0b;
+const RTC_REG_STATUS_C: u8 = 0x0c;
+const RTC_REG_STATUS_D: u8 = 0x0d;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_UPDATE_INT: u8 = 0x10;
+const STATUS_C_UPDATE_INT: u8 = 0x10;
+const HOUR_PM: u8 = 0x80;
+
+// MC146818 has no century, assume 20xx
+const BASE_YEAR: u16 = 2000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct RtcRegisters
+{
+    sec: u8,
+    min: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8
+}
+
+lazy_static!
+{
+    // updated by update-ended interrupt
+    static ref CURRENT_DATE_TIME: Mutex<Option<DateTime>> = Mutex::new(None);
+}
+
+pub fn init()
+{
+    let is_int_enabled = asm::disable_int();
+
+    // enable update-ended interrupt
+    let status_b = read_reg(RTC_REG_STATUS_B);
+    write_reg(RTC_REG_STATUS_B, status_b | STATUS_B_UPDATE_INT);
+    // clear pending interrupts
+    read_reg(RTC_REG_STATUS_C);
+
+    asm::restore_int(is_int_enabled);
+
+    if let Err(msg) = int::register_irq_handler(IRQ_RTC, rtc_int)
+    {
+        log_error(msg);
+        return;
+    }
+
+    *CURRENT_DATE_TIME.lock() = Some(read_date_time());
+    log_info("RTC initialized");
+}
+
+pub fn get_date_time() -> DateTime
+{
+    let is_int_enabled = asm::disable_int();
+    let current = *CURRENT_DATE_TIME.lock();
+    asm::restore_int(is_int_enabled);
+
+    if let Some(date_time) = current
+    {
+        return date_time;
+    }
+
+    return read_date_time();
+}
+
+pub fn read_date_time() -> DateTime
+{
+    let is_int_enabled = asm::disable_int();
+
+    // read twice and compare, because an update may occur while reading
+    let mut regs = read_regs();
+
+    loop
+    {
+        let tmp = read_regs();
+
+        if tmp == regs
+        {
+            break;
+        }
+
+        regs = tmp;
+    }
+
+    let status_b = read_reg(RTC_REG_STATUS_B);
+    asm::restore_int(is_int_enabled);
+
+    return convert_regs_to_date_time(regs, status_b);
+}
+
+fn read_regs() -> RtcRegisters
+{
+    while read_reg(RTC_REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+
+    return RtcRegisters
+    {
+        sec: read_reg(RTC_REG_SEC),
+        min: read_reg(RTC_REG_MIN),
+        hour: read_reg(RTC_REG_HOUR),
+        day: read_reg(RTC_REG_DAY),
+        month: read_reg(RTC_REG_MONTH),
+        year: read_reg(RTC_REG_YEAR)
+    };
+}
+
+fn convert_regs_to_date_time(regs: RtcRegisters, status_b: u8) -> DateTime
+{
+    let is_binary = status_b & STATUS_B_BINARY != 0;
+    let is_24_hour = status_b & STATUS_B_24_HOUR != 0;
+
+    let convert = |value: u8| -> u8
+    {
+        if is_binary
+        {
+            return value;
+        }
+
+        return (value >> 4) * 10 + (value & 0xf);
+    };
+
+    let is_pm = regs.hour & HOUR_PM != 0;
+    let mut hour = convert(regs.hour & !HOUR_PM);
+
+    if !is_24_hour
+    {
+        // 12AM -> 0, 12PM -> 12
+        hour %= 12;
+
+        if is_pm
+        {
+            hour += 12;
+        }
+    }
+
+    return DateTime::new(
+        BASE_YEAR + convert(regs.year) as u16,
+        convert(regs.month),
+        convert(regs.day),
+        hour,
+        convert(regs.min),
+        convert(regs.sec));
+}
+
+// NMI is disabled during the access only
+fn read_reg(reg: u8) -> u8
+{
+    asm::out8(PORT_CMOS_ADDR, CMOS_NMI_DISABLE | reg);
+    let data = asm::in8(PORT_CMOS_DATA);
+    enable_nmi();
+
+    return data;
+}
+
+fn write_reg(reg: u8, data: u8)
+{
+    asm::out8(PORT_CMOS_ADDR, CMOS_NMI_DISABLE | reg);
+    asm::out8(PORT_CMOS_DATA, data);
+    enable_nmi();
+}
+
+// address port is write-only, select read-only status D with NMI bit cleared
+fn enable_nmi()
+{
+    asm::out8(PORT_CMOS_ADDR, RTC_REG_STATUS_D);
+}
+
+/// RTC interrupt
+fn rtc_int(_frame: &mut InterruptFrame)
+{
+    // status C must be read, or IRQ8 won't be raised again
+    let status_c = read_reg(RTC_REG_STATUS_C);
+
+    if status_c & STATUS_C_UPDATE_INT != 0
+    {
+        let date_time = read_date_time();
+        *CURRENT_DATE_TIME.lock() = Some(date_time);
+    }
+}
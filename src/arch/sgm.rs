@@ -1,16 +1,37 @@
-use core::ptr::{read_volatile, write_volatile};
+use core::{fmt, mem::size_of, ptr::{read_volatile, write_volatile}};
 
-use crate::{handler, util::logger::*};
+use crate::{mem::phys_to_virt, util::logger::*};
 
-use super::asm;
+use super::{asm, ex_int::*, isr::{self, IDT_ENTRIES}};
 
-const GDT_ADDR: u32 = 0x270000;
+const GDT_ADDR: u32 = phys_to_virt(0x270000);
 const GDT_LIMIT: u32 = 0xffff;
-const IDT_ADDR: u32 = 0x26f800;
+const IDT_ADDR: u32 = phys_to_virt(0x26f800);
 const IDT_LIMIT: u32 = 0x7ff;
 const IDT_INT_SELECTOR: u32 = 0x8;
 
 const INTGATE: u8 = 0x8e;
+const TASKGATE: u8 = 0x85;
+const INTGATE_USER: u8 = 0xee; // DPL3, callable by int instruction from ring 3
+
+pub const GDT_SELECTOR_KERNEL_CODE: u16 = 0x08;
+pub const GDT_SELECTOR_KERNEL_DATA: u16 = 0x10;
+pub const GDT_SELECTOR_PERCPU: u16 = 0x18;
+pub const GDT_SELECTOR_KTSS: u16 = 0x20;
+pub const GDT_SELECTOR_USER_CODE: u16 = 0x28 | 3;
+pub const GDT_SELECTOR_USER_DATA: u16 = 0x30 | 3;
+pub const GDT_SELECTOR_DFTSS: u16 = 0x38;
+
+// GDT layout is the same on every CPU, so selectors are shared
+pub const PERCPU_GDT_ENTRIES: u32 = 8;
+pub const PERCPU_GDT_SIZE: u32 = PERCPU_GDT_ENTRIES * 8;
+
+const SGM_FLAGS_KERNEL_CODE: u16 = 0xcf9a;
+const SGM_FLAGS_KERNEL_DATA: u16 = 0xcf92;
+const SGM_FLAGS_USER_CODE: u16 = 0xcffa;
+const SGM_FLAGS_USER_DATA: u16 = 0xcff2;
+const SGM_FLAGS_PERCPU_DATA: u16 = 0x4092; // byte granularity
+const SGM_FLAGS_TSS: u16 = 0x0089;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C, packed)]
@@ -55,6 +76,59 @@ struct GateDescriptor
     base_high: u16
 }
 
+// 32bit task state segment
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C, packed)]
+pub struct TaskStateSegment
+{
+    pub prev_task_link: u32,
+    pub esp0: u32,
+    pub ss0: u32,
+    pub esp1: u32,
+    pub ss1: u32,
+    pub esp2: u32,
+    pub ss2: u32,
+    pub cr3: u32,
+    pub eip: u32,
+    pub eflags: u32,
+    pub eax: u32,
+    pub ecx: u32,
+    pub edx: u32,
+    pub ebx: u32,
+    pub esp: u32,
+    pub ebp: u32,
+    pub esi: u32,
+    pub edi: u32,
+    pub es: u32,
+    pub cs: u32,
+    pub ss: u32,
+    pub ds: u32,
+    pub fs: u32,
+    pub gs: u32,
+    pub ldt_sgm_selector: u32,
+    pub trap: u16,
+    pub iomap_base: u16
+}
+
+impl fmt::Display for TaskStateSegment
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        // copy packed fields
+        let (eip, cs, eflags, cr3) = (self.eip, self.cs, self.eflags, self.cr3);
+        let (eax, ebx, ecx, edx) = (self.eax, self.ebx, self.ecx, self.edx);
+        let (esi, edi, ebp, esp) = (self.esi, self.edi, self.ebp, self.esp);
+        let (ds, es, fs, gs, ss) = (self.ds, self.es, self.fs, self.gs, self.ss);
+
+        writeln!(f, "EIP: 0x{:08x} CS: 0x{:04x} EFLAGS: 0x{:08x} CR3: 0x{:08x}", eip, cs as u16, eflags, cr3)?;
+        writeln!(f, "EAX: 0x{:08x} EBX: 0x{:08x} ECX: 0x{:08x} EDX: 0x{:08x}", eax, ebx, ecx, edx)?;
+        writeln!(f, "ESI: 0x{:08x} EDI: 0x{:08x} EBP: 0x{:08x} ESP: 0x{:08x}", esi, edi, ebp, esp)?;
+        write!(f, "DS: 0x{:04x} ES: 0x{:04x} FS: 0x{:04x} GS: 0x{:04x} SS: 0x{:04x}", ds as u16, es as u16, fs as u16, gs as u16, ss as u16)?;
+
+        return Ok(());
+    }
+}
+
 impl GateDescriptor
 {
     fn new(base: u32, selector: u32, flags: u8) -> GateDescriptor
@@ -72,10 +146,6 @@ impl GateDescriptor
 
 pub fn init()
 {
-    use crate::arch::int::*;
-    use crate::arch::ex_int::*;
-    use core::arch::asm;
-
     // init GDT
     for i in 0..=(GDT_LIMIT / 8)
     {
@@ -88,65 +158,89 @@ pub fn init()
     write_gdt(0, gdt);
 
     // code descriptor
-    let gdt = SegmentDescriptor::new(0xffff, 0, 0xcf9a);
+    let gdt = SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_KERNEL_CODE);
     write_gdt(1, gdt);
 
     // data descriptor
-    let gdt = SegmentDescriptor::new(0xffff, 0, 0xcf92);
+    let gdt = SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_KERNEL_DATA);
     write_gdt(2, gdt);
 
-    // temp descriptor
-    // task code descriptor
-    // task data descriptor
-    // ktss descriptor
+    // per-CPU data descriptor, flat until init_percpu()
+    let gdt = SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_KERNEL_DATA);
+    write_gdt(3, gdt);
+
+    // ktss descriptor is set by init_percpu()
+
+    // user code descriptor
+    let gdt = SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_USER_CODE);
+    write_gdt(5, gdt);
+
+    // user data descriptor
+    let gdt = SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_USER_DATA);
+    write_gdt(6, gdt);
 
     asm::load_gdtr(GDT_LIMIT as i32, GDT_ADDR as i32);
     log_info("GDT initialized");
 
     // init IDT
-    for i in 0..=(IDT_LIMIT / 8)
+    // all vectors are routed to isr_handler through the entry stubs
+    for i in 0..IDT_ENTRIES as u32
     {
-        let idt = GateDescriptor::new(0, 0, 0);
+        let idt = GateDescriptor::new(isr::get_stub_addr(i), IDT_INT_SELECTOR, INTGATE);
         write_idt(i, idt);
     }
 
-    // set exceptions
-    // divided by zero
-    let idt = GateDescriptor::new(handler!(ex_divided_by_zero) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(EX_INT_DIVIDED_BY_ZERO, idt);
-
-    // double fault
-    let idt = GateDescriptor::new(handler!(ex_double_fault) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(EX_INT_DOUBLE_FAULT, idt);
+    asm::load_idtr(IDT_LIMIT as i32, IDT_ADDR as i32);
+    log_info("IDT initialized");
+}
 
-    // general protection fault
-    let idt = GateDescriptor::new(handler!(ex_general_protection_fault) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(EX_INT_GENERAL_PROTECTION_FAULT, idt);
+/// load IDT on application processors
+pub fn load_idt()
+{
+    asm::load_idtr(IDT_LIMIT as i32, IDT_ADDR as i32);
+}
 
-    // breakpoint
-    let idt = GateDescriptor::new(handler!(ex_breakpoint) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(EX_INT_BREAKPOINT, idt);
+pub fn get_bsp_gdt_addr() -> u32
+{
+    return GDT_ADDR;
+}
 
-    // set interrupts
-    // PS/2 keyboard
-    let idt = GateDescriptor::new(handler!(keyboard_int) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(INT_VECTOR_IRQ1, idt);
+/// build GDT of the current CPU at gdt_addr, then load it with per-CPU data segment (gs) and TSS
+pub fn init_percpu(gdt_addr: u32, percpu_addr: u32, percpu_size: u32, tss_addr: u32, df_tss_addr: u32)
+{
+    write_gdt_at(gdt_addr, 0, SegmentDescriptor::new(0, 0, 0));
+    write_gdt_at(gdt_addr, 1, SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_KERNEL_CODE));
+    write_gdt_at(gdt_addr, 2, SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_KERNEL_DATA));
+    write_gdt_at(gdt_addr, 3, SegmentDescriptor::new(percpu_size - 1, percpu_addr, SGM_FLAGS_PERCPU_DATA));
+    write_gdt_at(gdt_addr, 4, SegmentDescriptor::new(size_of::<TaskStateSegment>() as u32 - 1, tss_addr, SGM_FLAGS_TSS));
+    write_gdt_at(gdt_addr, 5, SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_USER_CODE));
+    write_gdt_at(gdt_addr, 6, SegmentDescriptor::new(0xffff, 0, SGM_FLAGS_USER_DATA));
+    write_gdt_at(gdt_addr, 7, SegmentDescriptor::new(size_of::<TaskStateSegment>() as u32 - 1, df_tss_addr, SGM_FLAGS_TSS));
+
+    asm::load_gdtr(PERCPU_GDT_SIZE as i32 - 1, gdt_addr as i32);
+    asm::set_gs(GDT_SELECTOR_PERCPU);
+    asm::load_tr(GDT_SELECTOR_KTSS);
+}
 
-    // PS/2 mouse
-    let idt = GateDescriptor::new(handler!(mouse_int) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(INT_VECTOR_IRQ12, idt);
+/// switch to double fault TSS of the current CPU on double fault
+/// GDT of every CPU must have its double fault TSS (see init_percpu)
+pub fn enable_double_fault_task()
+{
+    let idt = GateDescriptor::new(0, GDT_SELECTOR_DFTSS as u32, TASKGATE);
+    write_idt(EX_INT_DOUBLE_FAULT, idt);
+}
 
-    asm::load_idtr(IDT_LIMIT as i32, IDT_ADDR as i32);
-    log_info("IDT initialized");
+/// allow ring 3 to raise the vector (e.g. system call)
+/// interrupt gate, handlers take locks which are not safe against interrupts
+pub fn set_user_gate(vector: u32)
+{
+    let idt = GateDescriptor::new(isr::get_stub_addr(vector), IDT_INT_SELECTOR, INTGATE_USER);
+    write_idt(vector, idt);
 }
 
 pub fn enable_page_fault_handler()
 {
-    use crate::arch::ex_int::*;
-    use core::arch::asm;
-
-    let idt = GateDescriptor::new(handler!(ex_page_fault) as u32, IDT_INT_SELECTOR, INTGATE);
-    write_idt(EX_INT_PAGE_FAULT, idt);
+    isr::register_handler(EX_INT_PAGE_FAULT, ex_page_fault);
 }
 
 fn read_gdt(index: u32) -> Option<SegmentDescriptor>
@@ -170,9 +264,14 @@ fn write_gdt(index: u32, gdt: SegmentDescriptor)
         return;
     }
 
+    write_gdt_at(GDT_ADDR, index, gdt);
+}
+
+fn write_gdt_at(gdt_addr: u32, index: u32, gdt: SegmentDescriptor)
+{
     unsafe
     {
-        let ptr = (GDT_ADDR + index * 8) as *mut SegmentDescriptor;
+        let ptr = (gdt_addr + index * 8) as *mut SegmentDescriptor;
         write_volatile(ptr, gdt);
     }
 }
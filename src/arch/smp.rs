@@ -0,0 +1,484 @@
+// symmetric multiprocessing
+// application processors are started by INIT-SIPI-SIPI with x86/ap_trampoline.asm
+
+use core::{mem::size_of, ptr::{copy_nonoverlapping, null_mut, read_volatile, write_volatile}};
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{mem::{phys_to_virt, PAGING}, println, util::logger::*};
+
+use super::{fpu::{self, FpuContext}, acpi::{ACPI, madt::{MadtEntry, MADT_LAPIC_FLAGS_ENABLED, MADT_LAPIC_FLAGS_ONLINE_CAPABLE}}, apic::APIC, asm, int::{self, InterruptController}, isr::{self, InterruptFrame}, pit, usermode::UserExit, sgm::{self, TaskStateSegment, GDT_SELECTOR_KERNEL_CODE, GDT_SELECTOR_KERNEL_DATA, GDT_SELECTOR_PERCPU, PERCPU_GDT_ENTRIES}};
+
+pub const MAX_CPUS: usize = 16;
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+pub const DOUBLE_FAULT_STACK_SIZE: usize = 8 * 1024;
+
+pub const IPI_VECTOR_TLB_SHOOTDOWN: u32 = 0xf0;
+pub const IPI_VECTOR_RESCHEDULE: u32 = 0xf1;
+
+const AP_TRAMPOLINE_ADDR: u32 = 0x8000;
+const AP_STARTUP_TIMEOUT_MS: u64 = 1000;
+const TLB_SHOOTDOWN_TIMEOUT_MS: u64 = 100;
+// flush all TLB entries instead of single page
+const TLB_SHOOTDOWN_ALL: u32 = 0xffffffff;
+
+extern
+{
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_cr3: u32;
+    static ap_trampoline_stack: u32;
+    static ap_trampoline_entry: u32;
+    static ap_trampoline_cpu_index: u32;
+    static ap_trampoline_nxe: u32;
+    fn double_fault_task_entry();
+}
+
+/// per-CPU data area, gs points here
+#[derive(Debug)]
+#[repr(C)]
+pub struct PerCpu
+{
+    // must be first, read by current_cpu()
+    self_addr: u32,
+    cpu_index: u32,
+    apic_id: u8,
+    is_online: bool,
+    kernel_stack_top: u32,
+    // saved kernel esp while running in user mode
+    kernel_context: u32,
+    user_exit: Option<UserExit>,
+    tss: TaskStateSegment,
+    // task switched to on double fault
+    df_tss: TaskStateSegment,
+    gdt: [u64; PERCPU_GDT_ENTRIES as usize],
+    // FPU state of kernel, running context and the one loaded in FPU
+    kernel_fpu: FpuContext,
+    fpu_current: *mut FpuContext,
+    fpu_owner: *mut FpuContext
+}
+
+impl PerCpu
+{
+    fn new(cpu_index: u32, apic_id: u8, kernel_stack_top: u32, df_stack_top: u32) -> PerCpu
+    {
+        let mut tss = TaskStateSegment::default();
+        tss.esp0 = kernel_stack_top;
+        tss.ss0 = GDT_SELECTOR_KERNEL_DATA as u32;
+        // no I/O permission bitmap
+        tss.iomap_base = size_of::<TaskStateSegment>() as u16;
+
+        let mut df_tss = TaskStateSegment::default();
+        df_tss.cr3 = asm::get_cr3();
+        df_tss.eip = double_fault_task_entry as usize as u32;
+        df_tss.eflags = 0x2; // interrupts disabled
+        df_tss.esp = df_stack_top;
+        df_tss.cs = GDT_SELECTOR_KERNEL_CODE as u32;
+        df_tss.ss = GDT_SELECTOR_KERNEL_DATA as u32;
+        df_tss.ds = GDT_SELECTOR_KERNEL_DATA as u32;
+        df_tss.es = GDT_SELECTOR_KERNEL_DATA as u32;
+        df_tss.fs = GDT_SELECTOR_KERNEL_DATA as u32;
+        df_tss.gs = GDT_SELECTOR_PERCPU as u32;
+        df_tss.iomap_base = size_of::<TaskStateSegment>() as u16;
+
+        return PerCpu
+        {
+            self_addr: 0,
+            cpu_index,
+            apic_id,
+            is_online: false,
+            kernel_stack_top,
+            kernel_context: 0,
+            user_exit: None,
+            tss,
+            df_tss,
+            gdt: [0; PERCPU_GDT_ENTRIES as usize],
+            kernel_fpu: FpuContext::new(),
+            fpu_current: null_mut(),
+            fpu_owner: null_mut()
+        };
+    }
+
+    pub fn get_cpu_index(&self) -> u32
+    {
+        return self.cpu_index;
+    }
+
+    pub fn get_apic_id(&self) -> u8
+    {
+        return self.apic_id;
+    }
+
+    pub fn is_online(&self) -> bool
+    {
+        return self.is_online;
+    }
+
+    pub fn get_kernel_stack_top(&self) -> u32
+    {
+        return self.kernel_stack_top;
+    }
+
+    pub fn get_tss(&mut self) -> &mut TaskStateSegment
+    {
+        return &mut self.tss;
+    }
+
+    pub fn get_df_tss(&self) -> &TaskStateSegment
+    {
+        return &self.df_tss;
+    }
+
+    pub fn get_kernel_fpu_ptr(&mut self) -> *mut FpuContext
+    {
+        return &mut self.kernel_fpu;
+    }
+
+    pub fn get_fpu_current(&self) -> *mut FpuContext
+    {
+        return self.fpu_current;
+    }
+
+    pub fn set_fpu_current(&mut self, ctx: *mut FpuContext)
+    {
+        self.fpu_current = ctx;
+    }
+
+    pub fn get_fpu_owner(&self) -> *mut FpuContext
+    {
+        return self.fpu_owner;
+    }
+
+    pub fn set_fpu_owner(&mut self, ctx: *mut FpuContext)
+    {
+        self.fpu_owner = ctx;
+    }
+
+    pub fn get_kernel_context_ptr(&mut self) -> *mut u32
+    {
+        return &mut self.kernel_context;
+    }
+
+    pub fn is_in_user_mode(&self) -> bool
+    {
+        return self.kernel_context != 0;
+    }
+
+    pub fn take_user_exit(&mut self) -> Option<UserExit>
+    {
+        return self.user_exit.take();
+    }
+
+    pub fn set_user_exit(&mut self, user_exit: UserExit)
+    {
+        self.user_exit = Some(user_exit);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TlbShootdown
+{
+    virt_addr: u32,
+    pending_cpus: u32
+}
+
+lazy_static!
+{
+    // addresses of PerCpu, index is cpu index
+    static ref CPUS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+    static ref TLB_SHOOTDOWN: Mutex<TlbShootdown> = Mutex::new(TlbShootdown { virt_addr: 0, pending_cpus: 0 });
+    static ref TLB_SHOOTDOWN_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// set up per-CPU data of BSP and start all application processors
+pub fn init()
+{
+    let bsp_apic_id = APIC.lock().get_local_apic_id();
+    let bsp = alloc_percpu(0, bsp_apic_id);
+    let bsp_addr = bsp.self_addr;
+
+    // BSP keeps the GDT at its fixed address
+    sgm::init_percpu(sgm::get_bsp_gdt_addr(), bsp_addr, size_of::<PerCpu>() as u32, &bsp.tss as *const TaskStateSegment as u32, &bsp.df_tss as *const TaskStateSegment as u32);
+    sgm::enable_double_fault_task();
+    bsp.is_online = true;
+    CPUS.lock().push(bsp_addr);
+
+    isr::register_handler(IPI_VECTOR_TLB_SHOOTDOWN, tlb_shootdown_int);
+    isr::register_handler(IPI_VECTOR_RESCHEDULE, reschedule_int);
+
+    if int::get_int_controller() != InterruptController::Apic
+    {
+        log_warn("SMP: APIC is not enabled, application processors were not started");
+        return;
+    }
+
+    let mut ap_apic_ids = Vec::new();
+
+    if let Some(madt) = ACPI.lock().get_madt()
+    {
+        for entry in madt.get_local_apics()
+        {
+            if let MadtEntry::LocalApic { apic_id, flags, .. } = *entry
+            {
+                if apic_id != bsp_apic_id && flags & (MADT_LAPIC_FLAGS_ENABLED | MADT_LAPIC_FLAGS_ONLINE_CAPABLE) != 0
+                {
+                    ap_apic_ids.push(apic_id);
+                }
+            }
+        }
+    }
+
+    copy_trampoline();
+
+    for apic_id in ap_apic_ids
+    {
+        let cpu_index = CPUS.lock().len() as u32;
+
+        if cpu_index as usize >= MAX_CPUS
+        {
+            log_warn("SMP: Too many CPUs, ignored");
+            break;
+        }
+
+        let ap = alloc_percpu(cpu_index, apic_id);
+        CPUS.lock().push(ap.self_addr);
+
+        if let Err(msg) = start_ap(ap)
+        {
+            log_warn(msg);
+        }
+    }
+
+    log_info("SMP initialized");
+}
+
+pub fn is_init() -> bool
+{
+    let is_int_enabled = asm::disable_int();
+    let is_init = CPUS.lock().len() != 0;
+    asm::restore_int(is_int_enabled);
+
+    return is_init;
+}
+
+/// per-CPU data of the current CPU
+pub fn current_cpu() -> Option<&'static mut PerCpu>
+{
+    if !is_init()
+    {
+        return None;
+    }
+
+    let addr = asm::read_gs(0);
+    return Some(unsafe { &mut *(addr as *mut PerCpu) });
+}
+
+pub fn get_cpu_cnt() -> usize
+{
+    let is_int_enabled = asm::disable_int();
+    let cnt = CPUS.lock().iter().filter(|addr| get_percpu(**addr).is_online).count();
+    asm::restore_int(is_int_enabled);
+
+    return cnt;
+}
+
+pub fn send_reschedule_ipi(cpu_index: u32)
+{
+    let is_int_enabled = asm::disable_int();
+    let addr = CPUS.lock().get(cpu_index as usize).copied();
+    asm::restore_int(is_int_enabled);
+
+    if let Some(addr) = addr
+    {
+        let cpu = get_percpu(addr);
+
+        if cpu.is_online
+        {
+            APIC.lock().send_ipi(cpu.apic_id, IPI_VECTOR_RESCHEDULE);
+        }
+    }
+}
+
+/// invalidate a page (or all pages if virt_addr is None) on every CPU
+pub fn tlb_shootdown(virt_addr: Option<u32>)
+{
+    let virt_addr = virt_addr.unwrap_or(TLB_SHOOTDOWN_ALL);
+    flush_tlb(virt_addr);
+
+    let other_cpus = get_cpu_cnt() as u32 - 1;
+
+    if other_cpus == 0
+    {
+        return;
+    }
+
+    // one shootdown at a time
+    let _lock = TLB_SHOOTDOWN_LOCK.lock();
+
+    *TLB_SHOOTDOWN.lock() = TlbShootdown { virt_addr, pending_cpus: other_cpus };
+    APIC.lock().send_ipi_all_excluding_self(IPI_VECTOR_TLB_SHOOTDOWN);
+
+    // often called with interrupts disabled (paging lock released in page fault handler)
+    let mut timeout = pit::Timeout::new(TLB_SHOOTDOWN_TIMEOUT_MS);
+
+    while TLB_SHOOTDOWN.lock().pending_cpus != 0
+    {
+        if timeout.is_expired()
+        {
+            log_warn("SMP: TLB shootdown timed out");
+            break;
+        }
+    }
+}
+
+pub fn cpus()
+{
+    let is_int_enabled = asm::disable_int();
+    let cpus = CPUS.lock().clone();
+    asm::restore_int(is_int_enabled);
+
+    for addr in cpus
+    {
+        let cpu = get_percpu(addr);
+        println!("CPU{}: APIC ID: {} {} stack: 0x{:08x}{}",
+            cpu.cpu_index,
+            cpu.apic_id,
+            if cpu.is_online { "online" } else { "offline" },
+            cpu.kernel_stack_top,
+            if cpu.cpu_index == 0 { " (BSP)" } else { "" });
+    }
+}
+
+fn alloc_percpu(cpu_index: u32, apic_id: u8) -> &'static mut PerCpu
+{
+    let stack = Box::leak(vec![0u8; KERNEL_STACK_SIZE].into_boxed_slice());
+    let kernel_stack_top = (stack.as_ptr() as u32 + KERNEL_STACK_SIZE as u32) & !0xf;
+
+    let df_stack = Box::leak(vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let df_stack_top = (df_stack.as_ptr() as u32 + DOUBLE_FAULT_STACK_SIZE as u32) & !0xf;
+
+    let percpu = Box::leak(Box::new(PerCpu::new(cpu_index, apic_id, kernel_stack_top, df_stack_top)));
+    percpu.self_addr = percpu as *mut PerCpu as u32;
+
+    return percpu;
+}
+
+fn get_percpu(addr: u32) -> &'static PerCpu
+{
+    return unsafe { &*(addr as *const PerCpu) };
+}
+
+fn copy_trampoline()
+{
+    unsafe
+    {
+        let start = &ap_trampoline_start as *const u8;
+        let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+        copy_nonoverlapping(start, phys_to_virt(AP_TRAMPOLINE_ADDR) as *mut u8, size);
+    }
+}
+
+// address of a trampoline parameter in the copy
+fn get_trampoline_param_addr(param: &u32) -> *mut u32
+{
+    let offset = param as *const u32 as u32 - unsafe { &ap_trampoline_start as *const u8 as u32 };
+    return phys_to_virt(AP_TRAMPOLINE_ADDR + offset) as *mut u32;
+}
+
+fn start_ap(ap: &'static mut PerCpu) -> Result<(), &'static str>
+{
+    unsafe
+    {
+        write_volatile(get_trampoline_param_addr(&ap_trampoline_cr3), asm::get_cr3());
+        write_volatile(get_trampoline_param_addr(&ap_trampoline_stack), ap.kernel_stack_top);
+        write_volatile(get_trampoline_param_addr(&ap_trampoline_entry), ap_main as usize as u32);
+        write_volatile(get_trampoline_param_addr(&ap_trampoline_cpu_index), ap.cpu_index);
+        write_volatile(get_trampoline_param_addr(&ap_trampoline_nxe), PAGING.lock().is_nx_enabled() as u32);
+    }
+
+    let apic_id = ap.apic_id;
+
+    APIC.lock().send_init_ipi(apic_id);
+    pit::delay_ms(10);
+
+    for _ in 0..2
+    {
+        APIC.lock().send_startup_ipi(apic_id, (AP_TRAMPOLINE_ADDR >> 12) as u8);
+        pit::delay_ms(1);
+
+        if is_online(ap)
+        {
+            return Ok(());
+        }
+    }
+
+    let mut timeout = pit::Timeout::new(AP_STARTUP_TIMEOUT_MS);
+
+    while !is_online(ap)
+    {
+        if timeout.is_expired()
+        {
+            return Err("SMP: Application processor startup timed out");
+        }
+    }
+
+    return Ok(());
+}
+
+fn is_online(cpu: &PerCpu) -> bool
+{
+    return unsafe { read_volatile(&cpu.is_online) };
+}
+
+/// entry point of application processors, called by trampoline
+extern "C" fn ap_main(cpu_index: u32) -> !
+{
+    let addr = CPUS.lock()[cpu_index as usize];
+    let cpu = unsafe { &mut *(addr as *mut PerCpu) };
+
+    sgm::init_percpu(&cpu.gdt as *const u64 as u32, addr, size_of::<PerCpu>() as u32, &cpu.tss as *const TaskStateSegment as u32, &cpu.df_tss as *const TaskStateSegment as u32);
+    sgm::load_idt();
+    APIC.lock().init_ap();
+    fpu::init();
+
+    unsafe { write_volatile(&mut cpu.is_online, true); }
+
+    loop
+    {
+        asm::sti();
+        asm::hlt();
+    }
+}
+
+fn flush_tlb(virt_addr: u32)
+{
+    if virt_addr == TLB_SHOOTDOWN_ALL
+    {
+        asm::flush_tlb();
+    }
+    else
+    {
+        asm::invlpg(virt_addr);
+    }
+}
+
+/// TLB shootdown IPI
+fn tlb_shootdown_int(_frame: &mut InterruptFrame)
+{
+    let virt_addr = TLB_SHOOTDOWN.lock().virt_addr;
+    flush_tlb(virt_addr);
+
+    let mut tlb_shootdown = TLB_SHOOTDOWN.lock();
+    tlb_shootdown.pending_cpus = tlb_shootdown.pending_cpus.saturating_sub(1);
+    drop(tlb_shootdown);
+
+    APIC.lock().send_eoi();
+}
+
+/// reschedule IPI, no scheduler yet
+fn reschedule_int(_frame: &mut InterruptFrame)
+{
+    APIC.lock().send_eoi();
+}
\ No newline at end of file
@@ -0,0 +1,317 @@
+// ring 3 execution
+// entry and exit are in x86/usermode.asm
+
+use core::{fmt, ptr::copy_nonoverlapping};
+
+use alloc::boxed::Box;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{mem::{PAGING, addr_space::{self, AddressSpace}, phys_to_virt, USER_CODE_BASE_ADDR, USER_HEAP_BASE_ADDR, USER_HEAP_SIZE, USER_STACK_SIZE, USER_STACK_TOP_ADDR, paging::{PAGE_FLAGS_NO_EXECUTE, PAGE_FLAGS_USER, PAGE_FLAGS_WRITABLE}, phys_mem::MEM_BLOCK_SIZE, vm::{self, VmRegionKind}}, println, syscall, util::logger::*};
+
+use super::{asm, isr::InterruptFrame, ex_int, fpu::{self, FpuContext}, smp};
+
+extern
+{
+    fn user_mode_enter(entry: u32, user_stack: u32, kernel_context: *mut u32) -> u32;
+    fn user_mode_exit(kernel_context: *mut u32, value: u32) -> !;
+}
+
+lazy_static!
+{
+    // program break of user heap
+    static ref USER_BRK: Mutex<u32> = Mutex::new(USER_HEAP_BASE_ADDR);
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum UserExit
+{
+    Exited(u32),
+    Killed { vector: u32, eip: u32 }
+}
+
+impl fmt::Display for UserExit
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self
+        {
+            UserExit::Exited(code) => return write!(f, "exited with code {}", code),
+            UserExit::Killed { vector, eip } => return write!(f, "killed by {} exception (0x{:x}) at 0x{:08x}", ex_int::get_ex_name(vector), vector, eip)
+        }
+    }
+}
+
+/// register user heap and stack regions, their pages are mapped on first access
+pub fn init()
+{
+    let flags = PAGE_FLAGS_USER | PAGE_FLAGS_WRITABLE | PAGE_FLAGS_NO_EXECUTE;
+
+    // heap is grown by sbrk
+    if let Err(msg) = vm::add_region("user_heap", USER_HEAP_BASE_ADDR, 0, flags, VmRegionKind::Heap)
+    {
+        log_error(msg);
+    }
+
+    if let Err(msg) = vm::add_region("user_stack", USER_STACK_TOP_ADDR - USER_STACK_SIZE, USER_STACK_SIZE, flags, VmRegionKind::Stack)
+    {
+        log_error(msg);
+    }
+}
+
+/// run code at entry in ring 3 with addr_space until it exits or faults
+/// entry page must be mapped as user page, stack should be in user stack region
+pub fn enter(addr_space: &AddressSpace, entry: u32, user_stack_top: u32) -> Result<UserExit, &'static str>
+{
+    let kernel_context = match smp::current_cpu()
+    {
+        Some(cpu) =>
+        {
+            if cpu.is_in_user_mode()
+            {
+                return Err("Already running in user mode");
+            }
+
+            cpu.get_kernel_context_ptr()
+        },
+        None => return Err("Per-CPU data wasn't initialized")
+    };
+
+    addr_space.switch();
+
+    // FPU state of the user program
+    let mut fpu_ctx = Box::new(FpuContext::new());
+    let fpu_ctx_ptr = &mut *fpu_ctx as *mut FpuContext;
+
+    let is_int_enabled = asm::disable_int();
+    fpu::switch_to(fpu_ctx_ptr);
+    let value = unsafe { user_mode_enter(entry, user_stack_top, kernel_context) };
+    fpu::release(fpu_ctx_ptr);
+    fpu::switch_to_kernel();
+    asm::restore_int(is_int_enabled);
+
+    let cpu = smp::current_cpu().unwrap();
+    unsafe { *cpu.get_kernel_context_ptr() = 0; }
+
+    release_heap();
+    release_stack();
+    syscall::release_user_files();
+    addr_space::switch_to_kernel();
+
+    return Ok(cpu.take_user_exit().unwrap_or(UserExit::Exited(value)));
+}
+
+/// move the program break of user heap, returns previous break
+pub fn sbrk(increment: i32) -> Result<u32, &'static str>
+{
+    let mut brk = USER_BRK.lock();
+    let prev_brk = *brk;
+    let new_brk = prev_brk as i64 + increment as i64;
+
+    if new_brk < USER_HEAP_BASE_ADDR as i64 || new_brk > (USER_HEAP_BASE_ADDR + USER_HEAP_SIZE) as i64
+    {
+        return Err("Program break is out of user heap area");
+    }
+
+    let new_brk = new_brk as u32;
+
+    // pages beyond new end are freed, new pages are mapped on first access
+    vm::resize_region(USER_HEAP_BASE_ADDR, align_up(new_brk) - USER_HEAP_BASE_ADDR)?;
+
+    *brk = new_brk;
+    return Ok(prev_brk);
+}
+
+/// terminate the user program and return to enter()
+pub fn exit(code: u32) -> !
+{
+    return_to_kernel(UserExit::Exited(code), code);
+}
+
+/// terminate the user program because of an exception
+pub fn kill(frame: &InterruptFrame) -> !
+{
+    log_warn("User program was killed");
+    return_to_kernel(UserExit::Killed { vector: frame.vector, eip: frame.eip }, 0);
+}
+
+/// run test programs in ring 3
+pub fn test()
+{
+    // call $+5; pop ecx; add ecx, 0x20 (message)
+    // write(1, ecx, 22); exit(0)
+    let mut code: [u8; 59] = [0; 59];
+    let prog: [u8; 37] =
+    [
+        0xe8, 0x00, 0x00, 0x00, 0x00, 0x59, 0x83, 0xc1, 0x20,
+        0xb8, 0x02, 0x00, 0x00, 0x00, 0xbb, 0x01, 0x00, 0x00, 0x00, 0xba, 0x16, 0x00, 0x00, 0x00, 0xcd, 0x80,
+        0xb8, 0x01, 0x00, 0x00, 0x00, 0x31, 0xdb, 0xcd, 0x80,
+        0xeb, 0xfe
+    ];
+    code[..prog.len()].copy_from_slice(&prog);
+    code[prog.len()..].copy_from_slice(b"Hello from user mode!\n");
+    run_test("syscall", &code);
+
+    // privileged instruction must be killed by general protection fault
+    // mov eax, 0x2a; cli; jmp $
+    let code: [u8; 8] = [0xb8, 0x2a, 0x00, 0x00, 0x00, 0xfa, 0xeb, 0xfe];
+    run_test("privileged instruction", &code);
+
+    // data page is shared by copy-on-write, both must exit with 2 (parent doesn't see write of child)
+    // inc dword [data]; mov ebx, [data]; exit(ebx)
+    let data_addr = USER_CODE_BASE_ADDR + MEM_BLOCK_SIZE;
+    let code: [u8; 21] =
+    [
+        0xff, 0x05, 0x00, 0x10, 0x40, 0x00,
+        0x8b, 0x1d, 0x00, 0x10, 0x40, 0x00,
+        0xb8, 0x01, 0x00, 0x00, 0x00, 0xcd, 0x80,
+        0xeb, 0xfe
+    ];
+    run_fork_test(&code, data_addr, 1);
+}
+
+fn run_test(name: &str, code: &[u8])
+{
+    match load_code(code)
+    {
+        Ok(addr_space) =>
+        {
+            match enter(&addr_space, USER_CODE_BASE_ADDR, USER_STACK_TOP_ADDR)
+            {
+                Ok(user_exit) => println!("{}: user program {}", name, user_exit),
+                Err(msg) => log_error(msg)
+            }
+        },
+        Err(msg) => log_error(msg)
+    }
+}
+
+// run a clone of the address space, then the original one
+fn run_fork_test(code: &[u8], data_addr: u32, data: u32)
+{
+    let parent = match load_code(code).and_then(|addr_space| { map_data_page(&addr_space, data_addr, data)?; return Ok(addr_space); })
+    {
+        Ok(addr_space) => addr_space,
+        Err(msg) =>
+        {
+            log_error(msg);
+            return;
+        }
+    };
+
+    let child = match parent.try_clone()
+    {
+        Ok(addr_space) => addr_space,
+        Err(msg) =>
+        {
+            log_error(msg);
+            return;
+        }
+    };
+
+    for (name, addr_space) in [("fork child", &child), ("fork parent", &parent)]
+    {
+        match enter(addr_space, USER_CODE_BASE_ADDR, USER_STACK_TOP_ADDR)
+        {
+            Ok(user_exit) => println!("{}: user program {}", name, user_exit),
+            Err(msg) => log_error(msg)
+        }
+    }
+}
+
+// map a writable page holding data at addr outside of regions, freed with the address space
+fn map_data_page(addr_space: &AddressSpace, addr: u32, data: u32) -> Result<(), &'static str>
+{
+    let page = match PAGING.lock().alloc_single_page()
+    {
+        Some(page) => page,
+        None => return Err("Failed to allocate user pages")
+    };
+
+    unsafe { *(phys_to_virt(page.mem_block_start_addr as u32) as *mut u32) = data; }
+
+    addr_space.switch();
+    let result = PAGING.lock().map(addr, page.mem_block_start_addr, PAGE_FLAGS_USER | PAGE_FLAGS_WRITABLE | PAGE_FLAGS_NO_EXECUTE);
+    addr_space::switch_to_kernel();
+
+    if let Err(msg) = result
+    {
+        PAGING.lock().dealloc_single_page(page);
+        return Err(msg);
+    }
+
+    return Ok(());
+}
+
+// new address space with code mapped read-only at USER_CODE_BASE_ADDR
+// code page is freed with the address space
+fn load_code(code: &[u8]) -> Result<AddressSpace, &'static str>
+{
+    if code.len() > MEM_BLOCK_SIZE as usize
+    {
+        return Err("User program is too large");
+    }
+
+    let addr_space = AddressSpace::new()?;
+
+    let code_page = match PAGING.lock().alloc_single_page()
+    {
+        Some(page) => page,
+        None => return Err("Failed to allocate user pages")
+    };
+
+    // write through direct map, user mapping is read-only
+    unsafe { copy_nonoverlapping(code.as_ptr(), phys_to_virt(code_page.mem_block_start_addr as u32) as *mut u8, code.len()); }
+
+    // user area can be changed only while the address space is current
+    addr_space.switch();
+    let result = PAGING.lock().map(USER_CODE_BASE_ADDR, code_page.mem_block_start_addr, PAGE_FLAGS_USER);
+    addr_space::switch_to_kernel();
+
+    if let Err(msg) = result
+    {
+        PAGING.lock().dealloc_single_page(code_page);
+        return Err(msg);
+    }
+
+    return Ok(addr_space);
+}
+
+// free user heap pages and reset program break
+fn release_heap()
+{
+    let mut brk = USER_BRK.lock();
+
+    if let Err(msg) = vm::resize_region(USER_HEAP_BASE_ADDR, 0)
+    {
+        log_error(msg);
+    }
+
+    *brk = USER_HEAP_BASE_ADDR;
+}
+
+// don't leak stack of previous program
+fn release_stack()
+{
+    if let Err(msg) = vm::release_region_pages(USER_STACK_TOP_ADDR - USER_STACK_SIZE)
+    {
+        log_error(msg);
+    }
+}
+
+fn align_up(addr: u32) -> u32
+{
+    return (addr + MEM_BLOCK_SIZE - 1) & !(MEM_BLOCK_SIZE - 1);
+}
+
+fn return_to_kernel(user_exit: UserExit, value: u32) -> !
+{
+    let cpu = match smp::current_cpu()
+    {
+        Some(cpu) if cpu.is_in_user_mode() => cpu,
+        _ => panic!("Not running in user mode ({})", user_exit)
+    };
+
+    cpu.set_user_exit(user_exit);
+    unsafe { user_mode_exit(cpu.get_kernel_context_ptr(), value); }
+}
\ No newline at end of file
@@ -2,11 +2,11 @@ use core::{fmt::{self, Write}, ptr::{write_volatile, read_volatile}};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use crate::device::serial::{SerialPort, IO_PORT_COM1};
+use crate::{device::serial::{SerialPort, IO_PORT_COM1}, mem::phys_to_virt};
 
 const VGA_HEIGHT: usize = 25;
 const VGA_WIDTH: usize = 80;
-const VGA_MEM: u32 = 0xb8000;
+const VGA_MEM: u32 = phys_to_virt(0xb8000);
 
 const TAB_CHAR: char = ' ';
 const TAB_INDENT_SIZE: usize = 4;
@@ -140,6 +140,11 @@ impl VgaScreen
         self.cursor_y = 1;
     }
 
+    pub fn flush(&self)
+    {
+        self.serial_port.flush();
+    }
+
     // TODO: support escape sequence
     fn write_to_serial(&self, c: char)
     {
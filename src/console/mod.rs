@@ -1,4 +1,4 @@
-use crate::{print, println, util::logger::*, data::fifo::Fifo, device::{PCI, AHCI}, meta, mem, arch::{vga::{VGA_SCREEN, Color}, asm}, fs::vfs::VFS};
+use crate::{print, println, util::logger::*, data::fifo::Fifo, device::{PCI, AHCI}, debug, meta, mem, arch::{vga::{VGA_SCREEN, Color}, asm, pit, rtc, power, apic, smp, usermode, acpi::ACPI, cpuid::CPU_INFO}, fs::vfs::VFS};
 use alloc::{vec::Vec, string::{String, ToString}};
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -104,9 +104,25 @@ impl SystemConsole
             "iahci" => self.do_process(|| AHCI.lock().ahci_info()),
             "mfree" => self.do_process(|| mem::free()),
             "minfo" => self.do_process(|| mem::info()),
+            "slabinfo" => self.do_process(|| mem::slab::slabinfo()),
+            "vminfo" => self.do_process(|| mem::vm::info()),
             "kmeta" => self.do_process(|| meta::print_info()),
             "clear" => self.do_process(|| VGA_SCREEN.lock().cls()),
             "itest" => self.do_process(|| asm::test()),
+            "acpi" => self.do_process(|| ACPI.lock().acpi_info()),
+            "apic" => self.do_process(|| apic::info()),
+            "cpuinfo" => self.do_process(|| CPU_INFO.cpu_info()),
+            "cpus" => self.do_process(|| smp::cpus()),
+            "utest" => self.do_process(|| usermode::test()),
+            "gdb" => self.do_process(|| debug::gdb::breakpoint()),
+            "date" => self.do_process(|| println!("{}", rtc::get_date_time())),
+            "uptime" => self.do_process(||
+            {
+                let uptime = pit::uptime();
+                println!("{}.{:03}s", uptime / 1000, uptime % 1000);
+            }),
+            "reboot" => self.do_process(|| power::reboot()),
+            "shutdown" => self.do_process(|| power::shutdown()),
             "ls" => self.do_process(|| VFS.lock().ls()),
             "cd" => self.do_process(||
             {
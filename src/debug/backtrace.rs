@@ -0,0 +1,110 @@
+// stack backtrace by walking ebp frame chain
+// needs frame pointers (see .cargo/config)
+
+use core::{arch::asm, ptr::read_volatile, sync::atomic::{AtomicU32, Ordering}};
+
+use crate::{mem::{phys_to_virt, KERNEL_VIRT_BASE, PHYS_MEM_MANAGER}, println};
+
+use super::symbol::{Demangle, SYMBOL_TABLE};
+
+const MAX_FRAMES: usize = 32;
+
+// context of the last kernel exception, printed by panic handler
+static FAULT_EIP: AtomicU32 = AtomicU32::new(0);
+static FAULT_EBP: AtomicU32 = AtomicU32::new(0);
+
+/// remember faulted context, next panic prints its backtrace instead of handler's one
+pub fn set_fault_context(eip: u32, ebp: u32)
+{
+    FAULT_EIP.store(eip, Ordering::SeqCst);
+    FAULT_EBP.store(ebp, Ordering::SeqCst);
+}
+
+/// backtrace of the caller
+pub fn print()
+{
+    let ebp: u32;
+    unsafe { asm!("mov {}, ebp", out(reg) ebp); }
+    print_from(None, ebp);
+}
+
+/// backtrace of the faulted context if exists, otherwise of the caller
+pub fn print_for_panic()
+{
+    let eip = FAULT_EIP.swap(0, Ordering::SeqCst);
+    let ebp = FAULT_EBP.swap(0, Ordering::SeqCst);
+
+    if eip != 0
+    {
+        print_from(Some(eip), ebp);
+    }
+    else
+    {
+        print();
+    }
+}
+
+/// walk frames from ebp, eip is the address where the context stopped
+pub fn print_from(eip: Option<u32>, mut ebp: u32)
+{
+    println!("Backtrace:");
+
+    let mut i = 0;
+
+    if let Some(eip) = eip
+    {
+        print_frame(i, eip);
+        i += 1;
+    }
+
+    // kernel stacks are in direct map
+    let mem_limit = match PHYS_MEM_MANAGER.try_lock()
+    {
+        Some(pmm) => phys_to_virt(pmm.get_low_mem_size()),
+        None => 0
+    };
+
+    while i < MAX_FRAMES
+    {
+        if ebp == 0 || ebp & 0x3 != 0 || (mem_limit != 0 && (ebp < KERNEL_VIRT_BASE || ebp.saturating_add(8) > mem_limit))
+        {
+            break;
+        }
+
+        // [ebp]: previous ebp, [ebp + 4]: return address
+        let prev_ebp = unsafe { read_volatile(ebp as *const u32) };
+        let ret_addr = unsafe { read_volatile((ebp + 4) as *const u32) };
+
+        if ret_addr == 0
+        {
+            break;
+        }
+
+        // call instruction is before the return address
+        print_frame(i, ret_addr - 1);
+        i += 1;
+
+        // stack grows down, caller frame is always above
+        if prev_ebp <= ebp
+        {
+            break;
+        }
+
+        ebp = prev_ebp;
+    }
+}
+
+fn print_frame(index: usize, addr: u32)
+{
+    let symbol = match SYMBOL_TABLE.try_lock()
+    {
+        Some(table) => table.resolve(addr),
+        None => None
+    };
+
+    match symbol
+    {
+        Some((name, offset)) => println!("  #{:02} 0x{:08x} {}+0x{:x}", index, addr, Demangle(name), offset),
+        None => println!("  #{:02} 0x{:08x} ???", index, addr)
+    }
+}
@@ -0,0 +1,597 @@
+// GDB remote serial protocol stub
+// kernel stops on int3 (breakpoint) or trap flag (single step) and talks to gdb over serial port
+// e.g. run QEMU with "-serial pty" and "target remote /dev/pts/N" in gdb
+
+use core::{mem::size_of, ptr::{read_volatile, write_volatile}};
+
+use alloc::{format, string::String, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{arch::{asm, ex_int::{self, EX_INT_BREAKPOINT, EX_INT_SINGLE_STEP}, isr::{self, InterruptFrame}, sgm::GDT_SELECTOR_KERNEL_DATA}, device::serial::SerialPort, mem::{phys_to_virt, KERNEL_VIRT_BASE, PHYS_MEM_MANAGER}, util::logger::*};
+
+const MAX_PACKET_SIZE: usize = 4096;
+const MAX_BREAKPOINTS: usize = 32;
+
+const EFLAGS_TF: u32 = 0x100;
+const INT3: u8 = 0xcc;
+
+// SIGTRAP
+const STOP_REPLY: &str = "S05";
+
+// i386 register numbers of gdb
+const REG_EAX: usize = 0;
+const REG_ECX: usize = 1;
+const REG_EDX: usize = 2;
+const REG_EBX: usize = 3;
+const REG_ESP: usize = 4;
+const REG_EBP: usize = 5;
+const REG_ESI: usize = 6;
+const REG_EDI: usize = 7;
+const REG_EIP: usize = 8;
+const REG_EFLAGS: usize = 9;
+const REG_CS: usize = 10;
+const REG_SS: usize = 11;
+const REG_DS: usize = 12;
+const REG_ES: usize = 13;
+const REG_FS: usize = 14;
+const REG_GS: usize = 15;
+const REG_CNT: usize = 16;
+
+lazy_static!
+{
+    pub static ref GDB_STUB: Mutex<GdbStub> = Mutex::new(GdbStub::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint
+{
+    addr: u32,
+    orig_data: u8
+}
+
+pub struct GdbStub
+{
+    serial_port: Option<SerialPort>,
+    breakpoints: Vec<Breakpoint>,
+    is_connected: bool,
+    // only direct map is accessed
+    mem_limit: u32
+}
+
+impl GdbStub
+{
+    pub fn new() -> GdbStub
+    {
+        return GdbStub { serial_port: None, breakpoints: Vec::new(), is_connected: false, mem_limit: 0 };
+    }
+
+    pub fn init(&mut self, io_port: u32)
+    {
+        let mut serial_port = SerialPort::new(io_port);
+        serial_port.init();
+
+        if !serial_port.is_init()
+        {
+            log_warn("GDB stub: Failed to initialize serial port");
+            return;
+        }
+
+        self.serial_port = Some(serial_port);
+        self.mem_limit = phys_to_virt(PHYS_MEM_MANAGER.lock().get_low_mem_size());
+        log_info("GDB stub initialized");
+    }
+
+    pub fn is_init(&self) -> bool
+    {
+        return self.serial_port.is_some();
+    }
+
+    /// talk to gdb until it resumes execution
+    fn handle_exception(&mut self, frame: &mut InterruptFrame)
+    {
+        // stopped at breakpoint, eip points after int3
+        if frame.vector == EX_INT_BREAKPOINT && self.find_breakpoint(frame.eip.wrapping_sub(1)).is_some()
+        {
+            frame.eip -= 1;
+        }
+
+        frame.eflags &= !EFLAGS_TF;
+
+        // gdb asks stop reason by itself on first connection
+        if self.is_connected
+        {
+            self.send_packet(STOP_REPLY);
+        }
+
+        loop
+        {
+            let data = self.recv_packet();
+            self.is_connected = true;
+
+            // supported packets are ASCII, args are sliced at byte offsets
+            if !data.is_ascii()
+            {
+                self.send_packet("");
+                continue;
+            }
+
+            let packet = String::from_utf8(data).unwrap_or_default();
+
+            let (cmd, args) = match packet.chars().next()
+            {
+                Some(cmd) => (cmd, &packet[1..]),
+                None => continue
+            };
+
+            let reply = match cmd
+            {
+                '?' => String::from(STOP_REPLY),
+                'g' => read_regs(frame),
+                'G' => write_regs(frame, args),
+                'p' => read_reg(frame, args),
+                'P' => write_reg(frame, args),
+                'm' => self.read_mem(args),
+                'M' => self.write_mem(args),
+                'Z' => self.insert_breakpoint(args),
+                'z' => self.remove_breakpoint(args),
+                'c' | 's' =>
+                {
+                    if let Some(addr) = parse_hex(args)
+                    {
+                        frame.eip = addr;
+                    }
+
+                    if cmd == 's'
+                    {
+                        frame.eflags |= EFLAGS_TF;
+                    }
+
+                    return;
+                },
+                'D' | 'k' =>
+                {
+                    self.remove_all_breakpoints();
+                    self.is_connected = false;
+
+                    if cmd == 'D'
+                    {
+                        self.send_packet("OK");
+                    }
+
+                    return;
+                },
+                'H' => String::from("OK"),
+                'q' if args.starts_with("Supported") => format!("PacketSize={:x}", MAX_PACKET_SIZE),
+                'q' if args.starts_with("Attached") => String::from("1"),
+                // not supported
+                _ => String::new()
+            };
+
+            self.send_packet(&reply);
+        }
+    }
+
+    // m<addr>,<len>
+    fn read_mem(&self, args: &str) -> String
+    {
+        let (addr, len) = match parse_addr_len(args)
+        {
+            Some(addr_len) => addr_len,
+            None => return String::from("E01")
+        };
+
+        if !self.is_valid_mem(addr, len)
+        {
+            return String::from("E14");
+        }
+
+        let mut s = String::new();
+
+        for i in 0..len
+        {
+            let data = unsafe { read_volatile((addr + i) as *const u8) };
+            s.push_str(&format!("{:02x}", data));
+        }
+
+        return s;
+    }
+
+    // M<addr>,<len>:<data>
+    fn write_mem(&self, args: &str) -> String
+    {
+        let mut iter = args.splitn(2, ':');
+        let addr_len = iter.next().and_then(parse_addr_len);
+        let data = iter.next().unwrap_or("");
+
+        let (addr, len) = match addr_len
+        {
+            Some(addr_len) => addr_len,
+            None => return String::from("E01")
+        };
+
+        if data.len() < len as usize * 2
+        {
+            return String::from("E01");
+        }
+
+        if !self.is_valid_mem(addr, len)
+        {
+            return String::from("E14");
+        }
+
+        for i in 0..len
+        {
+            let hi = hex_to_u8(data.as_bytes()[i as usize * 2]);
+            let lo = hex_to_u8(data.as_bytes()[i as usize * 2 + 1]);
+
+            match (hi, lo)
+            {
+                (Some(hi), Some(lo)) => unsafe { write_volatile((addr + i) as *mut u8, hi << 4 | lo) },
+                _ => return String::from("E01")
+            }
+        }
+
+        return String::from("OK");
+    }
+
+    fn is_valid_mem(&self, addr: u32, len: u32) -> bool
+    {
+        match addr.checked_add(len)
+        {
+            Some(end) => return addr >= KERNEL_VIRT_BASE && end <= self.mem_limit,
+            None => return false
+        }
+    }
+
+    fn find_breakpoint(&self, addr: u32) -> Option<usize>
+    {
+        return self.breakpoints.iter().position(|bp| bp.addr == addr);
+    }
+
+    // Z0,addr,kind (software breakpoint only)
+    fn insert_breakpoint(&mut self, args: &str) -> String
+    {
+        let addr = match parse_breakpoint_args(args)
+        {
+            Some(addr) => addr,
+            None => return String::new()
+        };
+
+        if self.find_breakpoint(addr).is_some()
+        {
+            return String::from("OK");
+        }
+
+        if self.breakpoints.len() >= MAX_BREAKPOINTS || !self.is_valid_mem(addr, 1)
+        {
+            return String::from("E01");
+        }
+
+        let orig_data = unsafe { read_volatile(addr as *const u8) };
+        unsafe { write_volatile(addr as *mut u8, INT3); }
+        self.breakpoints.push(Breakpoint { addr, orig_data });
+
+        return String::from("OK");
+    }
+
+    fn remove_breakpoint(&mut self, args: &str) -> String
+    {
+        let addr = match parse_breakpoint_args(args)
+        {
+            Some(addr) => addr,
+            None => return String::new()
+        };
+
+        if let Some(i) = self.find_breakpoint(addr)
+        {
+            let bp = self.breakpoints.remove(i);
+            unsafe { write_volatile(bp.addr as *mut u8, bp.orig_data); }
+        }
+
+        return String::from("OK");
+    }
+
+    fn remove_all_breakpoints(&mut self)
+    {
+        for bp in self.breakpoints.drain(..)
+        {
+            unsafe { write_volatile(bp.addr as *mut u8, bp.orig_data); }
+        }
+    }
+
+    fn recv_byte(&self) -> u8
+    {
+        let serial_port = self.serial_port.as_ref().unwrap();
+
+        loop
+        {
+            if let Some(data) = serial_port.receive_data()
+            {
+                return data;
+            }
+        }
+    }
+
+    fn send_byte(&self, data: u8)
+    {
+        let _ = self.serial_port.as_ref().unwrap().send_data(data);
+    }
+
+    // $data#checksum
+    fn recv_packet(&self) -> Vec<u8>
+    {
+        loop
+        {
+            while self.recv_byte() != b'$' {}
+
+            let mut data = Vec::new();
+            let mut checksum: u8 = 0;
+
+            loop
+            {
+                let c = self.recv_byte();
+
+                if c == b'#' || data.len() >= MAX_PACKET_SIZE
+                {
+                    break;
+                }
+
+                checksum = checksum.wrapping_add(c);
+                data.push(c);
+            }
+
+            let hi = hex_to_u8(self.recv_byte());
+            let lo = hex_to_u8(self.recv_byte());
+
+            if let (Some(hi), Some(lo)) = (hi, lo)
+            {
+                if hi << 4 | lo == checksum
+                {
+                    self.send_byte(b'+');
+                    return data;
+                }
+            }
+
+            self.send_byte(b'-');
+        }
+    }
+
+    fn send_packet(&self, data: &str)
+    {
+        let checksum = data.bytes().fold(0u8, |sum, c| sum.wrapping_add(c));
+
+        loop
+        {
+            self.send_byte(b'$');
+
+            for c in data.bytes()
+            {
+                self.send_byte(c);
+            }
+
+            self.send_byte(b'#');
+
+            for c in format!("{:02x}", checksum).bytes()
+            {
+                self.send_byte(c);
+            }
+
+            if self.recv_byte() == b'+'
+            {
+                return;
+            }
+        }
+    }
+}
+
+pub fn init(io_port: u32)
+{
+    GDB_STUB.lock().init(io_port);
+
+    if GDB_STUB.lock().is_init()
+    {
+        isr::register_handler(EX_INT_SINGLE_STEP, ex_debug);
+        isr::register_handler(EX_INT_BREAKPOINT, ex_debug);
+    }
+}
+
+/// stop here and wait for gdb
+pub fn breakpoint()
+{
+    if !GDB_STUB.lock().is_init()
+    {
+        log_warn("GDB stub wasn't initialized");
+        return;
+    }
+
+    log_info("Waiting for GDB...");
+    asm::int3();
+}
+
+/// breakpoint (#BP) and single step (#DB)
+fn ex_debug(frame: &mut InterruptFrame)
+{
+    // user programs are not debugged
+    if frame.is_from_user()
+    {
+        ex_int::throw(frame);
+    }
+
+    let mut stub = match GDB_STUB.try_lock()
+    {
+        Some(stub) => stub,
+        None => ex_int::throw(frame)
+    };
+
+    stub.handle_exception(frame);
+}
+
+// esp before the exception, kernel traps don't push esp/ss
+fn get_kernel_esp(frame: &InterruptFrame) -> u32
+{
+    return &frame.user_esp as *const u32 as u32;
+}
+
+fn get_reg(frame: &InterruptFrame, reg: usize) -> u32
+{
+    match reg
+    {
+        REG_EAX => return frame.eax,
+        REG_ECX => return frame.ecx,
+        REG_EDX => return frame.edx,
+        REG_EBX => return frame.ebx,
+        REG_ESP => return get_kernel_esp(frame),
+        REG_EBP => return frame.ebp,
+        REG_ESI => return frame.esi,
+        REG_EDI => return frame.edi,
+        REG_EIP => return frame.eip,
+        REG_EFLAGS => return frame.eflags,
+        REG_CS => return frame.cs,
+        REG_SS => return GDT_SELECTOR_KERNEL_DATA as u32,
+        REG_DS => return frame.ds,
+        REG_ES => return frame.es,
+        REG_FS => return frame.fs,
+        REG_GS => return frame.gs,
+        _ => return 0
+    }
+}
+
+// esp, ss and segment registers can't be changed
+fn set_reg(frame: &mut InterruptFrame, reg: usize, value: u32)
+{
+    match reg
+    {
+        REG_EAX => frame.eax = value,
+        REG_ECX => frame.ecx = value,
+        REG_EDX => frame.edx = value,
+        REG_EBX => frame.ebx = value,
+        REG_EBP => frame.ebp = value,
+        REG_ESI => frame.esi = value,
+        REG_EDI => frame.edi = value,
+        REG_EIP => frame.eip = value,
+        REG_EFLAGS => frame.eflags = value,
+        _ => ()
+    }
+}
+
+fn read_regs(frame: &InterruptFrame) -> String
+{
+    let mut s = String::new();
+
+    for reg in 0..REG_CNT
+    {
+        push_hex_u32(&mut s, get_reg(frame, reg));
+    }
+
+    return s;
+}
+
+fn write_regs(frame: &mut InterruptFrame, args: &str) -> String
+{
+    if args.len() < REG_CNT * 8
+    {
+        return String::from("E01");
+    }
+
+    for reg in 0..REG_CNT
+    {
+        match parse_hex_u32_le(&args[reg * 8..reg * 8 + 8])
+        {
+            Some(value) => set_reg(frame, reg, value),
+            None => return String::from("E01")
+        }
+    }
+
+    return String::from("OK");
+}
+
+// p<reg>
+fn read_reg(frame: &InterruptFrame, args: &str) -> String
+{
+    match parse_hex(args)
+    {
+        Some(reg) if (reg as usize) < REG_CNT =>
+        {
+            let mut s = String::new();
+            push_hex_u32(&mut s, get_reg(frame, reg as usize));
+            return s;
+        },
+        _ => return String::from("E01")
+    }
+}
+
+// P<reg>=<value>
+fn write_reg(frame: &mut InterruptFrame, args: &str) -> String
+{
+    let mut iter = args.splitn(2, '=');
+    let reg = iter.next().and_then(parse_hex);
+    let value = iter.next().and_then(parse_hex_u32_le);
+
+    match (reg, value)
+    {
+        (Some(reg), Some(value)) if (reg as usize) < REG_CNT =>
+        {
+            set_reg(frame, reg as usize, value);
+            return String::from("OK");
+        },
+        _ => return String::from("E01")
+    }
+}
+
+// <type>,<addr>,<kind>
+fn parse_breakpoint_args(args: &str) -> Option<u32>
+{
+    let mut iter = args.split(',');
+
+    if iter.next()? != "0"
+    {
+        return None;
+    }
+
+    return parse_hex(iter.next()?);
+}
+
+fn parse_addr_len(args: &str) -> Option<(u32, u32)>
+{
+    let mut iter = args.splitn(2, ',');
+    let addr = parse_hex(iter.next()?)?;
+    let len = parse_hex(iter.next()?)?;
+
+    if len as usize > MAX_PACKET_SIZE / 2
+    {
+        return None;
+    }
+
+    return Some((addr, len));
+}
+
+fn parse_hex(s: &str) -> Option<u32>
+{
+    return u32::from_str_radix(s, 16).ok();
+}
+
+// register values are sent in target byte order
+fn parse_hex_u32_le(s: &str) -> Option<u32>
+{
+    if s.len() != size_of::<u32>() * 2
+    {
+        return None;
+    }
+
+    let be = parse_hex(s)?;
+    return Some(be.swap_bytes());
+}
+
+fn push_hex_u32(s: &mut String, value: u32)
+{
+    for byte in value.to_le_bytes().iter()
+    {
+        s.push_str(&format!("{:02x}", byte));
+    }
+}
+
+fn hex_to_u8(c: u8) -> Option<u8>
+{
+    return (c as char).to_digit(16).map(|d| d as u8);
+}
@@ -0,0 +1,3 @@
+pub mod backtrace;
+pub mod gdb;
+pub mod symbol;
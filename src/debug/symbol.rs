@@ -0,0 +1,253 @@
+// kernel symbol lookup with ELF symbol table loaded by bootloader
+
+use core::{fmt, mem::size_of, ptr::read_volatile, slice, str};
+
+use lazy_static::lazy_static;
+use multiboot2::BootInformation;
+use spin::Mutex;
+
+use crate::{mem::phys_to_virt, util::{boot_info::get_elf_section, logger::*}};
+
+const STT_FUNC: u8 = 2;
+
+lazy_static!
+{
+    pub static ref SYMBOL_TABLE: Mutex<SymbolTable> = Mutex::new(SymbolTable::new());
+}
+
+// Elf32_Sym
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct ElfSymbol
+{
+    name: u32,
+    value: u32,
+    size: u32,
+    info: u8,
+    other: u8,
+    shndx: u16
+}
+
+impl ElfSymbol
+{
+    fn get_type(&self) -> u8
+    {
+        return self.info & 0xf;
+    }
+}
+
+#[derive(Debug)]
+pub struct SymbolTable
+{
+    symtab_addr: u32,
+    symtab_size: u32,
+    strtab_addr: u32,
+    strtab_size: u32,
+    is_init: bool
+}
+
+impl SymbolTable
+{
+    pub fn new() -> SymbolTable
+    {
+        return SymbolTable { symtab_addr: 0, symtab_size: 0, strtab_addr: 0, strtab_size: 0, is_init: false };
+    }
+
+    pub fn init(&mut self, boot_info: &BootInformation)
+    {
+        let (symtab_addr, symtab_size) = match get_elf_section(boot_info, ".symtab")
+        {
+            Some(section) => section,
+            None =>
+            {
+                log_warn("Kernel symbol table was not found");
+                return;
+            }
+        };
+
+        let (strtab_addr, strtab_size) = match get_elf_section(boot_info, ".strtab")
+        {
+            Some(section) => section,
+            None =>
+            {
+                log_warn("Kernel string table was not found");
+                return;
+            }
+        };
+
+        // placed at physical address by bootloader
+        self.symtab_addr = phys_to_virt(symtab_addr as u32);
+        self.symtab_size = symtab_size as u32;
+        self.strtab_addr = phys_to_virt(strtab_addr as u32);
+        self.strtab_size = strtab_size as u32;
+        self.is_init = true;
+
+        log_info("Kernel symbol table loaded");
+    }
+
+    pub fn is_init(&self) -> bool
+    {
+        return self.is_init;
+    }
+
+    /// function containing the address, returns (name, offset)
+    pub fn resolve(&self, addr: u32) -> Option<(&'static str, u32)>
+    {
+        if !self.is_init
+        {
+            return None;
+        }
+
+        let mut nearest: Option<ElfSymbol> = None;
+
+        for i in 0..self.symtab_size as usize / size_of::<ElfSymbol>()
+        {
+            let sym = unsafe { read_volatile((self.symtab_addr as usize + i * size_of::<ElfSymbol>()) as *const ElfSymbol) };
+
+            if sym.get_type() != STT_FUNC || sym.value > addr
+            {
+                continue;
+            }
+
+            if sym.size != 0 && addr < sym.value + sym.size
+            {
+                nearest = Some(sym);
+                break;
+            }
+
+            // some symbols (e.g. in assembly) have no size
+            if sym.size == 0 && nearest.map_or(true, |n| n.value < sym.value)
+            {
+                nearest = Some(sym);
+            }
+        }
+
+        let sym = nearest?;
+        return Some((self.get_str(sym.name)?, addr - sym.value));
+    }
+
+    fn get_str(&self, offset: u32) -> Option<&'static str>
+    {
+        if offset >= self.strtab_size
+        {
+            return None;
+        }
+
+        let start = (self.strtab_addr + offset) as *const u8;
+        let max_len = (self.strtab_size - offset) as usize;
+        let bytes = unsafe { slice::from_raw_parts(start, max_len) };
+        let len = bytes.iter().position(|b| *b == 0).unwrap_or(max_len);
+
+        return str::from_utf8(&bytes[..len]).ok();
+    }
+}
+
+/// rust legacy mangled name (_ZN...E) as path, hash is omitted
+pub struct Demangle<'a>(pub &'a str);
+
+impl fmt::Display for Demangle<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let mut s = match self.0.strip_prefix("_ZN")
+        {
+            Some(s) => s,
+            None => return write!(f, "{}", self.0)
+        };
+
+        let mut is_first = true;
+
+        loop
+        {
+            let digits = s.bytes().take_while(|b| b.is_ascii_digit()).count();
+
+            if digits == 0
+            {
+                break;
+            }
+
+            let len: usize = s[..digits].parse().unwrap_or(0);
+            s = &s[digits..];
+
+            if len > s.len() || !s.is_char_boundary(len)
+            {
+                return write!(f, "{}", self.0);
+            }
+
+            let (ident, rest) = s.split_at(len);
+            s = rest;
+
+            // hash of the last element (h + 16 hex digits)
+            if s.starts_with('E') && ident.len() == 17 && ident.starts_with('h') && ident[1..].bytes().all(|b| b.is_ascii_hexdigit())
+            {
+                break;
+            }
+
+            if !is_first
+            {
+                write!(f, "::")?;
+            }
+
+            write_ident(f, ident)?;
+            is_first = false;
+        }
+
+        return Ok(());
+    }
+}
+
+fn write_ident(f: &mut fmt::Formatter, ident: &str) -> fmt::Result
+{
+    // leading "_" is added to idents starting with "$"
+    let mut s = if ident.starts_with("_$") { &ident[1..] } else { ident };
+
+    while s.len() != 0
+    {
+        if s.starts_with("..")
+        {
+            write!(f, "::")?;
+            s = &s[2..];
+            continue;
+        }
+
+        if s.starts_with('$')
+        {
+            if let Some(end) = s[1..].find('$')
+            {
+                let esc = &s[1..end + 1];
+
+                let c = match esc
+                {
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "RF" => Some('&'),
+                    "BP" => Some('*'),
+                    "C" => Some(','),
+                    "SP" => Some('@'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    _ if esc.starts_with('u') => u32::from_str_radix(&esc[1..], 16).ok().and_then(char::from_u32),
+                    _ => None
+                };
+
+                if let Some(c) = c
+                {
+                    write!(f, "{}", c)?;
+                    s = &s[end + 2..];
+                    continue;
+                }
+            }
+        }
+
+        let c = s.chars().next().unwrap();
+        write!(f, "{}", c)?;
+        s = &s[c.len_utf8()..];
+    }
+
+    return Ok(());
+}
+
+pub fn init(boot_info: &BootInformation)
+{
+    SYMBOL_TABLE.lock().init(boot_info);
+}
@@ -46,14 +46,20 @@ impl SerialPort
         self.is_init = true;
     }
 
+    /// received byte, None if nothing has arrived
     pub fn receive_data(&self) -> Option<u8>
     {
-        if !self.is_init
+        if !self.is_init || asm::in8(self.io_port + 5) & 0x1 == 0
         {
             return None;
         }
 
-        return Some(asm::in8(self.io_port + 5) & 1);
+        return Some(asm::in8(self.io_port));
+    }
+
+    pub fn is_init(&self) -> bool
+    {
+        return self.is_init;
     }
 
     pub fn send_data(&self, data: u8) -> Result<(), &str>
@@ -68,6 +74,17 @@ impl SerialPort
         return Ok(());
     }
 
+    /// wait until all queued data has been sent
+    pub fn flush(&self)
+    {
+        if !self.is_init
+        {
+            return;
+        }
+
+        while asm::in8(self.io_port + 5) & 0x40 == 0 {}
+    }
+
     fn is_transmit_empty(&self) -> u8
     {
         return asm::in8(self.io_port + 5) & 0x20;
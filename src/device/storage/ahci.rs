@@ -2,7 +2,7 @@ use core::{ptr::{read_volatile, write_volatile}, mem::size_of};
 
 use modular_bitfield::{bitfield, prelude::*};
 
-use crate::{util::logger::*, device::{pci::{PciDevice, BaseAddressRegister}, PCI}, println, mem::{PHYS_MEM_MANAGER, phys_mem::{MemoryBlockInfo, MEM_BLOCK_SIZE}}, print};
+use crate::{arch::pit, util::logger::*, device::{pci::{PciDevice, BaseAddressRegister}, PCI}, println, mem::{PHYS_MEM_MANAGER, phys_to_virt, mmio::{self, Mmio}, phys_mem::{MemoryBlockInfo, MEM_BLOCK_SIZE}}, print};
 
 const PCI_AHCI_BASE_CLASS_CODE: u8 = 0x01;
 const PCI_AHCI_SUB_CLASS_CODE: u8 = 0x06;
@@ -27,6 +27,11 @@ const ATA_CMD_WRITE: u8 = 0x35;
 const ATA_DEV_BUSY: u8 = 0x80;
 const ATA_DEV_DRQ: u8 = 0x08;
 
+const PORT_TIMEOUT_MS: u64 = 1000;
+
+// generic host control + port registers
+const HBA_MMIO_SIZE: u32 = (size_of::<HostBusAdapterMemoryRegisters>() + size_of::<PortControlRegisters>() * MAX_PORT_COUNT) as u32;
+
 #[derive(Debug, PartialEq)]
 enum PortType
 {
@@ -41,7 +46,7 @@ pub struct Ahci
 {
     is_init: bool,
     pci_ahci_device: PciDevice,
-    hba_base_addr: u32
+    hba_mmio: Option<Mmio>
 }
 
 impl Ahci
@@ -50,7 +55,7 @@ impl Ahci
     {
         let pci_ahci_device = PciDevice::new();
 
-        return Ahci { is_init: false, pci_ahci_device, hba_base_addr: 0 };
+        return Ahci { is_init: false, pci_ahci_device, hba_mmio: None };
     }
 
     pub fn init(&mut self)
@@ -72,14 +77,24 @@ impl Ahci
 
         let base_addr = self.pci_ahci_device.get_base_addr(PCI_AHCI_BASE_ADDR_INDEX);
 
-        if let Some(BaseAddressRegister::MemoryAddress32Bit(addr)) = base_addr
+        let hba_base_addr = match base_addr
         {
-            self.hba_base_addr = addr;
-        }
-        else
+            Some(BaseAddressRegister::MemoryAddress32Bit(addr)) => addr,
+            _ =>
+            {
+                log_warn("AHCI BAR#5 not found");
+                return;
+            }
+        };
+
+        match mmio::ioremap(hba_base_addr, HBA_MMIO_SIZE)
         {
-            log_warn("AHCI BAR#5 not found");
-            return;
+            Ok(mmio) => self.hba_mmio = Some(mmio),
+            Err(msg) =>
+            {
+                log_error(msg);
+                return;
+            }
         }
 
         // init port memory space
@@ -105,22 +120,19 @@ impl Ahci
         self.is_init = true;
     }
 
+    fn get_hba_mmio(&self) -> &Mmio
+    {
+        return self.hba_mmio.as_ref().expect("AHCI HBA memory is not mapped");
+    }
+
     fn read_hba_mem_regs(&self) -> HostBusAdapterMemoryRegisters
     {
-        unsafe
-        {
-            let ptr = self.hba_base_addr as *const HostBusAdapterMemoryRegisters;
-            return read_volatile(ptr);
-        }
+        return self.get_hba_mmio().read(0);
     }
 
     fn write_hba_mem_regs(&self, hba_mem_regs: HostBusAdapterMemoryRegisters)
     {
-        unsafe
-        {
-            let ptr = self.hba_base_addr as *mut HostBusAdapterMemoryRegisters;
-            write_volatile(ptr, hba_mem_regs);
-        }
+        self.get_hba_mmio().write(0, hba_mem_regs);
     }
 
     fn read_port_ctrl_regs(&self, port_num: usize) -> Option<PortControlRegisters>
@@ -130,14 +142,8 @@ impl Ahci
             return None;
         }
 
-        let hba_base_addr = self.hba_base_addr;
         let offset = (size_of::<HostBusAdapterMemoryRegisters>() + size_of::<PortControlRegisters>() * port_num) as u32;
-
-        unsafe
-        {
-            let ptr = (hba_base_addr + offset) as *const PortControlRegisters;
-            return Some(read_volatile(ptr));
-        }
+        return Some(self.get_hba_mmio().read(offset));
     }
 
     fn write_port_ctrl_regs(&self, port_num: usize, port_ctrl_regs: PortControlRegisters)
@@ -147,14 +153,8 @@ impl Ahci
             return;
         }
 
-        let hba_base_addr = self.hba_base_addr;
         let offset = (size_of::<HostBusAdapterMemoryRegisters>() + size_of::<PortControlRegisters>() * port_num) as u32;
-
-        unsafe
-        {
-            let ptr = (hba_base_addr + offset) as *mut PortControlRegisters;
-            write_volatile(ptr, port_ctrl_regs);
-        }
+        self.get_hba_mmio().write(offset, port_ctrl_regs);
     }
 
     fn read_cmd_header(&self, port_num: usize, header_index: u32) -> Option<CommandHeader>
@@ -170,7 +170,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = addr as *const CommandHeader;
+            let ptr = phys_to_virt(addr) as *const CommandHeader;
             return Some(read_volatile(ptr));
         }
     }
@@ -188,7 +188,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = addr as *mut CommandHeader;
+            let ptr = phys_to_virt(addr) as *mut CommandHeader;
             write_volatile(ptr, cmd_header);
         }
     }
@@ -199,7 +199,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *const CommandTable;
+            let ptr = phys_to_virt(base_addr) as *const CommandTable;
             return read_volatile(ptr);
         }
     }
@@ -217,7 +217,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *mut CommandTable;
+            let ptr = phys_to_virt(base_addr) as *mut CommandTable;
             write_volatile(ptr, cmd_table);
         }
     }
@@ -228,7 +228,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *mut PhysicalRegionDescriptorTable;
+            let ptr = phys_to_virt(base_addr) as *mut PhysicalRegionDescriptorTable;
             return read_volatile(ptr);
         }
     }
@@ -239,7 +239,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *mut PhysicalRegionDescriptorTable;
+            let ptr = phys_to_virt(base_addr) as *mut PhysicalRegionDescriptorTable;
             write_volatile(ptr, prdt);
         }
     }
@@ -250,7 +250,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *const FisHostToDeviceRegisters;
+            let ptr = phys_to_virt(base_addr) as *const FisHostToDeviceRegisters;
             return read_volatile(ptr);
         }
     }
@@ -261,7 +261,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *mut FisHostToDeviceRegisters;
+            let ptr = phys_to_virt(base_addr) as *mut FisHostToDeviceRegisters;
             write_volatile(ptr, fis);
         }
     }
@@ -272,7 +272,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *const FisDeviceToHostRegisters;
+            let ptr = phys_to_virt(base_addr) as *const FisDeviceToHostRegisters;
             return read_volatile(ptr);
         }
     }
@@ -283,7 +283,7 @@ impl Ahci
 
         unsafe
         {
-            let ptr = base_addr as *mut FisDeviceToHostRegisters;
+            let ptr = phys_to_virt(base_addr) as *mut FisDeviceToHostRegisters;
             write_volatile(ptr, fis);
         }
     }
@@ -364,6 +364,7 @@ impl Ahci
     }
 
     // TODO
+    // buf_base_addr is physical address of DMA buffer
     pub fn read(&self, port_num: usize, start_base_addr_low: u32, start_base_addr_high: u32, mut buf_base_addr: u32, mut sector_cnt: u16) -> Result<(), &str>
     {
         if !self.is_available_port_num(port_num)
@@ -435,7 +436,7 @@ impl Ahci
             self.write_fis_h2d_regs(&cmd_header, fis);
             println!("{:?}", self.read_cmd_table(&cmd_header));
 
-            let mut spin = 0;
+            let mut timeout = pit::Timeout::new(PORT_TIMEOUT_MS);
 
             // wait busy
             loop
@@ -444,25 +445,23 @@ impl Ahci
 
                 println!("tfd: {:032b}", port_ctrl_regs.task_file_data);
 
-                if ((port_ctrl_regs.task_file_data & (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) != 0) && spin < 1000000
-                {
-                    spin += 1;
-                }
-                else
+                if (port_ctrl_regs.task_file_data & (ATA_DEV_BUSY | ATA_DEV_DRQ) as u32) == 0
                 {
                     break;
                 }
-            }
 
-            if spin == 1000000
-            {
-                return Err("Port is hung");
+                if timeout.is_expired()
+                {
+                    return Err("Port is hung");
+                }
             }
 
             let mut port_ctrl_regs = self.read_port_ctrl_regs(port_num).unwrap();
             port_ctrl_regs.cmd_issue = 1 << slot;
             self.write_port_ctrl_regs(port_num, port_ctrl_regs);
 
+            let mut timeout = pit::Timeout::new(PORT_TIMEOUT_MS);
+
             loop
             {
                 println!("[AHCI]: Reading disk...");
@@ -481,6 +480,11 @@ impl Ahci
                 {
                     return Err("Read disk error when waiting");
                 }
+
+                if timeout.is_expired()
+                {
+                    return Err("Read disk timed out");
+                }
             }
 
             let port_ctrl_regs = self.read_port_ctrl_regs(port_num).unwrap();
@@ -574,11 +578,12 @@ impl Ahci
             }
         }
 
+        // blocks are in direct map, below 4GiB
         let mut port_ctrl_regs = self.read_port_ctrl_regs(port_num).unwrap();
-        port_ctrl_regs.cmd_list_base_addr_low = mbs_info[0].unwrap().mem_block_start_addr;
+        port_ctrl_regs.cmd_list_base_addr_low = mbs_info[0].unwrap().mem_block_start_addr as u32;
         port_ctrl_regs.cmd_list_base_addr_high = 0;
 
-        port_ctrl_regs.fis_base_addr_low = mbs_info[1].unwrap().mem_block_start_addr;
+        port_ctrl_regs.fis_base_addr_low = mbs_info[1].unwrap().mem_block_start_addr as u32;
         port_ctrl_regs.fis_base_addr_high = 0;
 
         self.write_port_ctrl_regs(port_num, port_ctrl_regs);
@@ -591,7 +596,7 @@ impl Ahci
                 //println!("allocated mem block (0x{:x}~) to cmd_header{}", mb_info.mem_block_start_addr, i);
 
                 cmd_header.set_phys_region_desc_table_len(8);
-                cmd_header.set_cmd_table_desc_base_addr_low(mb_info.mem_block_start_addr);
+                cmd_header.set_cmd_table_desc_base_addr_low(mb_info.mem_block_start_addr as u32);
                 cmd_header.set_cmd_table_desc_base_addr_high(0);
                 self.write_cmd_header(port_num, i, cmd_header);
             }
@@ -1,6 +1,6 @@
-use crate::util::logger::*;
+use crate::{mem::mmio::{self, Mmio}, println, util::logger::*};
 
-use super::{pci::{PciDevice, Pci, PCI_VENDOR_ID_INTEL}, PCI};
+use super::{pci::{PciDevice, Pci, BaseAddressRegister, PCI_VENDOR_ID_INTEL}, PCI};
 
 const PCI_USB_BASE_CLASS_CODE: u8 = 0x0c;
 const PCI_USB_SUB_CLASS_CODE: u8 = 0x03;
@@ -8,6 +8,12 @@ const PCI_OHCI_USB_PRGIF: u8 = 0x10;     // OHCI USB1.1
 const PCI_UHCI_USB_PRGIF: u8 = 0x0;      // UHCI USB1.1
 const PCI_EHCI_USB_PRGIF: u8 = 0x20;     // EHCI USB2.0
 const PCI_XHCI_USB_PRGIF: u8 = 0x30;     // xHCI USB3.0
+const PCI_USB_BASE_ADDR_INDEX: usize = 0;
+
+// capability and operational registers
+const USB_MMIO_SIZE: u32 = 0x1000;
+const CAP_REG_CAPLENGTH: u32 = 0x0;
+const CAP_REG_HCIVERSION: u32 = 0x2;
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
 pub enum UsbMode
@@ -23,7 +29,8 @@ pub struct Usb
 {
     is_init: bool,
     mode: UsbMode,
-    pci_usb_device: PciDevice
+    pci_usb_device: PciDevice,
+    mmio: Option<Mmio>
 }
 
 impl Usb
@@ -33,7 +40,7 @@ impl Usb
         let pci_usb_device = PciDevice::new();
         let mode = UsbMode::Ohci; // default
 
-        return Usb { is_init: false, mode, pci_usb_device };
+        return Usb { is_init: false, mode, pci_usb_device, mmio: None };
     }
 
     pub fn init(&mut self, mode: UsbMode)
@@ -78,6 +85,21 @@ impl Usb
             self.switch_ehci_to_xhci_mode();
         }
 
+        // UHCI uses I/O ports
+        if self.mode != UsbMode::Uhci
+        {
+            if let Err(msg) = self.map_regs()
+            {
+                log_warn(msg);
+                return;
+            }
+
+            let mmio = self.mmio.as_ref().unwrap();
+            let cap_len = mmio.read::<u8>(CAP_REG_CAPLENGTH);
+            let version = mmio.read::<u16>(CAP_REG_HCIVERSION);
+            println!("[USB]: {:?} controller version: 0x{:x}, operational registers: +0x{:x}", self.mode, version, cap_len);
+        }
+
         self.is_init = true;
     }
 
@@ -86,6 +108,20 @@ impl Usb
         return self.is_init;
     }
 
+    fn map_regs(&mut self) -> Result<(), &'static str>
+    {
+        let base_addr = match self.pci_usb_device.get_base_addr(PCI_USB_BASE_ADDR_INDEX)
+        {
+            Some(BaseAddressRegister::MemoryAddress32Bit(addr)) => addr,
+            Some(BaseAddressRegister::MemoryAddress64Bit(addr)) if addr <= u32::MAX as u64 => addr as u32,
+            Some(BaseAddressRegister::MemoryAddress64Bit(_)) => return Err("USB controller registers are above 4GiB"),
+            _ => return Err("USB controller BAR#0 not found")
+        };
+
+        self.mmio = Some(mmio::ioremap(base_addr, USB_MMIO_SIZE)?);
+        return Ok(());
+    }
+
     fn switch_ehci_to_xhci_mode(&self)
     {
         let mut is_exist_intel_ehc = false;
@@ -3,7 +3,7 @@ use core::{ptr::read_volatile, char::{decode_utf16, REPLACEMENT_CHARACTER}};
 use alloc::{string::{String, ToString}, vec::Vec};
 use modular_bitfield::{bitfield, prelude::*};
 
-use crate::println;
+use crate::{println, util::date_time::DateTime};
 
 pub const CURRENT_DIR_FILE_NAME: &str = ".          ";
 pub const PARENT_DIR_FILE_NAME: &str = "..         ";
@@ -85,6 +85,25 @@ impl DirectoryEntry
         }
     }
 
+    pub fn get_create_date_time(&self) -> DateTime
+    {
+        let mut date_time = convert_fat_date_time(self.create_date(), self.create_time());
+        // create_time_ms is counted in 10ms (0 - 199)
+        date_time.sec += self.create_time_ms() / 100;
+
+        return date_time;
+    }
+
+    pub fn get_last_modified_date_time(&self) -> DateTime
+    {
+        return convert_fat_date_time(self.last_modified_date(), self.last_modified_time());
+    }
+
+    pub fn get_file_size(&self) -> usize
+    {
+        return self.file_size() as usize;
+    }
+
     pub fn get_first_cluster_num(&self) -> usize
     {
         let low = self.first_cluster_num_low() as usize;
@@ -114,6 +133,20 @@ impl DirectoryEntry
     }
 }
 
+// date: year(7bit, from 1980) | month(4bit) | day(5bit)
+// time: hour(5bit) | min(6bit) | sec(5bit, 2sec unit)
+fn convert_fat_date_time(date: u16, time: u16) -> DateTime
+{
+    let year = 1980 + (date >> 9);
+    let month = ((date >> 5) & 0xf) as u8;
+    let day = (date & 0x1f) as u8;
+    let hour = (time >> 11) as u8;
+    let min = ((time >> 5) & 0x3f) as u8;
+    let sec = ((time & 0x1f) * 2) as u8;
+
+    return DateTime::new(year, month, day, hour, min, sec);
+}
+
 #[bitfield]
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
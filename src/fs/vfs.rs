@@ -4,16 +4,19 @@ use alloc::{vec::Vec, string::{ToString, String}, format};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
-use crate::{util::logger::{log_info, log_warn, log_debug}, println, fs::fat::{file_allocation_table::ClusterType, dir_entery::{FileAttribute, PARENT_DIR_FILE_NAME, DirectoryEntry}}, mem::PHYS_MEM_MANAGER, print};
+use crate::{util::{date_time::DateTime, logger::{log_info, log_warn, log_debug}}, println, fs::fat::{file_allocation_table::ClusterType, dir_entery::{FileAttribute, PARENT_DIR_FILE_NAME, DirectoryEntry}}, mem::{PHYS_MEM_MANAGER, slab::{self, SlabBox, SlabCacheId}}, print};
 
 use super::fat::{FatVolume, dir_entery::EntryType};
 
 pub const PATH_SEPARATOR: &str = "/";
 pub const PARENT_DIR_PATH: &str = "../";
+pub const MAX_OPEN_FILES: usize = 16;
 
 lazy_static!
 {
     pub static ref VFS: Mutex<VirtualFileSystem> = Mutex::new(VirtualFileSystem::new());
+    static ref DIR_ENTRY_CACHE: SlabCacheId = slab::create_cache_for::<File>("dir_entry").unwrap();
+    static ref FILE_DESC_CACHE: SlabCacheId = slab::create_cache_for::<FileDescriptor>("file_desc").unwrap();
 }
 
 #[derive(Debug)]
@@ -21,14 +24,36 @@ struct File
 {
     pub file_name: String,
     pub attr: FileAttribute,
-    pub pointing_cluster_num: usize
+    pub pointing_cluster_num: usize,
+    pub size: usize,
+    pub create_date_time: DateTime,
+    pub last_modified_date_time: DateTime
+}
+
+// opened file
+#[derive(Debug)]
+struct FileDescriptor
+{
+    cluster_chain_list: Vec<usize>,
+    size: usize,
+    offset: usize
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VfsError
+{
+    NotInitialized,
+    FileNotFound,
+    TooManyOpenFiles,
+    OutOfMemory
 }
 
 pub struct VirtualFileSystem
 {
     fat_volume: FatVolume,
     is_init: bool,
-    current_dir_cluster_num: usize
+    current_dir_cluster_num: usize,
+    fd_table: Vec<Option<SlabBox<FileDescriptor>>>
 }
 
 impl VirtualFileSystem
@@ -36,7 +61,7 @@ impl VirtualFileSystem
     pub fn new() -> VirtualFileSystem
     {
         let fat = FatVolume::new(0, 0);
-        return VirtualFileSystem { fat_volume: fat, is_init: false, current_dir_cluster_num: 2 };
+        return VirtualFileSystem { fat_volume: fat, is_init: false, current_dir_cluster_num: 2, fd_table: Vec::new() };
     }
 
     pub fn init(&mut self, start_base_addr: u32, end_base_addr: u32)
@@ -56,8 +81,19 @@ impl VirtualFileSystem
         }
     }
 
+    /// write back pending changes
+    pub fn flush(&mut self)
+    {
+        if !self.is_init
+        {
+            return;
+        }
+
+        // volume is read-only module image, nothing to write back for now
+    }
+
     // return Vec<(filename, file attribute, pointing cluster num)>
-    fn scan(&mut self, start_cluster_num: usize) -> Vec<File>
+    fn scan(&mut self, start_cluster_num: usize) -> Vec<SlabBox<File>>
     {
         let mut result = Vec::new();
 
@@ -110,9 +146,23 @@ impl VirtualFileSystem
                     //println!("\"{}\", len: {}", joined, joined.len());
                     //println!("pushed: {:?}, str len: {}", joined, joined.len());
                     //result.push(joined ,de.get_file_attr().unwrap(), de.get_first_cluster_num()));
-                    let file = File { file_name: joined, attr: de.get_file_attr().unwrap(), pointing_cluster_num: de.get_first_cluster_num() };
+                    let file = File
+                    {
+                        file_name: joined,
+                        attr: de.get_file_attr().unwrap(),
+                        pointing_cluster_num: de.get_first_cluster_num(),
+                        size: de.get_file_size(),
+                        create_date_time: de.get_create_date_time(),
+                        last_modified_date_time: de.get_last_modified_date_time()
+                    };
                     //println!("{:?}", file);
-                    result.push(file);
+
+                    match SlabBox::new(*DIR_ENTRY_CACHE, file)
+                    {
+                        Ok(file) => result.push(file),
+                        Err(msg) => log_warn(msg)
+                    }
+
                     //println!("{:?}", result.last());
                     // log_debug("file name buf", &file_name_buf);
                     file_name_buf.clear()
@@ -120,11 +170,105 @@ impl VirtualFileSystem
             }
         }
 
-        println!("len: {}", result[3].file_name.len());
-
         return result;
     }
 
+    /// open a file in current directory, returns file descriptor
+    pub fn open(&mut self, file_name: &str) -> Result<usize, VfsError>
+    {
+        if !self.is_init
+        {
+            return Err(VfsError::NotInitialized);
+        }
+
+        let file = match self.scan(self.current_dir_cluster_num).into_iter().find(|f| f.attr == FileAttribute::Archive && f.file_name == file_name)
+        {
+            Some(file) => file,
+            None => return Err(VfsError::FileNotFound)
+        };
+
+        let cluster_chain_list = if file.pointing_cluster_num == 0
+        {
+            Vec::new()
+        }
+        else
+        {
+            self.fat_volume.get_cluster_chain_list(file.pointing_cluster_num)
+        };
+
+        let fd = SlabBox::new(*FILE_DESC_CACHE, FileDescriptor { cluster_chain_list, size: file.size, offset: 0 }).map_err(|_| VfsError::OutOfMemory)?;
+
+        if let Some(i) = self.fd_table.iter().position(|fd| fd.is_none())
+        {
+            self.fd_table[i] = Some(fd);
+            return Ok(i);
+        }
+
+        if self.fd_table.len() >= MAX_OPEN_FILES
+        {
+            return Err(VfsError::TooManyOpenFiles);
+        }
+
+        self.fd_table.push(Some(fd));
+        return Ok(self.fd_table.len() - 1);
+    }
+
+    /// read from current offset, returns read size (0 at end of file)
+    pub fn read(&mut self, fd: usize, buf: &mut [u8]) -> Result<usize, &'static str>
+    {
+        let cluster_size = self.fat_volume.get_dir_entries_per_cluster() * size_of::<DirectoryEntry>();
+        let entries_per_cluster = self.fat_volume.get_dir_entries_per_cluster();
+
+        let desc = match self.fd_table.get_mut(fd)
+        {
+            Some(Some(desc)) => desc,
+            _ => return Err("Invalid file descriptor")
+        };
+
+        let mut read_size = 0;
+
+        while read_size < buf.len() && desc.offset < desc.size
+        {
+            let cluster_num = match desc.cluster_chain_list.get(desc.offset / cluster_size)
+            {
+                Some(cluster_num) => *cluster_num,
+                None => break
+            };
+
+            let cluster_addr = match self.fat_volume.get_dir_entry_base_addr((cluster_num - 2) * entries_per_cluster)
+            {
+                Some(addr) => addr,
+                None => return Err("Invalid cluster")
+            };
+
+            let offset_in_cluster = desc.offset % cluster_size;
+            let len = (cluster_size - offset_in_cluster).min(desc.size - desc.offset).min(buf.len() - read_size);
+
+            for i in 0..len
+            {
+                buf[read_size + i] = unsafe { read_volatile((cluster_addr + (offset_in_cluster + i) as u32) as *const u8) };
+            }
+
+            read_size += len;
+            desc.offset += len;
+        }
+
+        return Ok(read_size);
+    }
+
+    pub fn close(&mut self, fd: usize) -> Result<(), &'static str>
+    {
+        match self.fd_table.get_mut(fd)
+        {
+            Some(desc) if desc.is_some() =>
+            {
+                *desc = None;
+                return Ok(());
+            },
+            _ => return Err("Invalid file descriptor")
+        }
+    }
+
     pub fn cat(&mut self, file_name: &str)
     {
         let mut read_cnt = 0;
@@ -214,7 +358,7 @@ impl VirtualFileSystem
 
         for file in current_dir
         {
-            println!("{}", file.file_name);
+            println!("{} {}", file.last_modified_date_time, file.file_name);
         }
     }
 
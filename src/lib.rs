@@ -11,25 +11,28 @@
 mod arch;
 mod console;
 mod data;
+mod debug;
 mod device;
 mod fs;
 mod meta;
 mod mem;
+mod syscall;
 mod util;
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
-use arch::{vga::{VGA_SCREEN, Color}, asm, sgm};
+use arch::{vga::{VGA_SCREEN, Color}, asm, sgm, pit, rtc, acpi, smp, fpu, usermode};
 use multiboot2::{self, BootInformation};
 
-use crate::{arch::int::{self, KEYBUF, MOUSEBUF}, device::keyboard::{Keyboard, KeyLayout}, util::{boot_info::*, logger::*}, console::{SystemConsole, ascii}, mem::PAGING, fs::{fat::FatVolume, vfs::VFS}};
+use crate::{arch::int::{self, KEYBUF, MOUSEBUF}, device::{keyboard::{Keyboard, KeyLayout}, serial::IO_PORT_COM2}, util::{boot_info::*, logger::*}, console::{SystemConsole, ascii}, mem::PAGING, fs::{fat::FatVolume, vfs::VFS}};
 
 #[no_mangle]
 #[start]
 pub extern "C" fn kernel_main(magic: u32, boot_info_addr: u32) -> !
 {
-    let boot_info = unsafe { multiboot2::load(boot_info_addr as usize).expect("Failed to load
+    // boot_info_addr is physical, accessed through direct map
+    let boot_info = unsafe { multiboot2::load(mem::phys_to_virt(boot_info_addr) as usize).expect("Failed to load
     boot info") };
 
     if magic != multiboot2::MULTIBOOT2_BOOTLOADER_MAGIC
@@ -45,11 +48,21 @@ pub extern "C" fn kernel_main(magic: u32, boot_info_addr: u32) -> !
     sgm::init();
     int::init_pic();
     int::enable_mouse();
+    pit::init(pit::DEFAULT_FREQ);
+    rtc::init();
     mem::init(&boot_info);
+    debug::symbol::init(&boot_info);
+    acpi::init(&boot_info);
+    int::enable_apic();
+    smp::init();
+    fpu::init();
+    syscall::init();
+    debug::gdb::init(IO_PORT_COM2);
 
     if PAGING.lock().is_enabled()
     {
         sgm::enable_page_fault_handler();
+        usermode::init();
     }
 
     device::init();
@@ -57,7 +70,7 @@ pub extern "C" fn kernel_main(magic: u32, boot_info_addr: u32) -> !
     let mut keyboard = Keyboard::new(KeyLayout::AnsiUs104);
 
     let module = get_module_tags(&boot_info).last().unwrap();
-    VFS.lock().init(module.start_address(), module.end_address());
+    VFS.lock().init(mem::phys_to_virt(module.start_address()), mem::phys_to_virt(module.end_address()));
 
     let mut console = SystemConsole::new();
     console.start();
@@ -111,6 +124,7 @@ fn panic(info: &PanicInfo) -> !
 {
     VGA_SCREEN.lock().set_fore_color(Color::Red);
     println!("{}", info);
+    debug::backtrace::print_for_panic();
     loop {};
 }
 
@@ -0,0 +1,66 @@
+// per-process address space
+// kernel area is shared by all address spaces, user area (below KERNEL_VIRT_BASE) is separated
+
+use super::{phys_mem::MemoryBlockInfo, PAGING};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AddressSpace
+{
+    pd_block: MemoryBlockInfo
+}
+
+impl AddressSpace
+{
+    /// address space with empty user area
+    pub fn new() -> Result<AddressSpace, &'static str>
+    {
+        match PAGING.lock().create_page_directory()
+        {
+            Some(pd_block) => return Ok(AddressSpace { pd_block }),
+            None => return Err("Failed to allocate page directory")
+        }
+    }
+
+    /// address space sharing user pages by copy-on-write (fork)
+    pub fn try_clone(&self) -> Result<AddressSpace, &'static str>
+    {
+        let addr_space = AddressSpace::new()?;
+
+        // partially shared pages are released on drop
+        PAGING.lock().clone_user_space(self.get_page_directory_addr(), addr_space.get_page_directory_addr())?;
+
+        return Ok(addr_space);
+    }
+
+    pub fn switch(&self)
+    {
+        PAGING.lock().switch_page_directory(self.get_page_directory_addr());
+    }
+
+    pub fn is_current(&self) -> bool
+    {
+        return PAGING.lock().get_current_page_directory_addr() == self.get_page_directory_addr();
+    }
+
+    pub fn get_page_directory_addr(&self) -> u32
+    {
+        return self.pd_block.mem_block_start_addr as u32;
+    }
+}
+
+impl Drop for AddressSpace
+{
+    fn drop(&mut self)
+    {
+        // switches to kernel address space if this is current one
+        PAGING.lock().free_page_directory(self.pd_block);
+    }
+}
+
+/// switch to kernel page directory
+pub fn switch_to_kernel()
+{
+    let mut paging = PAGING.lock();
+    let pd_addr = paging.get_kernel_page_directory_addr();
+    paging.switch_page_directory(pd_addr);
+}
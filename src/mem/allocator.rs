@@ -1,56 +1,353 @@
-use core::{alloc::{GlobalAlloc, Layout}, ptr::null_mut, cell::UnsafeCell};
+// kernel heap allocator
+// free blocks are kept in a list sorted by address and merged with neighbors on free
 
-use crate::println;
+use core::{alloc::{GlobalAlloc, Layout}, mem::size_of, ptr::null_mut};
 
-pub const HEAP_AREA_BASE_ADDR: u32 = 0x6400000;
-pub const HEAP_SIZE: u32 = 1024 * 1024 * 1024; // 10MiB
+use spin::Mutex;
+
+use crate::{arch::asm, println, util::logger::*};
+
+use super::{paging::{KernelPageTables, PAGE_FLAGS_NO_EXECUTE, PAGE_FLAGS_WRITABLE}, phys_mem::MEM_BLOCK_SIZE, phys_to_virt, DIRECT_MAP_SIZE, KERNEL_VIRT_BASE, PAGING, PHYS_MEM_MANAGER};
+
+// in direct map
+pub const HEAP_AREA_BASE_ADDR: u32 = phys_to_virt(0x6400000);
+// reserved at boot, usable before paging is enabled
+pub const HEAP_INIT_SIZE: u32 = 16 * 1024 * 1024; // 16MiB
+// heap grows by mapping pages in this range after direct map, frames can be anywhere
+pub const HEAP_GROW_BASE_ADDR: u32 = KERNEL_VIRT_BASE + DIRECT_MAP_SIZE;
+pub const HEAP_GROW_SIZE: u32 = 128 * 1024 * 1024; // 128MiB
+pub const HEAP_MAX_SIZE: u32 = HEAP_INIT_SIZE + HEAP_GROW_SIZE;
+
+// every block can hold a free block header
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const MIN_ALIGN: usize = size_of::<usize>();
 
 #[global_allocator]
-static ALLOCATOR: Allocator = Allocator { base_addr: UnsafeCell::new(HEAP_AREA_BASE_ADDR) };
+static ALLOCATOR: Allocator = Allocator { heap: Mutex::new(Heap::new()) };
 
-pub struct Allocator
+struct FreeBlock
 {
-    base_addr: UnsafeCell<u32>
+    size: usize,
+    next: *mut FreeBlock
 }
 
-unsafe impl Sync for Allocator {}
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats
+{
+    pub heap_size: usize,
+    pub used_size: usize,
+    pub free_size: usize,
+    pub free_blocks: usize,
+    pub largest_free_block: usize,
+    pub alloc_cnt: usize,
+    pub dealloc_cnt: usize
+}
 
-unsafe impl GlobalAlloc for Allocator
+struct Heap
 {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    // sorted by address
+    head: *mut FreeBlock,
+    heap_size: usize,
+    // set after paging is initialized
+    page_tables: Option<KernelPageTables>,
+    used_size: usize,
+    alloc_cnt: usize,
+    dealloc_cnt: usize
+}
+
+unsafe impl Send for Heap {}
+
+impl Heap
+{
+    const fn new() -> Heap
+    {
+        return Heap { head: null_mut(), heap_size: 0, page_tables: None, used_size: 0, alloc_cnt: 0, dealloc_cnt: 0 };
+    }
+
+    unsafe fn init(&mut self)
     {
-        let size = layout.size() as u32;
-        let align = layout.align() as u32;
-        let base_addr = self.base_addr.get();
+        self.add_free_block(HEAP_AREA_BASE_ADDR as usize, HEAP_INIT_SIZE as usize);
+        self.heap_size = HEAP_INIT_SIZE as usize;
+    }
 
-        if size > HEAP_SIZE
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8
+    {
+        if self.heap_size == 0
         {
-            return null_mut();
+            self.init();
+        }
+
+        let (size, align) = get_size_align(layout);
+
+        if let Some(addr) = self.alloc_from_list(size, align)
+        {
+            return addr as *mut u8;
         }
 
-        if align > size
+        // worst case of alignment gap
+        if !self.grow(size + align + MIN_BLOCK_SIZE)
         {
             return null_mut();
         }
 
-        //println!("addr: {}, size: {}, align: {}", *base_addr, size, align);
-        // (i + (N-1)) & ~(N-1)
+        match self.alloc_from_list(size, align)
+        {
+            Some(addr) => return addr as *mut u8,
+            None => return null_mut()
+        }
+    }
 
-        let offset = (size + (align - 1)) & !(align - 1);
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout)
+    {
+        let (size, _) = get_size_align(layout);
+        self.add_free_block(ptr as usize, size);
+        self.used_size -= size;
+        self.dealloc_cnt += 1;
+    }
 
-        //println!("0x{:x} > 0x{:x}", *base_addr + offset, HEAP_AREA_BASE_ADDR + HEAP_SIZE);
+    // first fit
+    unsafe fn alloc_from_list(&mut self, size: usize, align: usize) -> Option<usize>
+    {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut block = self.head;
 
-        if *base_addr + offset > HEAP_AREA_BASE_ADDR + HEAP_SIZE
+        while !block.is_null()
         {
-            return null_mut();
+            let block_addr = block as usize;
+            let block_end = block_addr + (*block).size;
+
+            let mut addr = align_up(block_addr, align);
+
+            // gap before the allocation must be able to hold a free block
+            if addr != block_addr && addr - block_addr < MIN_BLOCK_SIZE
+            {
+                addr = align_up(block_addr + MIN_BLOCK_SIZE, align);
+            }
+
+            let end = addr + size;
+
+            // same for the rest after the allocation
+            if end <= block_end && (block_end - end == 0 || block_end - end >= MIN_BLOCK_SIZE)
+            {
+                let next = (*block).next;
+
+                if prev.is_null()
+                {
+                    self.head = next;
+                }
+                else
+                {
+                    (*prev).next = next;
+                }
+
+                if addr != block_addr
+                {
+                    self.add_free_block(block_addr, addr - block_addr);
+                }
+
+                if end != block_end
+                {
+                    self.add_free_block(end, block_end - end);
+                }
+
+                self.used_size += size;
+                self.alloc_cnt += 1;
+
+                return Some(addr);
+            }
+
+            prev = block;
+            block = (*block).next;
+        }
+
+        return None;
+    }
+
+    // insert to sorted list and merge with neighbors
+    unsafe fn add_free_block(&mut self, addr: usize, size: usize)
+    {
+        let new_block = addr as *mut FreeBlock;
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next = self.head;
+
+        while !next.is_null() && (next as usize) < addr
+        {
+            prev = next;
+            next = (*next).next;
         }
 
-        let before_base_addr = (*self.base_addr.get()).clone();
+        (*new_block).size = size;
+        (*new_block).next = next;
 
-        *base_addr += offset;
+        if !next.is_null() && addr + size == next as usize
+        {
+            (*new_block).size += (*next).size;
+            (*new_block).next = (*next).next;
+        }
 
-        return before_base_addr as *mut u8;
+        if prev.is_null()
+        {
+            self.head = new_block;
+        }
+        else if prev as usize + (*prev).size == addr
+        {
+            (*prev).size += (*new_block).size;
+            (*prev).next = (*new_block).next;
+        }
+        else
+        {
+            (*prev).next = new_block;
+        }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
-}
\ No newline at end of file
+    // map pages at the end of heap growth range, page tables are ready so Paging isn't locked
+    unsafe fn grow(&mut self, min_size: usize) -> bool
+    {
+        let size = align_up(min_size, MEM_BLOCK_SIZE as usize);
+
+        if self.heap_size + size > HEAP_MAX_SIZE as usize
+        {
+            return false;
+        }
+
+        let page_tables = match self.page_tables.as_ref()
+        {
+            Some(page_tables) => page_tables,
+            None => return false
+        };
+
+        let start_addr = HEAP_GROW_BASE_ADDR as usize + self.heap_size - HEAP_INIT_SIZE as usize;
+        let mut addr = start_addr;
+
+        while addr < start_addr + size
+        {
+            // frame is accessed through this mapping, it can be above direct map
+            let mb_info = match PHYS_MEM_MANAGER.lock().alloc_high_mem_block()
+            {
+                Some(mb_info) => mb_info,
+                None => break
+            };
+
+            if page_tables.map(addr as u32, mb_info.mem_block_start_addr).is_err()
+            {
+                PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(mb_info);
+                break;
+            }
+
+            addr += MEM_BLOCK_SIZE as usize;
+        }
+
+        if addr == start_addr
+        {
+            return false;
+        }
+
+        self.add_free_block(start_addr, addr - start_addr);
+        self.heap_size += addr - start_addr;
+
+        return addr == start_addr + size;
+    }
+
+    fn get_stats(&self) -> HeapStats
+    {
+        let mut free_size = 0;
+        let mut free_blocks = 0;
+        let mut largest_free_block = 0;
+        let mut block = self.head;
+
+        while !block.is_null()
+        {
+            let size = unsafe { (*block).size };
+            free_size += size;
+            free_blocks += 1;
+            largest_free_block = largest_free_block.max(size);
+            block = unsafe { (*block).next };
+        }
+
+        return HeapStats
+        {
+            heap_size: self.heap_size,
+            used_size: self.used_size,
+            free_size,
+            free_blocks,
+            largest_free_block,
+            alloc_cnt: self.alloc_cnt,
+            dealloc_cnt: self.dealloc_cnt
+        };
+    }
+}
+
+pub struct Allocator
+{
+    heap: Mutex<Heap>
+}
+
+unsafe impl GlobalAlloc for Allocator
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        // interrupt handlers may allocate
+        let is_int_enabled = asm::disable_int();
+        let ptr = self.heap.lock().alloc(layout);
+        asm::restore_int(is_int_enabled);
+
+        return ptr;
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        let is_int_enabled = asm::disable_int();
+        self.heap.lock().dealloc(ptr, layout);
+        asm::restore_int(is_int_enabled);
+    }
+}
+
+/// prepare page tables of heap growth range, called once after paging is initialized
+pub fn init_grow()
+{
+    let page_tables = match PAGING.lock().alloc_kernel_page_tables(HEAP_GROW_BASE_ADDR, HEAP_GROW_SIZE, PAGE_FLAGS_WRITABLE | PAGE_FLAGS_NO_EXECUTE)
+    {
+        Ok(page_tables) => page_tables,
+        Err(msg) =>
+        {
+            log_warn(msg);
+            return;
+        }
+    };
+
+    let is_int_enabled = asm::disable_int();
+    ALLOCATOR.heap.lock().page_tables = Some(page_tables);
+    asm::restore_int(is_int_enabled);
+}
+
+pub fn get_stats() -> HeapStats
+{
+    let is_int_enabled = asm::disable_int();
+    let stats = ALLOCATOR.heap.lock().get_stats();
+    asm::restore_int(is_int_enabled);
+
+    return stats;
+}
+
+pub fn heap_info()
+{
+    let stats = get_stats();
+
+    println!("Heap: {}B / {}B (max: {}B)", stats.used_size, stats.heap_size, HEAP_MAX_SIZE);
+    println!("Free: {}B in {} blocks (largest: {}B)", stats.free_size, stats.free_blocks, stats.largest_free_block);
+    println!("Alloc: {} Dealloc: {}", stats.alloc_cnt, stats.dealloc_cnt);
+}
+
+// rounded size and alignment, dealloc must get the same size as alloc
+fn get_size_align(layout: Layout) -> (usize, usize)
+{
+    let align = layout.align().max(MIN_ALIGN);
+    let size = align_up(layout.size().max(MIN_BLOCK_SIZE), MIN_ALIGN);
+
+    return (size, align);
+}
+
+fn align_up(addr: usize, align: usize) -> usize
+{
+    return (addr + align - 1) & !(align - 1);
+}
@@ -0,0 +1,193 @@
+// uncached mapping of device memory (PCI BARs etc.) into a kernel virtual window
+
+use core::{mem::size_of, ptr::{read_volatile, write_volatile}};
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use super::{allocator::{HEAP_GROW_BASE_ADDR, HEAP_GROW_SIZE}, paging::{PAGE_FLAGS_CACHE_DISABLE, PAGE_FLAGS_NO_EXECUTE, PAGE_FLAGS_WRITABLE, PAGE_FLAGS_WRITE_THROUGH, PAGE_SIZE}, PAGING};
+
+// after heap growth range
+pub const IOREMAP_BASE_ADDR: u32 = HEAP_GROW_BASE_ADDR + HEAP_GROW_SIZE;
+// kmap slots of paging.rs are above
+pub const IOREMAP_END_ADDR: u32 = 0xfebfe000;
+
+lazy_static!
+{
+    static ref IOREMAP_WINDOW: Mutex<IoRemapWindow> = Mutex::new(IoRemapWindow::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IoRemapArea
+{
+    virt_addr: u32,
+    phys_addr: u32,
+    size: u32
+}
+
+#[derive(Debug)]
+struct IoRemapWindow
+{
+    // sorted by address
+    areas: Vec<IoRemapArea>
+}
+
+impl IoRemapWindow
+{
+    fn new() -> IoRemapWindow
+    {
+        return IoRemapWindow { areas: Vec::new() };
+    }
+
+    // first fit, size is page aligned
+    fn reserve(&mut self, phys_addr: u32, size: u32) -> Option<u32>
+    {
+        let mut addr = IOREMAP_BASE_ADDR;
+        let mut index = 0;
+
+        for area in self.areas.iter()
+        {
+            if area.virt_addr - addr >= size
+            {
+                break;
+            }
+
+            addr = area.virt_addr + area.size;
+            index += 1;
+        }
+
+        if IOREMAP_END_ADDR - addr < size
+        {
+            return None;
+        }
+
+        self.areas.insert(index, IoRemapArea { virt_addr: addr, phys_addr, size });
+        return Some(addr);
+    }
+
+    fn release(&mut self, virt_addr: u32) -> Option<IoRemapArea>
+    {
+        let index = self.areas.iter().position(|a| a.virt_addr == virt_addr)?;
+        return Some(self.areas.remove(index));
+    }
+}
+
+/// mapped device memory, unmapped on drop
+#[derive(Debug, PartialEq, Eq)]
+pub struct Mmio
+{
+    // page aligned start of the mapping
+    area_addr: u32,
+    virt_addr: u32,
+    phys_addr: u32,
+    size: u32
+}
+
+impl Mmio
+{
+    pub fn get_virt_addr(&self) -> u32
+    {
+        return self.virt_addr;
+    }
+
+    pub fn get_phys_addr(&self) -> u32
+    {
+        return self.phys_addr;
+    }
+
+    pub fn get_size(&self) -> u32
+    {
+        return self.size;
+    }
+
+    /// read T at offset from the start of the region
+    pub fn read<T>(&self, offset: u32) -> T
+    {
+        let ptr = self.get_ptr::<T>(offset) as *const T;
+        return unsafe { read_volatile(ptr) };
+    }
+
+    /// write T at offset from the start of the region
+    pub fn write<T>(&self, offset: u32, value: T)
+    {
+        let ptr = self.get_ptr::<T>(offset);
+        unsafe { write_volatile(ptr, value); }
+    }
+
+    pub fn read32(&self, offset: u32) -> u32
+    {
+        return self.read::<u32>(offset);
+    }
+
+    pub fn write32(&self, offset: u32, value: u32)
+    {
+        self.write::<u32>(offset, value);
+    }
+
+    fn get_ptr<T>(&self, offset: u32) -> *mut T
+    {
+        if offset as u64 + size_of::<T>() as u64 > self.size as u64
+        {
+            panic!("MMIO access is out of range (offset: 0x{:x}, size: 0x{:x})", offset, self.size);
+        }
+
+        return (self.virt_addr + offset) as *mut T;
+    }
+}
+
+impl Drop for Mmio
+{
+    fn drop(&mut self)
+    {
+        if let Some(area) = IOREMAP_WINDOW.lock().release(self.area_addr)
+        {
+            PAGING.lock().unmap_range(area.virt_addr, area.size);
+        }
+    }
+}
+
+/// map size bytes of device memory at phys_addr with cache disabled
+pub fn ioremap(phys_addr: u32, size: u32) -> Result<Mmio, &'static str>
+{
+    if size == 0
+    {
+        return Err("Invalid MMIO size");
+    }
+
+    if !PAGING.lock().is_enabled()
+    {
+        return Err("Paging is not enabled");
+    }
+
+    let base_addr = phys_addr & !(PAGE_SIZE - 1);
+    let offset = phys_addr - base_addr;
+
+    let end_addr = phys_addr as u64 + size as u64;
+    let area_size = (end_addr - base_addr as u64 + PAGE_SIZE as u64 - 1) & !(PAGE_SIZE as u64 - 1);
+
+    if end_addr > u32::MAX as u64 + 1 || area_size > (IOREMAP_END_ADDR - IOREMAP_BASE_ADDR) as u64
+    {
+        return Err("MMIO region is too large");
+    }
+
+    let area_size = area_size as u32;
+    let mut window = IOREMAP_WINDOW.lock();
+
+    let area_addr = match window.reserve(base_addr, area_size)
+    {
+        Some(addr) => addr,
+        None => return Err("No space left in ioremap window")
+    };
+
+    let mut paging = PAGING.lock();
+
+    if let Err(msg) = paging.map_range(area_addr, base_addr as u64, area_size, PAGE_FLAGS_WRITABLE | PAGE_FLAGS_CACHE_DISABLE | PAGE_FLAGS_WRITE_THROUGH | PAGE_FLAGS_NO_EXECUTE)
+    {
+        paging.unmap_range(area_addr, area_size);
+        window.release(area_addr);
+        return Err(msg);
+    }
+
+    return Ok(Mmio { area_addr, virt_addr: area_addr + offset, phys_addr, size });
+}
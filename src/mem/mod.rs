@@ -1,5 +1,5 @@
 use crate::mem::phys_mem::MEM_BLOCK_SIZE;
-use crate::{println, util::logger::*, mem::paging::Paging};
+use crate::{println, util::logger::*, mem::paging::{Paging, PagingLock}};
 use multiboot2::BootInformation;
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -11,23 +11,61 @@ pub mod phys_mem;
 pub mod virt_mem;
 pub mod paging;
 pub mod allocator;
+pub mod slab;
+pub mod mmio;
+pub mod vm;
+pub mod addr_space;
+
+// kernel is linked here, physical memory is mapped from here (direct map)
+// lower 3GiB is user area of each address space
+pub const KERNEL_VIRT_BASE: u32 = 0xc0000000;
+// direct map ends at ioremap window
+pub const DIRECT_MAP_SIZE: u32 = 0x30000000; // 768MiB
+
+// user program code, heap (grown by sbrk) and stack
+// heap and stack pages are mapped on demand (see vm.rs)
+pub const USER_CODE_BASE_ADDR: u32 = 0x400000;
+pub const USER_HEAP_BASE_ADDR: u32 = 0x5400000;
+pub const USER_HEAP_SIZE: u32 = 15 * 1024 * 1024; // 15MiB
+pub const USER_STACK_SIZE: u32 = 1024 * 1024; // 1MiB
+pub const USER_STACK_TOP_ADDR: u32 = USER_HEAP_BASE_ADDR + USER_HEAP_SIZE + USER_STACK_SIZE;
 
 lazy_static!
 {
     pub static ref PHYS_MEM_MANAGER: Mutex<PhysicalMemoryManager> = Mutex::new(PhysicalMemoryManager::new());
-    pub static ref PAGING: Mutex<Paging> = Mutex::new(Paging::new());
+    pub static ref PAGING: PagingLock = PagingLock::new(Paging::new());
+}
+
+/// kernel virtual address of physical memory in direct map
+pub const fn phys_to_virt(phys_addr: u32) -> u32
+{
+    return phys_addr + KERNEL_VIRT_BASE;
+}
+
+/// physical address of kernel virtual address in direct map
+pub const fn virt_to_phys(virt_addr: u32) -> u32
+{
+    return virt_addr - KERNEL_VIRT_BASE;
 }
 
 pub fn init(boot_info: &BootInformation)
 {
-    PHYS_MEM_MANAGER.lock().init(boot_info);
+    // frames above 4GiB are used only with PAE
+    let max_phys_addr = PAGING.lock().get_max_phys_addr();
+    PHYS_MEM_MANAGER.lock().init(boot_info, max_phys_addr);
 
     PAGING.lock().init();
     PAGING.lock().enable();
+    allocator::init_grow();
 
     if PAGING.lock().is_enabled()
     {
         log_info("Paging enabled");
+
+        if PAGING.lock().is_nx_enabled()
+        {
+            log_info("No-execute enabled");
+        }
     }
     else
     {
@@ -50,4 +88,7 @@ pub fn info()
     let total = PHYS_MEM_MANAGER.lock().get_mem_blocks();
 
     println!("Allocated: {} / {}", allocated, total);
+    println!("Page tables: {}", PAGING.lock().get_page_table_cnt());
+
+    allocator::heap_info();
 }
\ No newline at end of file
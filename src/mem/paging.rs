@@ -1,56 +1,117 @@
-use core::ptr::{write_volatile, read_volatile};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{copy_nonoverlapping, write_bytes, write_volatile, read_volatile};
 
+use alloc::vec::Vec;
 use multiboot2::BootInformation;
+use spin::{Mutex, MutexGuard};
 
-use crate::{arch::asm, println, util::logger::log_info};
+use crate::{arch::{asm, cpuid::{self, CpuFeature}, smp}, println, util::logger::*};
 
-use super::{phys_mem::{PhysicalMemoryManager, MemoryBlockInfo, MEM_BLOCK_SIZE}, virt_mem::VirtualAddress, PHYS_MEM_MANAGER};
+use super::{phys_mem::{PhysicalMemoryManager, MemoryBlockInfo, MEM_BLOCK_SIZE}, virt_mem::VirtualAddress, phys_to_virt, virt_to_phys, PHYS_MEM_MANAGER, KERNEL_VIRT_BASE, DIRECT_MAP_SIZE};
 
-const PDE_PAGE_TABLE_ADDR_MASK: u32 = 0xfffff000;
-const PDE_PAGE_TABLE_ADDR_MAX: u32 = 0xfffff;
+// 32-bit entry has no address bits above 4GiB, PAE entry has up to 52 bits
+const PDE_PAGE_TABLE_ADDR_MASK: u64 = 0xffffffffff000;
 const PDE_PAGE_TABLE_ADDR_SHIFT: u32 = 12;
-const PDE_FLAGS_MASK: u32 = 0xff;
-const PDE_FLAGS_MAX: u32 = 0xfff;
-const PDE_FLAGS_PS: u32 = 0x80;
-const PDE_FLAGS_AVL: u32 = 0x40;
-const PDE_FLAGS_A: u32 = 0x20;
-const PDE_FLAGS_PCD: u32 = 0x10;
-const PDE_FLAGS_PWT: u32 = 0x8;
-const PDE_FLAGS_U_S: u32 = 0x4;
-const PDE_FLAGS_R_W: u32 = 0x2;
-const PDE_FLAGS_P: u32 = 0x1;
-
-const PTE_PAGE_FRAME_ADDR_MASK: u32 = 0xfffff000;
-const PTE_PAGE_FRAME_ADDR_MAX: u32 = 0xfffff;
+const PDE_FLAGS_MASK: u64 = 0xff;
+const PDE_FLAGS_MAX: u64 = 0xfff;
+const PDE_FLAGS_PS: u64 = 0x80;
+const PDE_FLAGS_AVL: u64 = 0x40;
+const PDE_FLAGS_A: u64 = 0x20;
+const PDE_FLAGS_PCD: u64 = 0x10;
+const PDE_FLAGS_PWT: u64 = 0x8;
+const PDE_FLAGS_U_S: u64 = 0x4;
+const PDE_FLAGS_R_W: u64 = 0x2;
+const PDE_FLAGS_P: u64 = 0x1;
+
+const PTE_PAGE_FRAME_ADDR_MASK: u64 = 0xffffffffff000;
 const PTE_PAGE_FRAME_ADDR_SHIFT: u32 = 12;
-const PTE_FLAGS_MASK: u32 = 0x1ff;
-const PTE_FLAGS_MAX: u32 = 0xfff;
-const PTE_FLAGS_G: u32 = 0x100;
-const PTE_FLAGS_PAT: u32 = 0x80;
-const PTE_FLAGS_D: u32 = 0x40;
-const PTE_FLAGS_A: u32 = 0x20;
-const PTE_FLAGS_PCD: u32 = 0x10;
-const PTE_FLAGS_PWT: u32 = 0x8;
-const PTE_FLAGS_U_S: u32 = 0x4;
-const PTE_FLAGS_R_W: u32 = 0x2;
-const PTE_FLAGS_P: u32 = 0x1;
+// execute disable, only in PAE entry and reserved unless EFER.NXE is set
+const PTE_FLAGS_XD: u64 = 0x8000000000000000;
+const PTE_FLAGS_MASK: u64 = 0x1ff | PTE_FLAGS_XD;
+const PTE_FLAGS_MAX: u64 = 0xfff | PTE_FLAGS_XD;
+const PTE_FLAGS_G: u64 = 0x100;
+const PTE_FLAGS_PAT: u64 = 0x80;
+const PTE_FLAGS_D: u64 = 0x40;
+const PTE_FLAGS_A: u64 = 0x20;
+const PTE_FLAGS_PCD: u64 = 0x10;
+const PTE_FLAGS_PWT: u64 = 0x8;
+const PTE_FLAGS_U_S: u64 = 0x4;
+const PTE_FLAGS_R_W: u64 = 0x2;
+const PTE_FLAGS_P: u64 = 0x1;
+// available for software, page is shared read-only until the first write
+const PTE_FLAGS_AVL_COW: u64 = 0x200;
+// flags set by PAGE_FLAGS_*
+const PTE_FLAGS_PAGE: u64 = PTE_FLAGS_R_W | PTE_FLAGS_U_S | PTE_FLAGS_PWT | PTE_FLAGS_PCD | PTE_FLAGS_G | PTE_FLAGS_XD;
+
+// PAE page directory pointer table, 4 entries for 1GiB each
+// PDPTE has no R/W and U/S flags, and it's loaded to CPU with cr3
+const PDPTE_PAGE_DIRECTORY_ADDR_MASK: u64 = 0xffffffffff000;
+const PDPTE_FLAGS_P: u64 = 0x1;
+const PDPT_ENTRIES: usize = 4;
+
+// kernel writes must fault on copy-on-write pages too
+const CR0_WP: u32 = 0x10000;
+// set by kernel.asm if CPU supports PAE
+const CR4_PAE: u32 = 0x20;
+const MSR_EFER: u32 = 0xc0000080;
+const EFER_NXE: u64 = 0x800;
+
+pub const PAGE_SIZE: u32 = MEM_BLOCK_SIZE;
+
+// flags for map and protect, present flag is always set
+pub const PAGE_FLAGS_WRITABLE: u32 = PTE_FLAGS_R_W as u32;
+pub const PAGE_FLAGS_USER: u32 = PTE_FLAGS_U_S as u32;
+pub const PAGE_FLAGS_WRITE_THROUGH: u32 = PTE_FLAGS_PWT as u32;
+pub const PAGE_FLAGS_CACHE_DISABLE: u32 = PTE_FLAGS_PCD as u32;
+pub const PAGE_FLAGS_GLOBAL: u32 = PTE_FLAGS_G as u32;
+// set as PTE_FLAGS_XD, ignored if NX is not enabled
+pub const PAGE_FLAGS_NO_EXECUTE: u32 = 0x80000000;
+const PAGE_FLAGS_ALL: u32 = PAGE_FLAGS_WRITABLE | PAGE_FLAGS_USER | PAGE_FLAGS_WRITE_THROUGH | PAGE_FLAGS_CACHE_DISABLE | PAGE_FLAGS_GLOBAL | PAGE_FLAGS_NO_EXECUTE;
+
+const PAGE_DIRECTORY_ENTRIES: usize = 1024;
+const PAGE_TABLE_ENTRIES: usize = 1024;
+const PAE_PAGE_DIRECTORY_ENTRIES: usize = 512;
+const PAE_PAGE_TABLE_ENTRIES: usize = 512;
+const PAE_PD_INDEX_SHIFT: u32 = 21;
+
+// PDEs below kernel area belong to each address space, others are shared kernel mappings
+// index of PAE PDE counts all page directories
+const USER_PDE_END: usize = (KERNEL_VIRT_BASE >> 22) as usize;
+const PAE_USER_PDE_END: usize = (KERNEL_VIRT_BASE >> PAE_PD_INDEX_SHIFT) as usize;
+const PAE_USER_PDPTE_END: usize = PAE_USER_PDE_END / PAE_PAGE_DIRECTORY_ENTRIES;
+
+// frames above direct map are mapped here temporarily while the lock is held
+const KMAP_SLOT_SRC: u32 = 0xfebfe000;
+const KMAP_SLOT_DST: u32 = 0xfebff000;
+
+// real mode area is executable for AP trampoline
+const REAL_MODE_AREA_SIZE: u32 = 0x100000;
+
+extern
+{
+    // linker.ld
+    static kernel_text_start: u8;
+    static kernel_text_end: u8;
+}
 
 #[derive(Debug, PartialEq, Eq)]
 struct PageTableEntry
 {
-    base_addr: u32
+    base_addr: u32,
+    // 64-bit entry
+    is_pae: bool
 }
 
 impl PageTableEntry
 {
-    pub fn new(base_addr: u32) -> PageTableEntry
+    pub fn new(base_addr: u32, is_pae: bool) -> PageTableEntry
     {
-        return PageTableEntry { base_addr };
+        return PageTableEntry { base_addr, is_pae };
     }
 
-    pub fn set(&mut self, mut page_frame_addr: u32, flags: u32)
+    pub fn set(&mut self, mut page_frame_addr: u64, flags: u64)
     {
-        if flags > PTE_FLAGS_MAX
+        if flags & !PTE_FLAGS_MAX != 0
         {
             panic!("flags is out of range");
         }
@@ -59,9 +120,9 @@ impl PageTableEntry
         self.set_inner(page_frame_addr | flags);
     }
 
-    pub fn set_flag(&mut self, flags: u32)
+    pub fn set_flag(&mut self, flags: u64)
     {
-        if flags > PTE_FLAGS_MAX
+        if flags & !PTE_FLAGS_MAX != 0
         {
             panic!("flags is out of range");
         }
@@ -71,9 +132,9 @@ impl PageTableEntry
         self.set_inner(tmp);
     }
 
-    pub fn clear_flag(&mut self, flags: u32)
+    pub fn clear_flag(&mut self, flags: u64)
     {
-        if flags > PTE_FLAGS_MAX
+        if flags & !PTE_FLAGS_MAX != 0
         {
             panic!("flags is out of range");
         }
@@ -83,12 +144,12 @@ impl PageTableEntry
         self.set_inner(tmp);
     }
 
-    pub fn get_page_frame_addr(&self) -> u32
+    pub fn get_page_frame_addr(&self) -> u64
     {
         return self.get_inner() & PTE_PAGE_FRAME_ADDR_MASK;
     }
 
-    pub fn set_page_frame_addr(&self, mut page_frame_addr: u32)
+    pub fn set_page_frame_addr(&self, mut page_frame_addr: u64)
     {
         page_frame_addr &= PTE_PAGE_FRAME_ADDR_MASK;
         let mut tmp = self.get_inner();
@@ -96,7 +157,7 @@ impl PageTableEntry
         self.set_inner(tmp);
     }
 
-    pub fn get_flags(&self) -> u32
+    pub fn get_flags(&self) -> u64
     {
         return self.get_inner() & PTE_FLAGS_MASK;
     }
@@ -111,55 +172,48 @@ impl PageTableEntry
         return self.get_flags() & PTE_FLAGS_R_W != 0;
     }
 
-    pub fn get_size() -> u32
+    pub fn get_size(&self) -> u32
     {
-        return u32::BITS;
+        return if self.is_pae { u64::BITS } else { u32::BITS };
     }
 
-    fn get_inner(&self) -> u32
+    fn get_inner(&self) -> u64
     {
-        unsafe
-        {
-            let ptr = self.base_addr as *const u32;
-            return read_volatile(ptr);
-        }
+        return read_entry(self.base_addr, self.is_pae);
     }
 
-    fn set_inner(&self, inner: u32)
+    fn set_inner(&self, inner: u64)
     {
-        unsafe
-        {
-            let ptr = self.base_addr as *mut u32;
-            write_volatile(ptr, inner);
-        }
+        write_entry(self.base_addr, self.is_pae, inner);
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 struct PageDirectoryEntry
 {
-    base_addr: u32
+    base_addr: u32,
+    // 64-bit entry
+    is_pae: bool
 }
 
 impl PageDirectoryEntry
 {
-    pub fn new(base_addr: u32) -> PageDirectoryEntry
+    pub fn new(base_addr: u32, is_pae: bool) -> PageDirectoryEntry
     {
-        return PageDirectoryEntry { base_addr };
+        return PageDirectoryEntry { base_addr, is_pae };
     }
 
-    pub fn set(&mut self, mut page_table_addr: u32, flags: u32)
+    pub fn set(&mut self, page_table_addr: u32, flags: u64)
     {
         if flags > PDE_FLAGS_MAX
         {
             panic!("flags is out of range");
         }
 
-        page_table_addr &= PDE_PAGE_TABLE_ADDR_MASK;
-        self.set_inner(page_table_addr | flags);
+        self.set_inner((page_table_addr as u64 & PDE_PAGE_TABLE_ADDR_MASK) | flags);
     }
 
-    pub fn set_flag(&mut self, flags: u32)
+    pub fn set_flag(&mut self, flags: u64)
     {
         if flags > PDE_FLAGS_MAX
         {
@@ -171,7 +225,7 @@ impl PageDirectoryEntry
         self.set_inner(tmp);
     }
 
-    pub fn clear_flag(&mut self, flags: u32)
+    pub fn clear_flag(&mut self, flags: u64)
     {
         if flags > PDE_FLAGS_MAX
         {
@@ -183,20 +237,20 @@ impl PageDirectoryEntry
         self.set_inner(tmp);
     }
 
+    // page tables are allocated in direct map
     pub fn get_page_table_addr(&self) -> u32
     {
-        return self.get_inner() & PDE_PAGE_TABLE_ADDR_MASK;
+        return (self.get_inner() & PDE_PAGE_TABLE_ADDR_MASK) as u32;
     }
 
-    pub fn set_page_table_addr(&self, mut page_table_addr: u32)
+    pub fn set_page_table_addr(&self, page_table_addr: u32)
     {
-        page_table_addr &= PDE_PAGE_TABLE_ADDR_MASK;
         let mut tmp = self.get_inner();
-        tmp |= page_table_addr;
+        tmp |= page_table_addr as u64 & PDE_PAGE_TABLE_ADDR_MASK;
         self.set_inner(tmp);
     }
 
-    pub fn get_flags(&self) -> u32
+    pub fn get_flags(&self) -> u64
     {
         return self.get_inner() & PDE_FLAGS_MASK;
     }
@@ -211,38 +265,160 @@ impl PageDirectoryEntry
         return self.get_flags() & PDE_FLAGS_R_W != 0;
     }
 
-    pub fn get_size() -> u32
+    pub fn get_size(&self) -> u32
+    {
+        return if self.is_pae { u64::BITS } else { u32::BITS };
+    }
+
+    fn get_inner(&self) -> u64
+    {
+        return read_entry(self.base_addr, self.is_pae);
+    }
+
+    fn set_inner(&self, inner: u64)
+    {
+        write_entry(self.base_addr, self.is_pae, inner);
+    }
+}
+
+// pages invalidated locally whose TLB entries on other CPUs are flushed after unlock
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum PendingFlush
+{
+    None,
+    Page(u32),
+    All
+}
+
+/// paging behind a spin lock, TLB shootdown is sent when the lock is released
+/// other CPUs waiting for the lock with interrupts disabled can't answer the IPI
+pub struct PagingLock
+{
+    inner: Mutex<Paging>
+}
+
+impl PagingLock
+{
+    pub fn new(paging: Paging) -> PagingLock
+    {
+        return PagingLock { inner: Mutex::new(paging) };
+    }
+
+    pub fn lock(&self) -> PagingGuard
+    {
+        return PagingGuard { guard: Some(self.inner.lock()) };
+    }
+
+    pub fn try_lock(&self) -> Option<PagingGuard>
+    {
+        return self.inner.try_lock().map(|guard| PagingGuard { guard: Some(guard) });
+    }
+}
+
+pub struct PagingGuard<'a>
+{
+    guard: Option<MutexGuard<'a, Paging>>
+}
+
+impl<'a> Deref for PagingGuard<'a>
+{
+    type Target = Paging;
+
+    fn deref(&self) -> &Paging
+    {
+        return self.guard.as_deref().unwrap();
+    }
+}
+
+impl<'a> DerefMut for PagingGuard<'a>
+{
+    fn deref_mut(&mut self) -> &mut Paging
     {
-        return u32::BITS;
+        return self.guard.as_deref_mut().unwrap();
     }
+}
 
-    fn get_inner(&self) -> u32
+impl<'a> Drop for PagingGuard<'a>
+{
+    fn drop(&mut self)
     {
-        unsafe
+        let pending_flush = match self.guard.as_deref_mut()
+        {
+            Some(paging) => core::mem::replace(&mut paging.pending_flush, PendingFlush::None),
+            None => PendingFlush::None
+        };
+
+        // unlock before waiting for other CPUs
+        self.guard = None;
+
+        match pending_flush
         {
-            let ptr = self.base_addr as *const u32;
-            return read_volatile(ptr);
+            PendingFlush::None => (),
+            PendingFlush::Page(virt_addr) => smp::tlb_shootdown(Some(virt_addr)),
+            PendingFlush::All => smp::tlb_shootdown(None)
         }
     }
+}
+
+/// page tables of a kernel range created in advance, its pages are mapped without Paging lock
+/// the range must have one owner which serializes mapping (e.g. heap allocator)
+#[derive(Debug)]
+pub struct KernelPageTables
+{
+    base_addr: u32,
+    // physical addresses
+    pt_addrs: Vec<u32>,
+    pte_cnt: u32,
+    entry_flags: u64,
+    is_pae: bool
+}
+
+impl KernelPageTables
+{
+    pub fn get_size(&self) -> u32
+    {
+        return self.pt_addrs.len() as u32 * self.pte_cnt * PAGE_SIZE;
+    }
 
-    fn set_inner(&self, inner: u32)
+    /// map a page which is not mapped yet, other CPUs don't cache it so no shootdown is needed
+    pub fn map(&self, virt_addr: u32, phys_addr: u64) -> Result<(), &'static str>
     {
-        unsafe
+        if virt_addr < self.base_addr || virt_addr - self.base_addr >= self.get_size() || virt_addr % PAGE_SIZE != 0
+        {
+            return Err("Address is out of kernel page tables");
+        }
+
+        let index = (virt_addr - self.base_addr) / PAGE_SIZE;
+        let entry_size = if self.is_pae { 8 } else { 4 };
+        let pt_addr = self.pt_addrs[(index / self.pte_cnt) as usize];
+        let mut pte = PageTableEntry::new(pt_addr + (index % self.pte_cnt) * entry_size, self.is_pae);
+
+        if pte.get_flag_present()
         {
-            let ptr = self.base_addr as *mut u32;
-            write_volatile(ptr, inner);
+            return Err("Page is already mapped");
         }
+
+        pte.set(phys_addr, self.entry_flags);
+        asm::invlpg(virt_addr);
+
+        return Ok(());
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Paging
 {
+    // kernel page directory (page directory pointer table if PAE), master copy of kernel mappings
     pd_block: MemoryBlockInfo,
-    pt_blocks: [MemoryBlockInfo; 1024],
+    current_pd_addr: u32,
+    page_table_cnt: usize,
     page_directory_addr_backup: u32,
+    // paging mode is selected by kernel.asm
+    is_pae: bool,
+    is_nx_enabled: bool,
     is_init: bool,
-    is_enabled: bool
+    is_enabled: bool,
+    pending_flush: PendingFlush
 }
 
 impl Paging
@@ -252,10 +428,14 @@ impl Paging
         return Paging
         {
             pd_block: MemoryBlockInfo::new(),
-            pt_blocks: [MemoryBlockInfo::new(); 1024],
+            current_pd_addr: 0,
+            page_table_cnt: 0,
             page_directory_addr_backup: 0,
+            is_pae: asm::get_cr4() & CR4_PAE != 0,
+            is_nx_enabled: false,
             is_init: false,
-            is_enabled: false
+            is_enabled: false,
+            pending_flush: PendingFlush::None
         };
     }
 
@@ -271,47 +451,62 @@ impl Paging
         }
 
         PHYS_MEM_MANAGER.lock().clear_mem_block(&self.pd_block);
+        self.current_pd_addr = self.pd_block.mem_block_start_addr as u32;
 
-        // allocate block for page table memory
-        for i in 0..self.pt_blocks.len()
+        // page directories of PAE aren't changed after cr3 is loaded
+        if self.is_pae
         {
-            let mb_info = PHYS_MEM_MANAGER.lock().alloc_single_mem_block();
-            self.pt_blocks[i] = mb_info.unwrap();
-            PHYS_MEM_MANAGER.lock().clear_mem_block(&self.pt_blocks[i]);
+            for i in 0..PDPT_ENTRIES
+            {
+                let pd_block = match self.alloc_frame()
+                {
+                    Some(pd_block) => pd_block,
+                    None => return
+                };
+
+                write_pdpte(self.current_pd_addr, i, pd_block.mem_block_start_addr | PDPTE_FLAGS_P);
+            }
         }
 
+        // XD bit is reserved without PAE
+        self.is_nx_enabled = self.is_pae && cpuid::has_feature(CpuFeature::Nx);
+
         // back up cr3 address
         self.page_directory_addr_backup = asm::get_cr3();
 
-        // mapping physical and virtual address
+        // direct map of physical memory, lower half is left for user area
+        // trampoline page directory of kernel.asm is used until enable()
+        let low_mem_size = PHYS_MEM_MANAGER.lock().get_low_mem_size();
         let mut i = 0;
-        loop
+
+        while low_mem_size - i >= MEM_BLOCK_SIZE
         {
-            if PHYS_MEM_MANAGER.lock().get_total_mem_size() - i < MEM_BLOCK_SIZE
+            let mut flags = PAGE_FLAGS_WRITABLE;
+
+            if !is_kernel_code(i)
             {
-                break;
+                flags |= PAGE_FLAGS_NO_EXECUTE;
             }
 
-            let va = VirtualAddress::new(i);
-
-            let pd_i = va.get_page_directory_index();
-            let pt_i = va.get_page_table_index();
-
-            if pt_i == 0
+            if let Err(msg) = self.set_page(phys_to_virt(i), i as u64, flags)
             {
-                let mut pde = self.get_page_directory_entry(pd_i);
-                let pt_block = self.pt_blocks[pd_i];
-                pde.set(pt_block.mem_block_start_addr, PDE_FLAGS_P | PDE_FLAGS_R_W);
+                log_error(msg);
+                return;
             }
 
-            let mut pte = self.get_page_table_entry(pd_i, pt_i);
-            pte.set(i, PTE_FLAGS_P | PTE_FLAGS_R_W);
-
             i += MEM_BLOCK_SIZE;
         }
 
         self.is_init = true;
-        log_info("Paging initialized");
+
+        if self.is_pae
+        {
+            log_info("Paging initialized (PAE)");
+        }
+        else
+        {
+            log_info("Paging initialized");
+        }
     }
 
     pub fn enable(&mut self)
@@ -321,8 +516,14 @@ impl Paging
             return;
         }
 
-        asm::set_cr3(self.pd_block.mem_block_start_addr);
+        if self.is_nx_enabled
+        {
+            asm::wrmsr(MSR_EFER, asm::rdmsr(MSR_EFER) | EFER_NXE);
+        }
+
+        asm::set_cr3(self.get_kernel_page_directory_addr());
         asm::enable_paging();
+        asm::set_cr0(asm::get_cr0() | CR0_WP);
         self.is_enabled = true;
     }
 
@@ -336,27 +537,240 @@ impl Paging
         return self.is_init;
     }
 
-    pub fn alloc_single_page(&mut self) -> Option<MemoryBlockInfo>
+    pub fn is_pae(&self) -> bool
     {
-        if !self.is_enabled()
+        return self.is_pae;
+    }
+
+    pub fn is_nx_enabled(&self) -> bool
+    {
+        return self.is_nx_enabled;
+    }
+
+    /// physical address limit of page frames (64GiB is the minimum of PAE)
+    pub fn get_max_phys_addr(&self) -> u64
+    {
+        if self.is_pae
+        {
+            return 1 << 36;
+        }
+
+        return 1 << 32;
+    }
+
+    /// map the page at virt_addr to phys_addr, existing mapping is replaced
+    pub fn map(&mut self, virt_addr: u32, phys_addr: u64, flags: u32) -> Result<(), &'static str>
+    {
+        self.check_args(virt_addr, flags)?;
+
+        if phys_addr % PAGE_SIZE as u64 != 0
+        {
+            return Err("Physical address is not page aligned");
+        }
+
+        if phys_addr >= self.get_max_phys_addr()
+        {
+            return Err("Physical address is out of range");
+        }
+
+        let was_present = self.set_page(virt_addr, phys_addr, flags)?;
+        self.flush_page(virt_addr, was_present);
+
+        return Ok(());
+    }
+
+    /// unmap the page at virt_addr, returns physical address of the page
+    pub fn unmap(&mut self, virt_addr: u32) -> Result<u64, &'static str>
+    {
+        self.check_args(virt_addr, 0)?;
+
+        let mut pte = match self.find_page_table_entry(virt_addr)
+        {
+            Some(pte) if pte.get_flag_present() => pte,
+            _ => return Err("Page is not mapped")
+        };
+
+        let phys_addr = pte.get_page_frame_addr();
+        pte.set(0, 0);
+        self.flush_page(virt_addr, true);
+
+        return Ok(phys_addr);
+    }
+
+    /// physical address mapped to virt_addr
+    pub fn translate(&self, virt_addr: u32) -> Option<u64>
+    {
+        if !self.is_init()
         {
             return None;
         }
 
-        if let Some(mb_info) = PHYS_MEM_MANAGER.lock().alloc_single_mem_block()
+        let pte = self.find_page_table_entry(virt_addr)?;
+
+        if !pte.get_flag_present()
+        {
+            return None;
+        }
+
+        return Some(pte.get_page_frame_addr() | VirtualAddress::new(virt_addr).get_page_offset() as u64);
+    }
+
+    /// replace flags of mapped pages in the range
+    pub fn protect(&mut self, virt_addr: u32, size: u32, flags: u32) -> Result<(), &'static str>
+    {
+        self.check_args(virt_addr, flags)?;
+
+        let entry_flags = self.get_entry_flags(flags);
+        let end_addr = virt_addr as u64 + size as u64;
+        let mut addr = virt_addr as u64;
+
+        while addr < end_addr
         {
-            PHYS_MEM_MANAGER.lock().clear_mem_block(&mb_info);
-            let pd_i = self.get_page_directory_index(mb_info.mem_block_index);
-            let pt_i = self.get_page_table_index(mb_info.mem_block_index);
-            let mut pte = self.get_page_table_entry(pd_i, pt_i);
-            pte.set(mb_info.mem_block_start_addr, PTE_FLAGS_P);
+            let va = VirtualAddress::new(addr as u32);
 
-            return Some(mb_info);
+            let mut pte = match self.find_page_table_entry(va.get_addr())
+            {
+                Some(pte) if pte.get_flag_present() => pte,
+                _ => return Err("Page is not mapped")
+            };
+
+            if flags & PAGE_FLAGS_USER != 0
+            {
+                self.get_page_directory_entry(self.get_pd_index(va.get_addr())).set_flag(PDE_FLAGS_U_S);
+            }
+
+            let page_frame_addr = pte.get_page_frame_addr();
+            let mut new_flags = entry_flags;
+
+            // frame shared by copy-on-write stays read-only until the first write
+            if new_flags & PTE_FLAGS_R_W != 0 && PHYS_MEM_MANAGER.lock().get_ref_cnt((page_frame_addr / MEM_BLOCK_SIZE as u64) as usize) > 1
+            {
+                new_flags = (new_flags & !PTE_FLAGS_R_W) | PTE_FLAGS_AVL_COW;
+            }
+
+            if pte.get_inner() & (PTE_FLAGS_PAGE | PTE_FLAGS_AVL_COW) != new_flags
+            {
+                pte.set(page_frame_addr, new_flags | PTE_FLAGS_P);
+                self.flush_page(va.get_addr(), true);
+            }
+
+            addr += PAGE_SIZE as u64;
         }
-        else
+
+        return Ok(());
+    }
+
+    /// map size bytes from virt_addr to phys_addr, pages mapped before an error are kept
+    pub fn map_range(&mut self, virt_addr: u32, phys_addr: u64, size: u32, flags: u32) -> Result<(), &'static str>
+    {
+        let mut offset = 0;
+
+        while (offset as u64) < size as u64
+        {
+            self.map(virt_addr + offset, phys_addr + offset as u64, flags)?;
+
+            if size - offset <= PAGE_SIZE
+            {
+                break;
+            }
+
+            offset += PAGE_SIZE;
+        }
+
+        return Ok(());
+    }
+
+    /// unmap size bytes from virt_addr, pages which are not mapped are skipped
+    pub fn unmap_range(&mut self, virt_addr: u32, size: u32)
+    {
+        let end_addr = virt_addr as u64 + size as u64;
+        let mut addr = virt_addr as u64;
+
+        while addr < end_addr
+        {
+            let _ = self.unmap(addr as u32);
+            addr += PAGE_SIZE as u64;
+        }
+    }
+
+    /// allocate page tables covering size bytes from base_addr for KernelPageTables::map
+    /// created before user address spaces, so they share the kernel PDEs
+    pub fn alloc_kernel_page_tables(&mut self, base_addr: u32, size: u32, flags: u32) -> Result<KernelPageTables, &'static str>
+    {
+        if !self.is_init()
+        {
+            return Err("Paging is not initialized");
+        }
+
+        let pte_cnt = self.get_pte_cnt() as u32;
+        let pt_size = pte_cnt * PAGE_SIZE;
+
+        if size == 0 || base_addr < KERNEL_VIRT_BASE || base_addr % pt_size != 0 || size % pt_size != 0 || base_addr.checked_add(size - 1).is_none()
+        {
+            return Err("Invalid kernel page table range");
+        }
+
+        let mut pt_addrs = Vec::new();
+
+        for i in 0..size / pt_size
+        {
+            let mut pde = self.get_page_directory_entry(self.get_pd_index(base_addr + i * pt_size));
+
+            if !pde.get_flag_present()
+            {
+                let pt_addr = match self.alloc_page_table()
+                {
+                    Some(addr) => addr,
+                    None => return Err("Failed to allocate page table")
+                };
+
+                pde.set(pt_addr, PDE_FLAGS_P | PDE_FLAGS_R_W);
+            }
+
+            pt_addrs.push(pde.get_page_table_addr());
+        }
+
+        return Ok(KernelPageTables { base_addr, pt_addrs, pte_cnt, entry_flags: self.get_entry_flags(flags) | PTE_FLAGS_P, is_pae: self.is_pae });
+    }
+
+    /// check if ring 3 can access the page
+    pub fn is_user_page(&self, virt_addr: u32, need_write: bool) -> bool
+    {
+        if !self.is_init()
+        {
+            return false;
+        }
+
+        let pd_i = self.get_pd_index(virt_addr);
+        let pde = self.get_page_directory_entry(pd_i);
+
+        if !pde.get_flag_present() || pde.get_flags() & PDE_FLAGS_U_S == 0
+        {
+            return false;
+        }
+
+        let pte = self.get_page_table_entry(pd_i, self.get_pt_index(virt_addr));
+
+        if !pte.get_flag_present() || pte.get_flags() & PTE_FLAGS_U_S == 0
+        {
+            return false;
+        }
+
+        return !need_write || (pde.get_flag_writable() && pte.get_flag_writable());
+    }
+
+    pub fn alloc_single_page(&mut self) -> Option<MemoryBlockInfo>
+    {
+        if !self.is_enabled()
         {
             return None;
         }
+
+        // accessed at phys_to_virt(mem_block_start_addr)
+        let mb_info = PHYS_MEM_MANAGER.lock().alloc_single_mem_block()?;
+        PHYS_MEM_MANAGER.lock().clear_mem_block(&mb_info);
+
+        return Some(mb_info);
     }
 
     pub fn dealloc_single_page(&mut self, mem_block: MemoryBlockInfo)
@@ -366,48 +780,652 @@ impl Paging
             return;
         }
 
-        PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(mem_block);
         PHYS_MEM_MANAGER.lock().clear_mem_block(&mem_block);
-        let pd_i = self.get_page_directory_index(mem_block.mem_block_index);
-        let pt_i = self.get_page_table_index(mem_block.mem_block_index);
-        let mut pte = self.get_page_table_entry(pd_i, pt_i);
-        pte.clear_flag(PTE_FLAGS_P);
+        PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(mem_block);
     }
 
-    pub fn get_total_mem_size(&self) -> u32
+    /// new page directory sharing kernel mappings, user area is empty
+    pub fn create_page_directory(&mut self) -> Option<MemoryBlockInfo>
+    {
+        if !self.is_init()
+        {
+            return None;
+        }
+
+        let pd_block = self.alloc_frame()?;
+        let pd_addr = pd_block.mem_block_start_addr as u32;
+
+        // kernel page directory is shared, user page directories are allocated now
+        if self.is_pae
+        {
+            for i in 0..PDPT_ENTRIES
+            {
+                if i >= PAE_USER_PDPTE_END
+                {
+                    write_pdpte(pd_addr, i, read_pdpte(self.get_kernel_page_directory_addr(), i));
+                    continue;
+                }
+
+                match self.alloc_frame()
+                {
+                    Some(mb_info) => write_pdpte(pd_addr, i, mb_info.mem_block_start_addr | PDPTE_FLAGS_P),
+                    None =>
+                    {
+                        free_user_page_directories(pd_addr);
+                        PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(pd_block);
+                        return None;
+                    }
+                }
+            }
+
+            return Some(pd_block);
+        }
+
+        for i in 0..PAGE_DIRECTORY_ENTRIES
+        {
+            if !self.is_user_pde(i)
+            {
+                let pde = self.get_page_directory_entry(i).get_inner();
+                self.get_pde_of(pd_addr, i).set_inner(pde);
+            }
+        }
+
+        return Some(pd_block);
+    }
+
+    /// share user pages of src page directory with dst page directory
+    /// writable pages become copy-on-write in both
+    pub fn clone_user_space(&mut self, src_pd_addr: u32, dst_pd_addr: u32) -> Result<(), &'static str>
+    {
+        if !self.is_init()
+        {
+            return Err("Paging is not initialized");
+        }
+
+        let mut is_src_changed = false;
+
+        for i in 0..self.get_user_pde_end()
+        {
+            let src_pde = self.get_pde_of(src_pd_addr, i);
+
+            if !src_pde.get_flag_present()
+            {
+                continue;
+            }
+
+            let pt_addr = match self.alloc_page_table()
+            {
+                Some(addr) => addr,
+                None => return Err("Failed to allocate page table")
+            };
+
+            self.get_pde_of(dst_pd_addr, i).set(pt_addr, src_pde.get_flags());
+
+            for j in 0..self.get_pte_cnt()
+            {
+                let mut src_pte = self.get_pte_of(src_pde.get_page_table_addr(), j);
+                let dst_pte = self.get_pte_of(pt_addr, j);
+
+                if !src_pte.get_flag_present()
+                {
+                    continue;
+                }
+
+                let src_addr = src_pte.get_page_frame_addr();
+
+                if PHYS_MEM_MANAGER.lock().ref_mem_block((src_addr / MEM_BLOCK_SIZE as u64) as usize).is_ok()
+                {
+                    if src_pte.get_flag_writable()
+                    {
+                        src_pte.clear_flag(PTE_FLAGS_R_W);
+                        src_pte.set_flag(PTE_FLAGS_AVL_COW);
+                        is_src_changed = true;
+                    }
+
+                    dst_pte.set_inner(src_pte.get_inner());
+                    continue;
+                }
+
+                // reference count is saturated or frame is reserved, give a private copy
+                let frame = match self.copy_frame(src_addr)
+                {
+                    Some(frame) => frame,
+                    None => return Err("Failed to allocate page frame")
+                };
+
+                let mut flags = src_pte.get_flags();
+
+                if src_pte.get_inner() & PTE_FLAGS_AVL_COW != 0
+                {
+                    flags |= PTE_FLAGS_R_W;
+                }
+
+                dst_pte.set_inner((frame.mem_block_start_addr & PTE_PAGE_FRAME_ADDR_MASK) | flags);
+            }
+        }
+
+        // user area is used only by this CPU
+        if is_src_changed && src_pd_addr == self.current_pd_addr && self.is_enabled()
+        {
+            asm::flush_tlb();
+        }
+
+        return Ok(());
+    }
+
+    /// give the page its own writable frame if it's shared by copy-on-write
+    pub fn copy_on_write(&mut self, virt_addr: u32) -> Result<(), &'static str>
+    {
+        let page_addr = virt_addr & !(PAGE_SIZE - 1);
+
+        let mut pte = match self.find_page_table_entry(page_addr)
+        {
+            Some(pte) if pte.get_flag_present() => pte,
+            _ => return Err("Page is not mapped")
+        };
+
+        if pte.get_inner() & PTE_FLAGS_AVL_COW == 0
+        {
+            return Err("Page is not copy-on-write");
+        }
+
+        let src_addr = pte.get_page_frame_addr();
+        let flags = pte.get_flags() | PTE_FLAGS_R_W;
+
+        // last reference takes over the frame
+        if PHYS_MEM_MANAGER.lock().get_ref_cnt((src_addr / MEM_BLOCK_SIZE as u64) as usize) <= 1
+        {
+            pte.set(src_addr, flags);
+        }
+        else
+        {
+            let frame = match self.copy_frame(src_addr)
+            {
+                Some(frame) => frame,
+                None => return Err("Failed to allocate page frame")
+            };
+
+            pte.set(frame.mem_block_start_addr, flags);
+            free_frame(src_addr);
+        }
+
+        self.flush_page(page_addr, true);
+        return Ok(());
+    }
+
+    /// free user pages, page tables and the page directory itself
+    pub fn free_page_directory(&mut self, pd_block: MemoryBlockInfo)
+    {
+        let pd_addr = pd_block.mem_block_start_addr as u32;
+
+        if pd_addr == self.get_kernel_page_directory_addr()
+        {
+            return;
+        }
+
+        if pd_addr == self.current_pd_addr
+        {
+            self.switch_page_directory(self.get_kernel_page_directory_addr());
+        }
+
+        for i in 0..self.get_user_pde_end()
+        {
+            let mut pde = self.get_pde_of(pd_addr, i);
+
+            if !pde.get_flag_present()
+            {
+                continue;
+            }
+
+            for j in 0..self.get_pte_cnt()
+            {
+                let pte = self.get_pte_of(pde.get_page_table_addr(), j);
+
+                if pte.get_flag_present()
+                {
+                    free_frame(pte.get_page_frame_addr());
+                }
+            }
+
+            free_frame(pde.get_page_table_addr() as u64);
+            self.page_table_cnt -= 1;
+            pde.set(0, 0);
+        }
+
+        if self.is_pae
+        {
+            free_user_page_directories(pd_addr);
+        }
+
+        PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(pd_block);
+    }
+
+    /// load page directory to cr3, user area is switched to the one of pd_addr
+    pub fn switch_page_directory(&mut self, pd_addr: u32)
+    {
+        if !self.is_enabled() || self.current_pd_addr == pd_addr
+        {
+            return;
+        }
+
+        self.current_pd_addr = pd_addr;
+        asm::set_cr3(pd_addr);
+    }
+
+    pub fn get_kernel_page_directory_addr(&self) -> u32
+    {
+        return self.pd_block.mem_block_start_addr as u32;
+    }
+
+    pub fn get_current_page_directory_addr(&self) -> u32
+    {
+        return self.current_pd_addr;
+    }
+
+    /// copy kernel PDE of virt_addr to current page directory if it was changed, returns true if updated
+    /// kernel page directory of PAE is shared, so it's never out of date
+    pub fn sync_kernel_pde(&mut self, virt_addr: u32) -> bool
+    {
+        let index = self.get_pd_index(virt_addr);
+
+        if !self.is_enabled() || self.is_pae || self.is_user_pde(index) || self.current_pd_addr == self.get_kernel_page_directory_addr()
+        {
+            return false;
+        }
+
+        let kernel_pde = self.get_page_directory_entry(index).get_inner();
+        let pde = self.get_pde_of(self.current_pd_addr, index);
+
+        if pde.get_inner() == kernel_pde
+        {
+            return false;
+        }
+
+        pde.set_inner(kernel_pde);
+        asm::invlpg(virt_addr);
+
+        return true;
+    }
+
+    pub fn get_total_mem_size(&self) -> u64
     {
         return PHYS_MEM_MANAGER.lock().get_total_mem_size();
     }
 
-    pub fn get_used_mem_size(&self) -> u32
+    pub fn get_used_mem_size(&self) -> u64
     {
-        return PHYS_MEM_MANAGER.lock().get_allocated_blocks() * MEM_BLOCK_SIZE;
+        return PHYS_MEM_MANAGER.lock().get_used_mem_size();
     }
 
-    pub fn get_free_mem_size(&self) -> u32
+    pub fn get_free_mem_size(&self) -> u64
     {
         return self.get_total_mem_size() - self.get_used_mem_size();
     }
 
+    /// fill the frame at phys_addr with zero, frame can be above direct map
+    pub fn clear_frame(&mut self, phys_addr: u64) -> Result<(), &'static str>
+    {
+        let addr = self.kmap(phys_addr, KMAP_SLOT_DST)?;
+        unsafe { write_bytes(addr as *mut u8, 0, PAGE_SIZE as usize); }
+        self.kunmap(addr);
+
+        return Ok(());
+    }
+
+    /// raw PDE and PTE (if page table exists) of virt_addr
+    pub fn get_raw_entries(&self, virt_addr: u32) -> Option<(u64, Option<u64>)>
+    {
+        if !self.is_init()
+        {
+            return None;
+        }
+
+        let pde = self.get_page_directory_entry(self.get_pd_index(virt_addr)).get_inner();
+        let pte = self.find_page_table_entry(virt_addr).map(|pte| pte.get_inner());
+
+        return Some((pde, pte));
+    }
+
+    pub fn get_page_table_cnt(&self) -> usize
+    {
+        return self.page_table_cnt;
+    }
+
+    fn check_args(&self, virt_addr: u32, flags: u32) -> Result<(), &'static str>
+    {
+        if !self.is_init()
+        {
+            return Err("Paging is not initialized");
+        }
+
+        if virt_addr % PAGE_SIZE != 0
+        {
+            return Err("Virtual address is not page aligned");
+        }
+
+        if flags & !PAGE_FLAGS_ALL != 0
+        {
+            return Err("Invalid page flags");
+        }
+
+        return Ok(());
+    }
+
+    // PAGE_FLAGS_* to flags of PTE
+    fn get_entry_flags(&self, flags: u32) -> u64
+    {
+        let mut entry_flags = (flags & !PAGE_FLAGS_NO_EXECUTE) as u64;
+
+        if flags & PAGE_FLAGS_NO_EXECUTE != 0 && self.is_nx_enabled
+        {
+            entry_flags |= PTE_FLAGS_XD;
+        }
+
+        return entry_flags;
+    }
+
+    // set PTE and allocate the page table if needed, returns true if the page was present
+    fn set_page(&mut self, virt_addr: u32, phys_addr: u64, flags: u32) -> Result<bool, &'static str>
+    {
+        let pd_i = self.get_pd_index(virt_addr);
+        let pt_i = self.get_pt_index(virt_addr);
+
+        let mut pde = self.get_page_directory_entry(pd_i);
+
+        if !pde.get_flag_present()
+        {
+            let pt_addr = match self.alloc_page_table()
+            {
+                Some(addr) => addr,
+                None => return Err("Failed to allocate page table")
+            };
+
+            pde.set(pt_addr, PDE_FLAGS_P | PDE_FLAGS_R_W);
+        }
+
+        // U/S of PDE must be set too, PTE still protects other pages
+        if flags & PAGE_FLAGS_USER != 0
+        {
+            pde.set_flag(PDE_FLAGS_U_S);
+        }
+
+        // other address spaces get the kernel PDE on page fault
+        if !self.is_pae && !self.is_user_pde(pd_i) && self.current_pd_addr != self.get_kernel_page_directory_addr()
+        {
+            self.get_pde_of(self.current_pd_addr, pd_i).set_inner(pde.get_inner());
+        }
+
+        let mut pte = self.get_page_table_entry(pd_i, pt_i);
+        let was_present = pte.get_flag_present();
+        pte.set(phys_addr, self.get_entry_flags(flags) | PTE_FLAGS_P);
+
+        return Ok(was_present);
+    }
+
+    fn alloc_page_table(&mut self) -> Option<u32>
+    {
+        let mb_info = self.alloc_frame()?;
+        self.page_table_cnt += 1;
+
+        return Some(mb_info.mem_block_start_addr as u32);
+    }
+
+    // zeroed frame in direct map for paging structures
+    fn alloc_frame(&mut self) -> Option<MemoryBlockInfo>
+    {
+        let mb_info = PHYS_MEM_MANAGER.lock().alloc_single_mem_block()?;
+        PHYS_MEM_MANAGER.lock().clear_mem_block(&mb_info);
+
+        return Some(mb_info);
+    }
+
+    // new frame with contents of the frame at src_addr, both can be above direct map
+    fn copy_frame(&mut self, src_addr: u64) -> Option<MemoryBlockInfo>
+    {
+        let frame = PHYS_MEM_MANAGER.lock().alloc_high_mem_block()?;
+
+        let src = match self.kmap(src_addr, KMAP_SLOT_SRC)
+        {
+            Ok(addr) => addr,
+            Err(_) =>
+            {
+                free_frame(frame.mem_block_start_addr);
+                return None;
+            }
+        };
+
+        let dst = match self.kmap(frame.mem_block_start_addr, KMAP_SLOT_DST)
+        {
+            Ok(addr) => addr,
+            Err(_) =>
+            {
+                self.kunmap(src);
+                free_frame(frame.mem_block_start_addr);
+                return None;
+            }
+        };
+
+        unsafe { copy_nonoverlapping(src as *const u8, dst as *mut u8, MEM_BLOCK_SIZE as usize); }
+
+        self.kunmap(src);
+        self.kunmap(dst);
+
+        return Some(frame);
+    }
+
+    // kernel virtual address of the frame, frame above direct map is mapped at slot until kunmap
+    fn kmap(&mut self, phys_addr: u64, slot: u32) -> Result<u32, &'static str>
+    {
+        if phys_addr < DIRECT_MAP_SIZE as u64
+        {
+            return Ok(phys_to_virt(phys_addr as u32));
+        }
+
+        let was_present = self.set_page(slot, phys_addr, PAGE_FLAGS_WRITABLE | PAGE_FLAGS_NO_EXECUTE)?;
+        self.flush_page(slot, was_present);
+
+        return Ok(slot);
+    }
+
+    // slot is used only by this CPU while the lock is held, so local invalidation is enough
+    fn kunmap(&mut self, virt_addr: u32)
+    {
+        if virt_addr != KMAP_SLOT_SRC && virt_addr != KMAP_SLOT_DST
+        {
+            return;
+        }
+
+        if let Some(mut pte) = self.find_page_table_entry(virt_addr)
+        {
+            pte.set(0, 0);
+            asm::invlpg(virt_addr);
+        }
+    }
+
+    // other CPUs can have the old translation only if the page was present
+    // they are flushed by PagingGuard after unlock, more than one page flushes all
+    fn flush_page(&mut self, virt_addr: u32, was_present: bool)
+    {
+        if !self.is_enabled()
+        {
+            return;
+        }
+
+        asm::invlpg(virt_addr);
+
+        if !was_present || !smp::is_init()
+        {
+            return;
+        }
+
+        self.pending_flush = match self.pending_flush
+        {
+            PendingFlush::None => PendingFlush::Page(virt_addr),
+            PendingFlush::Page(addr) if addr == virt_addr => PendingFlush::Page(addr),
+            _ => PendingFlush::All
+        };
+    }
+
+    fn find_page_table_entry(&self, virt_addr: u32) -> Option<PageTableEntry>
+    {
+        let pd_i = self.get_pd_index(virt_addr);
+
+        if !self.get_page_directory_entry(pd_i).get_flag_present()
+        {
+            return None;
+        }
+
+        return Some(self.get_page_table_entry(pd_i, self.get_pt_index(virt_addr)));
+    }
+
     fn get_page_directory_entry(&self, index: usize) -> PageDirectoryEntry
     {
-        return PageDirectoryEntry::new(self.pd_block.mem_block_start_addr + index as u32 * 4);
+        let pd_addr = if self.is_user_pde(index) { self.current_pd_addr } else { self.get_kernel_page_directory_addr() };
+        return self.get_pde_of(pd_addr, index);
     }
 
     fn get_page_table_entry(&self, page_directory_index: usize, page_table_index: usize) -> PageTableEntry
     {
         let pde = self.get_page_directory_entry(page_directory_index);
-        let pt_addr = pde.get_page_table_addr();
-        return PageTableEntry::new(pt_addr + page_table_index as u32 * 4);
+        return self.get_pte_of(pde.get_page_table_addr(), page_table_index);
+    }
+
+    // PDE of the address space whose cr3 is pd_addr
+    fn get_pde_of(&self, pd_addr: u32, index: usize) -> PageDirectoryEntry
+    {
+        if !self.is_pae
+        {
+            return PageDirectoryEntry::new(pd_addr + index as u32 * 4, false);
+        }
+
+        let pdpte = read_pdpte(pd_addr, index / PAE_PAGE_DIRECTORY_ENTRIES);
+        let dir_addr = (pdpte & PDPTE_PAGE_DIRECTORY_ADDR_MASK) as u32;
+
+        return PageDirectoryEntry::new(dir_addr + (index % PAE_PAGE_DIRECTORY_ENTRIES) as u32 * 8, true);
+    }
+
+    fn get_pte_of(&self, pt_addr: u32, index: usize) -> PageTableEntry
+    {
+        let entry_size = if self.is_pae { 8 } else { 4 };
+        return PageTableEntry::new(pt_addr + index as u32 * entry_size, self.is_pae);
+    }
+
+    fn get_pd_index(&self, virt_addr: u32) -> usize
+    {
+        if self.is_pae
+        {
+            return (virt_addr >> PAE_PD_INDEX_SHIFT) as usize;
+        }
+
+        return VirtualAddress::new(virt_addr).get_page_directory_index();
+    }
+
+    fn get_pt_index(&self, virt_addr: u32) -> usize
+    {
+        if self.is_pae
+        {
+            return (virt_addr / PAGE_SIZE) as usize % PAE_PAGE_TABLE_ENTRIES;
+        }
+
+        return VirtualAddress::new(virt_addr).get_page_table_index();
+    }
+
+    fn get_pte_cnt(&self) -> usize
+    {
+        return if self.is_pae { PAE_PAGE_TABLE_ENTRIES } else { PAGE_TABLE_ENTRIES };
     }
 
-    fn get_page_directory_index(&self, mem_block_index: usize) -> usize
+    fn get_user_pde_end(&self) -> usize
     {
-        return mem_block_index / 1024 % 1024;
+        return if self.is_pae { PAE_USER_PDE_END } else { USER_PDE_END };
     }
 
-    fn get_page_table_index(&self, mem_block_index: usize) -> usize
+    fn is_user_pde(&self, index: usize) -> bool
     {
-        return mem_block_index % 1024;
+        return index < self.get_user_pde_end();
     }
-}
\ No newline at end of file
+}
+
+// base_addr is physical, paging structures are accessed through direct map
+fn read_entry(base_addr: u32, is_pae: bool) -> u64
+{
+    unsafe
+    {
+        if is_pae
+        {
+            return read_volatile(phys_to_virt(base_addr) as *const u64);
+        }
+
+        return read_volatile(phys_to_virt(base_addr) as *const u32) as u64;
+    }
+}
+
+// 64-bit entry is written by two stores, MMU must not see a present entry with half of new address
+fn write_entry(base_addr: u32, is_pae: bool, inner: u64)
+{
+    unsafe
+    {
+        let ptr = phys_to_virt(base_addr) as *mut u32;
+
+        if !is_pae
+        {
+            write_volatile(ptr, inner as u32);
+            return;
+        }
+
+        let high = (inner >> 32) as u32;
+
+        if read_volatile(ptr.offset(1)) != high
+        {
+            write_volatile(ptr, 0);
+            write_volatile(ptr.offset(1), high);
+        }
+
+        write_volatile(ptr, inner as u32);
+    }
+}
+
+fn read_pdpte(pdpt_addr: u32, index: usize) -> u64
+{
+    unsafe { return read_volatile((phys_to_virt(pdpt_addr) as *const u64).offset(index as isize)); }
+}
+
+fn write_pdpte(pdpt_addr: u32, index: usize, pdpte: u64)
+{
+    unsafe { write_volatile((phys_to_virt(pdpt_addr) as *mut u64).offset(index as isize), pdpte); }
+}
+
+// page directories of PAE user area, page tables must be freed before
+fn free_user_page_directories(pdpt_addr: u32)
+{
+    for i in 0..PAE_USER_PDPTE_END
+    {
+        let pdpte = read_pdpte(pdpt_addr, i);
+
+        if pdpte & PDPTE_FLAGS_P != 0
+        {
+            free_frame(pdpte & PDPTE_PAGE_DIRECTORY_ADDR_MASK);
+            write_pdpte(pdpt_addr, i, 0);
+        }
+    }
+}
+
+// kernel code and real mode area are executable in direct map
+fn is_kernel_code(phys_addr: u32) -> bool
+{
+    let text_start = virt_to_phys(unsafe { &kernel_text_start as *const u8 as u32 });
+    let text_end = virt_to_phys(unsafe { &kernel_text_end as *const u8 as u32 });
+
+    return phys_addr < REAL_MODE_AREA_SIZE || (phys_addr >= text_start && phys_addr < text_end);
+}
+
+fn free_frame(addr: u64)
+{
+    let mut pmm = PHYS_MEM_MANAGER.lock();
+    let index = pmm.get_mem_block_index_from_phys_addr(addr);
+
+    if let Some(mb_info) = pmm.get_mem_block(index)
+    {
+        pmm.dealloc_single_mem_block(mb_info);
+    }
+}
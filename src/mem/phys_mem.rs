@@ -1,16 +1,23 @@
 use core::ptr::{write_volatile, read_volatile};
 use multiboot2::{BootInformation, MemoryAreaType};
-use crate::{println, util::{boot_info::*, logger::*}, mem};
+use crate::{println, util::{boot_info::*, logger::*}};
 
-use super::allocator::{HEAP_AREA_BASE_ADDR, HEAP_SIZE};
+use super::{allocator::{HEAP_AREA_BASE_ADDR, HEAP_INIT_SIZE}, phys_to_virt, virt_to_phys, DIRECT_MAP_SIZE};
 
 pub const MEM_BLOCK_SIZE: u32 = 4096;
+// memory above this is not used (PAE can address up to 64GiB)
+pub const MAX_PHYS_MEM_SIZE: u64 = 0x400000000; // 16GiB
+// allocation bitmap, 1 bit per block (512KiB for MAX_PHYS_MEM_SIZE)
+pub const MEMMAP_ADDR: u32 = 0x5200000;
+// reference counts of blocks shared by copy-on-write, 1 byte per block (4MiB for MAX_PHYS_MEM_SIZE)
+pub const REF_CNT_TABLE_ADDR: u32 = 0x5300000;
+const REF_CNT_MAX: u8 = u8::MAX;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct MemoryBlockInfo
 {
     pub memmap_addr: u32,
-    pub mem_block_start_addr: u32,
+    pub mem_block_start_addr: u64,
     pub mem_block_size: u32,
     pub mem_block_index: usize,
     pub is_used: bool
@@ -34,8 +41,10 @@ impl MemoryBlockInfo
 #[derive(Debug, PartialEq, Eq)]
 pub struct PhysicalMemoryManager
 {
-    total_mem_size: u32,
+    total_mem_size: u64,
     mem_blocks: u32,
+    // blocks in direct map
+    low_mem_blocks: u32,
     allocated_blocks: u32,
     free_blocks: u32,
     memmap_addr: u32,
@@ -50,6 +59,7 @@ impl PhysicalMemoryManager
         {
             total_mem_size: 0,
             mem_blocks: 0,
+            low_mem_blocks: 0,
             allocated_blocks: 0,
             free_blocks: 0,
             memmap_addr: 0,
@@ -57,130 +67,85 @@ impl PhysicalMemoryManager
         }
     }
 
-    pub fn init(&mut self, boot_info: &BootInformation)
+    /// max_phys_addr is the limit of physical address of current paging mode
+    pub fn init(&mut self, boot_info: &BootInformation, max_phys_addr: u64)
     {
-        let total = get_total_mem_size(boot_info);
+        // blocks are indexed by physical address, up to the end of available memory
+        let mut mem_end = get_all_mem_areas(boot_info)
+            .filter(|area| area.typ() == MemoryAreaType::Available)
+            .map(|area| area.end_address())
+            .max()
+            .unwrap_or(0);
 
-        if total <= u32::MAX as u64
+        let max_mem_size = max_phys_addr.min(MAX_PHYS_MEM_SIZE);
+
+        if mem_end > max_mem_size
         {
-            self.total_mem_size = total as u32;
+            log_warn("Memory beyond maximum physical address is not used");
+            mem_end = max_mem_size;
         }
-        else  // greetar than 4GB memory
+
+        self.mem_blocks = (mem_end / MEM_BLOCK_SIZE as u64) as u32;
+        self.total_mem_size = self.mem_blocks as u64 * MEM_BLOCK_SIZE as u64;
+        // only blocks in direct map are accessed by kernel
+        self.low_mem_blocks = self.mem_blocks.min(DIRECT_MAP_SIZE / MEM_BLOCK_SIZE);
+        self.memmap_addr = MEMMAP_ADDR;
+        self.memmap_size = (self.mem_blocks + u32::BITS - 1) / u32::BITS * 4; // memmap size (byte)
+
+        // set all blocks to allocated
+        for i in 0..self.memmap_size / 4
         {
-            self.total_mem_size = u32::MAX;
+            self.write_memmap(i as isize, u32::MAX);
         }
 
-        self.mem_blocks = self.total_mem_size / MEM_BLOCK_SIZE;
         self.allocated_blocks = self.mem_blocks;
         self.free_blocks = 0;
-        self.memmap_size = self.mem_blocks / u32::BITS * 4; // memmap size (byte)
 
+        // set blocks of available memory to free
         for area in get_all_mem_areas(boot_info)
         {
-            if area.typ() == MemoryAreaType::Available
+            if area.typ() != MemoryAreaType::Available
             {
-                self.memmap_addr = area.start_address() as u32;
-
-                // allocate memory blocks
-                for i in self.memmap_addr..self.memmap_addr + self.memmap_size
-                {
-                    if i % MEM_BLOCK_SIZE == 0
-                    {
-                        let mb_index = self.get_mem_block_index_from_phys_addr(i);
-                        if !self.is_allocated_mem_block(mb_index)
-                        {
-                            self.allocate_mem_block(mb_index);
-                            self.allocated_blocks += 1;
-                        }
-                    }
-                }
+                continue;
             }
-        }
 
-        // set all blocks to allocated
-        for i in 0..self.mem_blocks
-        {
-            self.allocate_mem_block(i as usize);
-        }
+            let start = (area.start_address() + MEM_BLOCK_SIZE as u64 - 1) / MEM_BLOCK_SIZE as u64;
+            let end = area.end_address().min(self.total_mem_size) / MEM_BLOCK_SIZE as u64;
 
-        // set blocks of available memory to free
-        let mut mb_index = 0;
-        let mut tmp = 0;
-
-        for area in get_all_mem_areas(boot_info)
-        {
-            let mut i = area.start_address() as u32 + tmp;
-
-            loop
+            for i in start..end
             {
-                if self.free_blocks == self.mem_blocks
-                {
-                    break;
-                }
-
-                if area.typ() != MemoryAreaType::Available
+                if self.is_allocated_mem_block(i as usize)
                 {
-                    mb_index = area.size() as u32 / MEM_BLOCK_SIZE;
-                    tmp = area.size() as u32 % MEM_BLOCK_SIZE;
-                    break;
+                    self.deallocate_mem_block(i as usize);
+                    self.allocated_blocks -= 1;
+                    self.free_blocks += 1;
                 }
-
-                if i > (area.end_address() - 1) as u32
-                {
-                    tmp = i - (area.end_address() - 1) as u32;
-                    break;
-                }
-
-                self.deallocate_mem_block(mb_index as usize);
-                self.allocated_blocks -= 1;
-                self.free_blocks += 1;
-
-                mb_index += 1;
-                i += MEM_BLOCK_SIZE;
             }
         }
 
         // set reallocate blocks
         let (_, e) = get_multiboot_addr(boot_info);
-        for i in 0..e as u32
-        {
-            if i % MEM_BLOCK_SIZE == 0
-            {
-                let mb_index = self.get_mem_block_index_from_phys_addr(i);
-                if !self.is_allocated_mem_block(mb_index)
-                {
-                    self.allocate_mem_block(mb_index);
-                    self.allocated_blocks += 1;
-                    self.free_blocks -= 1;
-                }
-            }
-        }
+        self.reserve_mem_blocks(0, e as u32);
 
         // set allocate heap area blocks
-        for i in HEAP_AREA_BASE_ADDR..HEAP_AREA_BASE_ADDR + HEAP_SIZE
-        {
-            if i % MEM_BLOCK_SIZE == 0
-            {
-                let mb_index = self.get_mem_block_index_from_phys_addr(i);
-                if !self.is_allocated_mem_block(mb_index)
-                {
-                    self.allocate_mem_block(mb_index);
-                    self.allocated_blocks += 1;
-                    self.free_blocks -= 1;
-                }
-            }
-        }
+        self.reserve_mem_blocks(virt_to_phys(HEAP_AREA_BASE_ADDR), HEAP_INIT_SIZE);
+
+        // set allocate memmap and reference count table blocks
+        self.reserve_mem_blocks(MEMMAP_ADDR, self.memmap_size);
+        self.reserve_mem_blocks(REF_CNT_TABLE_ADDR, self.mem_blocks);
+
+        self.memset(REF_CNT_TABLE_ADDR, self.mem_blocks, 0);
     }
 
     pub fn get_mem_block(&mut self, index: usize) -> Option<MemoryBlockInfo>
     {
-        if index > self.mem_blocks as usize
+        if index >= self.mem_blocks as usize
         {
             return None;
         }
 
         let memmap_addr = self.memmap_addr + index as u32 / 8;
-        let mem_block_start_addr = index as u32 * MEM_BLOCK_SIZE;
+        let mem_block_start_addr = index as u64 * MEM_BLOCK_SIZE as u64;
         let mem_block_size = MEM_BLOCK_SIZE;
         let is_used = self.is_allocated_mem_block(index);
 
@@ -194,91 +159,150 @@ impl PhysicalMemoryManager
         });
     }
 
-    fn get_first_free_mem_block(&mut self) -> MemoryBlockInfo
+    fn get_first_free_mem_block(&self, start_index: usize, end_index: usize) -> Option<usize>
     {
-        let mut mem_block = MemoryBlockInfo::new();
-        let mut i = 0;
+        let mut i = start_index;
 
-        loop
+        while i < end_index
         {
-            if i > self.mem_blocks as usize
-            {
-                break;
-            }
+            let map = self.read_memmap((i / u32::BITS as usize) as isize);
 
-            if self.read_memmap(i as isize) == u32::MAX
+            if map == u32::MAX
             {
-                i += u32::BITS as usize;
+                i = (i / u32::BITS as usize + 1) * u32::BITS as usize;
                 continue;
             }
 
-            if !self.is_allocated_mem_block(i)
+            if map & (1 << (i % u32::BITS as usize)) == 0
             {
-                if let Some(mb) = self.get_mem_block(i)
-                {
-                    mem_block = mb;
-                }
-
-                break;
+                return Some(i);
             }
 
-            i += u32::BITS as usize;
+            i += 1;
         }
 
-        return mem_block;
+        return None;
     }
 
+    /// allocate a block in direct map, it can be accessed at phys_to_virt
     pub fn alloc_single_mem_block(&mut self) -> Option<MemoryBlockInfo>
     {
-        let mut result = None;
+        let result = match self.get_first_free_mem_block(0, self.low_mem_blocks as usize)
+        {
+            Some(index) => self.alloc_mem_block_at(index),
+            None => None
+        };
 
-        if self.free_blocks <= 0
+        if result == None
         {
-            result = None;
+            log_error("Failed to allocate memory block");
         }
 
-        let free_mb = self.get_first_free_mem_block();
-
-        self.allocate_mem_block(free_mb.mem_block_index);
-        self.free_blocks -= 1;
-        self.allocated_blocks += 1;
+        return result;
+    }
 
-        match self.get_mem_block(free_mb.mem_block_index)
+    /// allocate a block above direct map if possible (for user pages)
+    /// it's accessed through its own mapping or Paging::kmap
+    pub fn alloc_high_mem_block(&mut self) -> Option<MemoryBlockInfo>
+    {
+        if let Some(index) = self.get_first_free_mem_block(self.low_mem_blocks as usize, self.mem_blocks as usize)
         {
-            Some(mb_info) => result = Some(mb_info),
-            None => result = None
+            return self.alloc_mem_block_at(index);
         }
 
-        if result == None
+        return self.alloc_single_mem_block();
+    }
+
+    /// allocate the block of index if it's free
+    pub fn alloc_mem_block_at(&mut self, index: usize) -> Option<MemoryBlockInfo>
+    {
+        if index >= self.mem_blocks as usize || self.is_allocated_mem_block(index)
         {
-            log_error("Failed to allocate memory block");
+            return None;
         }
 
-        return result;
+        self.allocate_mem_block(index);
+        self.write_ref_cnt(index, 1);
+        self.free_blocks -= 1;
+        self.allocated_blocks += 1;
+
+        return self.get_mem_block(index);
     }
 
+    /// shared block is freed when the last reference is released
+    /// blocks reserved at init (kernel, memmap, heap etc.) have no count and are never freed
     pub fn dealloc_single_mem_block(&mut self, mem_block: MemoryBlockInfo)
     {
+        let ref_cnt = self.get_ref_cnt(mem_block.mem_block_index);
+
+        if ref_cnt == 0
+        {
+            return;
+        }
+
+        if mem_block.is_used && ref_cnt > 1
+        {
+            self.write_ref_cnt(mem_block.mem_block_index, ref_cnt - 1);
+            return;
+        }
+
         if mem_block.is_used
         {
+            self.write_ref_cnt(mem_block.mem_block_index, 0);
             self.deallocate_mem_block(mem_block.mem_block_index);
             self.free_blocks += 1;
             self.allocated_blocks -= 1;
         }
     }
 
+    /// add a reference to allocated block, fails if the count is saturated or block is reserved at init
+    pub fn ref_mem_block(&mut self, index: usize) -> Result<(), &'static str>
+    {
+        if index >= self.mem_blocks as usize || !self.is_allocated_mem_block(index)
+        {
+            return Err("Memory block is not allocated");
+        }
+
+        let ref_cnt = self.get_ref_cnt(index);
+
+        if ref_cnt == 0
+        {
+            return Err("Memory block is reserved");
+        }
+
+        if ref_cnt == REF_CNT_MAX
+        {
+            return Err("Too many references to memory block");
+        }
+
+        self.write_ref_cnt(index, ref_cnt + 1);
+        return Ok(());
+    }
+
+    pub fn get_ref_cnt(&self, index: usize) -> u8
+    {
+        if index >= self.mem_blocks as usize
+        {
+            return 0;
+        }
+
+        return unsafe { read_volatile(phys_to_virt(REF_CNT_TABLE_ADDR + index as u32) as *const u8) };
+    }
+
     // FIXME: this function has no end (but, throw no exception)
+    /// block must be in direct map
     pub fn clear_mem_block(&self, mem_block: &MemoryBlockInfo)
     {
-        //println!("Clearing memory block 0x{:x} - 0x{:x}...", mem_block.mem_block_start_addr, mem_block.mem_block_start_addr + mem_block.mem_block_size);
+        //println!("Clearing memory block 0x{:x} - 0x{:x}...", mem_block.mem_block_start_addr, mem_block.mem_block_start_addr + mem_block.mem_block_size as u64);
 
-        let mut i = mem_block.mem_block_start_addr;
+        let start_addr = mem_block.mem_block_start_addr as u32;
+        let mut i = start_addr;
 
-        while i < mem_block.mem_block_start_addr + mem_block.mem_block_size
+        while i < start_addr + mem_block.mem_block_size
         {
             unsafe
             {
-                let ptr = i as *mut u32;
+                let ptr = phys_to_virt(i) as *mut u32;
                 write_volatile(ptr, 0);
             }
 
@@ -286,31 +310,38 @@ impl PhysicalMemoryManager
         }
     }
 
+    /// base_addr is physical address
     pub fn memset(&self, base_addr: u32, size: u32, data: u8)
     {
         for i in base_addr..base_addr + size
         {
             unsafe
             {
-                let ptr = i as *mut u8;
+                let ptr = phys_to_virt(i) as *mut u8;
                 write_volatile(ptr, data);
             }
         }
     }
 
-    pub fn get_total_mem_size(&self) -> u32
+    pub fn get_total_mem_size(&self) -> u64
     {
         return self.total_mem_size;
     }
 
-    pub fn get_free_mem_size(&self) -> u32
+    pub fn get_free_mem_size(&self) -> u64
+    {
+        return self.total_mem_size - self.get_used_mem_size();
+    }
+
+    pub fn get_used_mem_size(&self) -> u64
     {
-        return self.total_mem_size - self.allocated_blocks * MEM_BLOCK_SIZE;
+        return self.allocated_blocks as u64 * MEM_BLOCK_SIZE as u64;
     }
 
-    pub fn get_used_mem_size(&self) -> u32
+    /// size of memory in direct map
+    pub fn get_low_mem_size(&self) -> u32
     {
-        return self.allocated_blocks * MEM_BLOCK_SIZE;
+        return self.low_mem_blocks * MEM_BLOCK_SIZE;
     }
 
     pub fn get_mem_blocks(&self) -> u32
@@ -328,9 +359,9 @@ impl PhysicalMemoryManager
         return self.free_blocks;
     }
 
-    pub fn get_mem_block_index_from_phys_addr(&self, phys_addr: u32) -> usize
+    pub fn get_mem_block_index_from_phys_addr(&self, phys_addr: u64) -> usize
     {
-        return (phys_addr / MEM_BLOCK_SIZE) as usize;
+        return (phys_addr / MEM_BLOCK_SIZE as u64) as usize;
     }
 
     pub fn get_memmap_start_addr(&self) -> u32
@@ -343,6 +374,23 @@ impl PhysicalMemoryManager
         return self.memmap_addr + self.memmap_size;
     }
 
+    /// set allocate blocks in the area reserved by kernel, base_addr is physical address
+    fn reserve_mem_blocks(&mut self, base_addr: u32, size: u32)
+    {
+        let start = base_addr / MEM_BLOCK_SIZE;
+        let end = (base_addr + size + MEM_BLOCK_SIZE - 1) / MEM_BLOCK_SIZE;
+
+        for i in start..end.min(self.mem_blocks)
+        {
+            if !self.is_allocated_mem_block(i as usize)
+            {
+                self.allocate_mem_block(i as usize);
+                self.allocated_blocks += 1;
+                self.free_blocks -= 1;
+            }
+        }
+    }
+
     fn allocate_mem_block(&mut self, mem_block_index: usize)
     {
         let offset = (mem_block_index / u32::BITS as usize) as isize;
@@ -365,11 +413,19 @@ impl PhysicalMemoryManager
         return tmp & (1 << (mem_block_index % u32::BITS as usize)) > 0;
     }
 
+    fn write_ref_cnt(&self, index: usize, ref_cnt: u8)
+    {
+        if index < self.mem_blocks as usize
+        {
+            unsafe { write_volatile(phys_to_virt(REF_CNT_TABLE_ADDR + index as u32) as *mut u8, ref_cnt); }
+        }
+    }
+
     fn read_memmap(&self, offset: isize) -> u32
     {
         unsafe
         {
-            let ptr = self.memmap_addr as *const u32;
+            let ptr = phys_to_virt(self.memmap_addr) as *const u32;
             return read_volatile(ptr.offset(offset));
         }
     }
@@ -378,7 +434,7 @@ impl PhysicalMemoryManager
     {
         unsafe
         {
-            let ptr = self.memmap_addr as *mut u32;
+            let ptr = phys_to_virt(self.memmap_addr) as *mut u32;
             write_volatile(ptr.offset(offset), map);
         }
     }
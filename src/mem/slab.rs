@@ -0,0 +1,368 @@
+// slab allocator for fixed-size kernel objects
+// each cache takes pages from Paging and carves them into objects of the same size
+
+use core::{marker::PhantomData, mem::{align_of, size_of}, ops::{Deref, DerefMut}, ptr::{self, null_mut}};
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{arch::asm, println};
+
+use super::{phys_mem::{MemoryBlockInfo, MEM_BLOCK_SIZE}, phys_to_virt, PAGING};
+
+const SLAB_SIZE: usize = MEM_BLOCK_SIZE as usize;
+// object can hold next pointer of free list
+const MIN_OBJ_SIZE: usize = size_of::<usize>();
+// empty slabs kept for next allocations
+const MAX_EMPTY_SLABS: usize = 1;
+
+/// called with the object on alloc (constructor) or free (destructor)
+/// hooks run after the cache lock is released, so they can use slab allocator
+pub type ObjectHook = fn(*mut u8);
+
+lazy_static!
+{
+    static ref SLAB_CACHES: Mutex<Vec<SlabCache>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlabCacheId(usize);
+
+#[derive(Debug)]
+struct Slab
+{
+    page: MemoryBlockInfo,
+    free_list: *mut u8,
+    in_use: usize
+}
+
+impl Slab
+{
+    fn contains(&self, ptr: *mut u8) -> bool
+    {
+        let addr = ptr as usize;
+        let start = phys_to_virt(self.page.mem_block_start_addr as u32) as usize;
+        return addr >= start && addr < start + SLAB_SIZE;
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabCacheStats
+{
+    pub name: &'static str,
+    pub obj_size: usize,
+    pub active_objs: usize,
+    pub total_objs: usize,
+    pub slabs: usize,
+    pub alloc_cnt: usize,
+    pub free_cnt: usize
+}
+
+#[derive(Debug)]
+pub struct SlabCache
+{
+    name: &'static str,
+    obj_size: usize,
+    objs_per_slab: usize,
+    ctor: Option<ObjectHook>,
+    dtor: Option<ObjectHook>,
+    slabs: Vec<Slab>,
+    alloc_cnt: usize,
+    free_cnt: usize
+}
+
+unsafe impl Send for SlabCache {}
+
+impl SlabCache
+{
+    fn new(name: &'static str, size: usize, align: usize, ctor: Option<ObjectHook>, dtor: Option<ObjectHook>) -> Result<SlabCache, &'static str>
+    {
+        if align == 0 || !align.is_power_of_two() || align > SLAB_SIZE
+        {
+            return Err("Invalid alignment");
+        }
+
+        let obj_size = (size.max(MIN_OBJ_SIZE) + align - 1) & !(align - 1);
+
+        if obj_size > SLAB_SIZE
+        {
+            return Err("Object is larger than slab");
+        }
+
+        return Ok(SlabCache
+        {
+            name,
+            obj_size,
+            objs_per_slab: SLAB_SIZE / obj_size,
+            ctor,
+            dtor,
+            slabs: Vec::new(),
+            alloc_cnt: 0,
+            free_cnt: 0
+        });
+    }
+
+    fn alloc(&mut self) -> Option<*mut u8>
+    {
+        // partial slab first, then empty one
+        let index = match self.slabs.iter().position(|s| s.in_use != 0 && !s.free_list.is_null())
+        {
+            Some(i) => i,
+            None => match self.slabs.iter().position(|s| !s.free_list.is_null())
+            {
+                Some(i) => i,
+                None => self.grow()?
+            }
+        };
+
+        let slab = &mut self.slabs[index];
+        let obj = slab.free_list;
+        slab.free_list = unsafe { *(obj as *mut *mut u8) };
+        slab.in_use += 1;
+        self.alloc_cnt += 1;
+
+        return Some(obj);
+    }
+
+    fn free(&mut self, obj: *mut u8) -> Result<(), &'static str>
+    {
+        let index = match self.slabs.iter().position(|s| s.contains(obj))
+        {
+            Some(i) => i,
+            None => return Err("Object doesn't belong to this cache")
+        };
+
+        let slab = &mut self.slabs[index];
+        unsafe { *(obj as *mut *mut u8) = slab.free_list; }
+        slab.free_list = obj;
+        slab.in_use -= 1;
+        self.free_cnt += 1;
+
+        if slab.in_use == 0 && self.slabs.iter().filter(|s| s.in_use == 0).count() > MAX_EMPTY_SLABS
+        {
+            let slab = self.slabs.remove(index);
+            PAGING.lock().dealloc_single_page(slab.page);
+        }
+
+        return Ok(());
+    }
+
+    fn contains(&self, obj: *mut u8) -> bool
+    {
+        return self.slabs.iter().any(|s| s.contains(obj));
+    }
+
+    // add a slab, returns its index
+    fn grow(&mut self) -> Option<usize>
+    {
+        let page = PAGING.lock().alloc_single_page()?;
+        let start = phys_to_virt(page.mem_block_start_addr as u32) as usize;
+
+        // link all objects
+        let mut free_list = null_mut();
+
+        for i in (0..self.objs_per_slab).rev()
+        {
+            let obj = (start + i * self.obj_size) as *mut u8;
+            unsafe { *(obj as *mut *mut u8) = free_list; }
+            free_list = obj;
+        }
+
+        self.slabs.push(Slab { page, free_list, in_use: 0 });
+        return Some(self.slabs.len() - 1);
+    }
+
+    /// release all empty slabs
+    fn shrink(&mut self)
+    {
+        let (empty, used): (Vec<Slab>, Vec<Slab>) = self.slabs.drain(..).partition(|s| s.in_use == 0);
+        self.slabs = used;
+
+        for slab in empty
+        {
+            PAGING.lock().dealloc_single_page(slab.page);
+        }
+    }
+
+    fn get_stats(&self) -> SlabCacheStats
+    {
+        return SlabCacheStats
+        {
+            name: self.name,
+            obj_size: self.obj_size,
+            active_objs: self.slabs.iter().map(|s| s.in_use).sum(),
+            total_objs: self.slabs.len() * self.objs_per_slab,
+            slabs: self.slabs.len(),
+            alloc_cnt: self.alloc_cnt,
+            free_cnt: self.free_cnt
+        };
+    }
+}
+
+/// object allocated from a slab cache, freed on drop
+pub struct SlabBox<T>
+{
+    ptr: *mut T,
+    cache: SlabCacheId,
+    _marker: PhantomData<T>
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+
+impl<T> SlabBox<T>
+{
+    pub fn new(cache: SlabCacheId, value: T) -> Result<SlabBox<T>, &'static str>
+    {
+        match get_stats(cache)
+        {
+            Some(stats) if stats.obj_size >= size_of::<T>() => (),
+            Some(_) => return Err("Object is larger than cache object size"),
+            None => return Err("Invalid slab cache")
+        }
+
+        let ptr = match alloc(cache)
+        {
+            Some(ptr) => ptr as *mut T,
+            None => return Err("Failed to allocate slab object")
+        };
+
+        if ptr as usize % align_of::<T>() != 0
+        {
+            let _ = free(cache, ptr as *mut u8);
+            return Err("Slab object is not aligned");
+        }
+
+        unsafe { ptr::write(ptr, value); }
+        return Ok(SlabBox { ptr, cache, _marker: PhantomData });
+    }
+}
+
+impl<T> Deref for SlabBox<T>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T
+    {
+        return unsafe { &*self.ptr };
+    }
+}
+
+impl<T> DerefMut for SlabBox<T>
+{
+    fn deref_mut(&mut self) -> &mut T
+    {
+        return unsafe { &mut *self.ptr };
+    }
+}
+
+impl<T> Drop for SlabBox<T>
+{
+    fn drop(&mut self)
+    {
+        unsafe { ptr::drop_in_place(self.ptr); }
+        let _ = free(self.cache, self.ptr as *mut u8);
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for SlabBox<T>
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result
+    {
+        return (**self).fmt(f);
+    }
+}
+
+pub fn create_cache(name: &'static str, size: usize, align: usize, ctor: Option<ObjectHook>, dtor: Option<ObjectHook>) -> Result<SlabCacheId, &'static str>
+{
+    let cache = SlabCache::new(name, size, align, ctor, dtor)?;
+
+    let is_int_enabled = asm::disable_int();
+    let mut caches = SLAB_CACHES.lock();
+    caches.push(cache);
+    let id = SlabCacheId(caches.len() - 1);
+    drop(caches);
+    asm::restore_int(is_int_enabled);
+
+    return Ok(id);
+}
+
+/// cache for objects of type T
+pub fn create_cache_for<T>(name: &'static str) -> Result<SlabCacheId, &'static str>
+{
+    return create_cache(name, size_of::<T>(), align_of::<T>(), None, None);
+}
+
+pub fn alloc(cache: SlabCacheId) -> Option<*mut u8>
+{
+    let is_int_enabled = asm::disable_int();
+    let result = SLAB_CACHES.lock().get_mut(cache.0).and_then(|c| Some((c.alloc()?, c.ctor)));
+    asm::restore_int(is_int_enabled);
+
+    let (obj, ctor) = result?;
+
+    if let Some(ctor) = ctor
+    {
+        ctor(obj);
+    }
+
+    return Some(obj);
+}
+
+pub fn free(cache: SlabCacheId, obj: *mut u8) -> Result<(), &'static str>
+{
+    let is_int_enabled = asm::disable_int();
+    let dtor = match SLAB_CACHES.lock().get(cache.0)
+    {
+        Some(c) if c.contains(obj) => Ok(c.dtor),
+        Some(_) => Err("Object doesn't belong to this cache"),
+        None => Err("Invalid slab cache")
+    };
+    asm::restore_int(is_int_enabled);
+
+    if let Some(dtor) = dtor?
+    {
+        dtor(obj);
+    }
+
+    let is_int_enabled = asm::disable_int();
+    let result = match SLAB_CACHES.lock().get_mut(cache.0)
+    {
+        Some(c) => c.free(obj),
+        None => Err("Invalid slab cache")
+    };
+    asm::restore_int(is_int_enabled);
+
+    return result;
+}
+
+pub fn shrink_all()
+{
+    let is_int_enabled = asm::disable_int();
+    SLAB_CACHES.lock().iter_mut().for_each(|c| c.shrink());
+    asm::restore_int(is_int_enabled);
+}
+
+pub fn get_stats(cache: SlabCacheId) -> Option<SlabCacheStats>
+{
+    let is_int_enabled = asm::disable_int();
+    let stats = SLAB_CACHES.lock().get(cache.0).map(|c| c.get_stats());
+    asm::restore_int(is_int_enabled);
+
+    return stats;
+}
+
+pub fn slabinfo()
+{
+    let is_int_enabled = asm::disable_int();
+    let stats: Vec<SlabCacheStats> = SLAB_CACHES.lock().iter().map(|c| c.get_stats()).collect();
+    asm::restore_int(is_int_enabled);
+
+    println!("{:<16} {:>6} {:>8} {:>8} {:>6} {:>5} {:>8} {:>8}", "name", "size", "active", "total", "slabs", "use%", "alloc", "free");
+
+    for s in stats
+    {
+        let usage = if s.total_objs == 0 { 0 } else { s.active_objs * 100 / s.total_objs };
+        println!("{:<16} {:>6} {:>8} {:>8} {:>6} {:>5} {:>8} {:>8}", s.name, s.obj_size, s.active_objs, s.total_objs, s.slabs, usage, s.alloc_cnt, s.free_cnt);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{arch::asm, println};
+use crate::{arch::{asm, smp}, println};
 
 use super::paging::Paging;
 
@@ -53,9 +53,17 @@ impl VirtualAddress
         return VirtualAddress { inner };
     }
 
+    /// invalidate the page on every CPU
     pub fn flash_tlb(&self)
     {
-        asm::invlpg(self.inner);
+        if smp::is_init()
+        {
+            smp::tlb_shootdown(Some(self.inner));
+        }
+        else
+        {
+            asm::invlpg(self.inner);
+        }
     }
 
     pub fn get_page_directory_index(&self) -> usize
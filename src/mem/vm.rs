@@ -0,0 +1,400 @@
+// virtual memory regions and page fault resolution
+// pages of heap and stack regions are allocated on first access, shared pages are copied on first write
+
+use core::fmt;
+
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::println;
+
+use super::{paging::{PAGE_FLAGS_NO_EXECUTE, PAGE_FLAGS_USER, PAGE_FLAGS_WRITABLE, PAGE_SIZE}, PAGING, PHYS_MEM_MANAGER};
+
+// page fault error code
+pub const PF_ERR_P: u32 = 0x1;     // protection violation (0: page not present)
+pub const PF_ERR_W: u32 = 0x2;     // write access
+pub const PF_ERR_U: u32 = 0x4;     // access from ring 3
+pub const PF_ERR_RSVD: u32 = 0x8;  // reserved bit set in paging structure
+pub const PF_ERR_I: u32 = 0x10;    // instruction fetch
+
+lazy_static!
+{
+    static ref VM_REGIONS: Mutex<Vec<VmRegion>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmRegionKind
+{
+    Heap,
+    Stack
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VmRegion
+{
+    pub name: &'static str,
+    pub start_addr: u32,
+    pub end_addr: u32,
+    // PAGE_FLAGS_*
+    pub flags: u32,
+    pub kind: VmRegionKind
+}
+
+impl VmRegion
+{
+    pub fn contains(&self, addr: u32) -> bool
+    {
+        return addr >= self.start_addr && addr < self.end_addr;
+    }
+}
+
+impl fmt::Display for VmRegion
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        return write!(f, "{} 0x{:08x}-0x{:08x} ({:?}, {}{}{})",
+            self.name,
+            self.start_addr,
+            self.end_addr,
+            self.kind,
+            if self.flags & PAGE_FLAGS_WRITABLE != 0 { "rw" } else { "r" },
+            if self.flags & PAGE_FLAGS_NO_EXECUTE != 0 { "" } else { "x" },
+            if self.flags & PAGE_FLAGS_USER != 0 { ", user" } else { "" });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFaultError(pub u32);
+
+impl PageFaultError
+{
+    pub fn is_present(&self) -> bool
+    {
+        return self.0 & PF_ERR_P != 0;
+    }
+
+    pub fn is_write(&self) -> bool
+    {
+        return self.0 & PF_ERR_W != 0;
+    }
+
+    pub fn is_user(&self) -> bool
+    {
+        return self.0 & PF_ERR_U != 0;
+    }
+
+    pub fn is_reserved(&self) -> bool
+    {
+        return self.0 & PF_ERR_RSVD != 0;
+    }
+
+    pub fn is_instruction_fetch(&self) -> bool
+    {
+        return self.0 & PF_ERR_I != 0;
+    }
+}
+
+impl fmt::Display for PageFaultError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let cause = if self.is_present() { "protection violation" } else { "page not present" };
+        let access = if self.is_instruction_fetch() { "instruction fetch" } else if self.is_write() { "write" } else { "read" };
+        let mode = if self.is_user() { "user" } else { "kernel" };
+
+        write!(f, "{} on {} in {} mode", cause, access, mode)?;
+
+        if self.is_reserved()
+        {
+            write!(f, ", reserved bit set")?;
+        }
+
+        return Ok(());
+    }
+}
+
+/// details of an unresolved page fault
+pub struct PageFaultReport
+{
+    pub fault_addr: u32,
+    pub err: PageFaultError,
+    pub reason: &'static str,
+    pub region: Option<VmRegion>,
+    // raw PDE and PTE (64-bit if PAE)
+    pub entries: Option<(u64, Option<u64>)>
+}
+
+impl PageFaultReport
+{
+    pub fn new(fault_addr: u32, err: PageFaultError, reason: &'static str) -> PageFaultReport
+    {
+        // report may be created while these are locked
+        let region = match VM_REGIONS.try_lock()
+        {
+            Some(regions) => regions.iter().find(|r| r.contains(fault_addr)).copied(),
+            None => None
+        };
+
+        let entries = match PAGING.try_lock()
+        {
+            Some(paging) => paging.get_raw_entries(fault_addr),
+            None => None
+        };
+
+        return PageFaultReport { fault_addr, err, reason, region, entries };
+    }
+}
+
+impl fmt::Display for PageFaultReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "CR2: 0x{:08x} ERR: 0x{:x} ({})", self.fault_addr, self.err.0, self.err)?;
+        writeln!(f, "Reason: {}", self.reason)?;
+
+        match self.region
+        {
+            Some(region) => writeln!(f, "Region: {}", region)?,
+            None => writeln!(f, "Region: none")?
+        }
+
+        match self.entries
+        {
+            Some((pde, Some(pte))) => write!(f, "PDE: 0x{:08x} PTE: 0x{:08x}", pde, pte)?,
+            Some((pde, None)) => write!(f, "PDE: 0x{:08x} PTE: -", pde)?,
+            None => write!(f, "PDE: - PTE: -")?
+        }
+
+        return Ok(());
+    }
+}
+
+/// register region of size bytes from start_addr, size can be 0 for regions growing later
+pub fn add_region(name: &'static str, start_addr: u32, size: u32, flags: u32, kind: VmRegionKind) -> Result<(), &'static str>
+{
+    if start_addr % PAGE_SIZE != 0 || size % PAGE_SIZE != 0
+    {
+        return Err("Region is not page aligned");
+    }
+
+    let end_addr = match start_addr.checked_add(size)
+    {
+        Some(addr) => addr,
+        None => return Err("Region is out of address space")
+    };
+
+    let mut regions = VM_REGIONS.lock();
+
+    if regions.iter().any(|r| (start_addr < r.end_addr && r.start_addr < end_addr) || r.start_addr == start_addr)
+    {
+        return Err("Region overlaps with other region");
+    }
+
+    regions.push(VmRegion { name, start_addr, end_addr, flags, kind });
+    return Ok(());
+}
+
+/// unregister region and free its pages
+pub fn remove_region(start_addr: u32) -> Result<(), &'static str>
+{
+    let mut regions = VM_REGIONS.lock();
+
+    let index = match regions.iter().position(|r| r.start_addr == start_addr)
+    {
+        Some(i) => i,
+        None => return Err("Region was not found")
+    };
+
+    let region = regions.remove(index);
+    drop(regions);
+
+    release_pages(region.start_addr, region.end_addr);
+    return Ok(());
+}
+
+/// change size of region, pages beyond new end are freed
+pub fn resize_region(start_addr: u32, size: u32) -> Result<(), &'static str>
+{
+    if size % PAGE_SIZE != 0
+    {
+        return Err("Region is not page aligned");
+    }
+
+    let end_addr = match start_addr.checked_add(size)
+    {
+        Some(addr) => addr,
+        None => return Err("Region is out of address space")
+    };
+
+    let mut regions = VM_REGIONS.lock();
+
+    let index = match regions.iter().position(|r| r.start_addr == start_addr)
+    {
+        Some(i) => i,
+        None => return Err("Region was not found")
+    };
+
+    if regions.iter().any(|r| r.start_addr != start_addr && start_addr < r.end_addr && r.start_addr < end_addr)
+    {
+        return Err("Region overlaps with other region");
+    }
+
+    let prev_end_addr = regions[index].end_addr;
+    regions[index].end_addr = end_addr;
+    drop(regions);
+
+    if end_addr < prev_end_addr
+    {
+        release_pages(end_addr, prev_end_addr);
+    }
+
+    return Ok(());
+}
+
+/// free populated pages of region, region is kept
+pub fn release_region_pages(start_addr: u32) -> Result<(), &'static str>
+{
+    let region = match VM_REGIONS.lock().iter().find(|r| r.start_addr == start_addr)
+    {
+        Some(region) => *region,
+        None => return Err("Region was not found")
+    };
+
+    release_pages(region.start_addr, region.end_addr);
+    return Ok(());
+}
+
+pub fn find_region(addr: u32) -> Option<VmRegion>
+{
+    return VM_REGIONS.lock().iter().find(|r| r.contains(addr)).copied();
+}
+
+/// map a zeroed page if the fault is an access to unpopulated page of a region
+pub fn handle_page_fault(fault_addr: u32, err: PageFaultError) -> Result<(), &'static str>
+{
+    if err.is_reserved()
+    {
+        return Err("Reserved bit is set in paging structure");
+    }
+
+    // kernel mapping was changed after the address space was created
+    if PAGING.lock().sync_kernel_pde(fault_addr)
+    {
+        return Ok(());
+    }
+
+    // first write to page shared by copy-on-write, cloned pages can be outside regions
+    if err.is_present() && err.is_write() && PAGING.lock().copy_on_write(fault_addr).is_ok()
+    {
+        return Ok(());
+    }
+
+    let region = match find_region(fault_addr)
+    {
+        Some(region) => region,
+        None => return Err("Address is not in any region")
+    };
+
+    if err.is_user() && region.flags & PAGE_FLAGS_USER == 0
+    {
+        return Err("User access to kernel region");
+    }
+
+    if err.is_write() && region.flags & PAGE_FLAGS_WRITABLE == 0
+    {
+        return Err("Write access to read-only region");
+    }
+
+    if err.is_instruction_fetch() && region.flags & PAGE_FLAGS_NO_EXECUTE != 0
+    {
+        return Err("Instruction fetch from non-executable region");
+    }
+
+    if err.is_present()
+    {
+        return Err("Access violates page protection");
+    }
+
+    return map_zeroed_page(fault_addr & !(PAGE_SIZE - 1), region.flags);
+}
+
+pub fn info()
+{
+    for region in VM_REGIONS.lock().iter()
+    {
+        let mut pages = 0;
+        let paging = PAGING.lock();
+
+        for addr in (region.start_addr..region.end_addr).step_by(PAGE_SIZE as usize)
+        {
+            if paging.translate(addr).is_some()
+            {
+                pages += 1;
+            }
+        }
+
+        println!("{} resident: {}B", region, pages * PAGE_SIZE);
+    }
+}
+
+fn map_zeroed_page(page_addr: u32, flags: u32) -> Result<(), &'static str>
+{
+    // frame can be above direct map
+    let mb_info = match PHYS_MEM_MANAGER.lock().alloc_high_mem_block()
+    {
+        Some(mb_info) => mb_info,
+        None => return Err("Out of physical memory")
+    };
+
+    let mut paging = PAGING.lock();
+
+    // populated by other CPU
+    if paging.translate(page_addr).is_some()
+    {
+        PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(mb_info);
+        return Ok(());
+    }
+
+    // clear it before the page is visible, user mapping can be read-only
+    let result = paging.clear_frame(mb_info.mem_block_start_addr)
+        .and_then(|_| paging.map(page_addr, mb_info.mem_block_start_addr, flags));
+
+    if let Err(msg) = result
+    {
+        PHYS_MEM_MANAGER.lock().dealloc_single_mem_block(mb_info);
+        return Err(msg);
+    }
+
+    return Ok(());
+}
+
+// unmap populated pages in the range and free their frames
+fn release_pages(start_addr: u32, end_addr: u32)
+{
+    let mut paging = PAGING.lock();
+    let mut frames = Vec::new();
+
+    for addr in (start_addr..end_addr).step_by(PAGE_SIZE as usize)
+    {
+        if let Ok(phys_addr) = paging.unmap(addr)
+        {
+            frames.push(phys_addr);
+        }
+    }
+
+    // frames are reused only after other CPUs flushed their TLB (shootdown on unlock)
+    drop(paging);
+
+    let mut pmm = PHYS_MEM_MANAGER.lock();
+
+    for phys_addr in frames
+    {
+        let index = pmm.get_mem_block_index_from_phys_addr(phys_addr);
+
+        if let Some(mb_info) = pmm.get_mem_block(index)
+        {
+            pmm.dealloc_single_mem_block(mb_info);
+        }
+    }
+}
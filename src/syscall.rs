@@ -0,0 +1,324 @@
+// system call interface (int 0x80)
+// eax: system call number, ebx, ecx, edx, esi, edi: arguments
+// result is returned in eax, negative value is error code
+
+use core::slice;
+
+use alloc::{string::String, vec::Vec};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+use crate::{arch::{asm, int::KEYBUF, isr::{self, InterruptFrame}, pit, sgm, usermode}, console::ascii, device::keyboard::{Keyboard, KeyLayout}, fs::vfs::{VfsError, VFS}, mem::{PAGING, vm::{self, PageFaultError, PF_ERR_P, PF_ERR_U, PF_ERR_W}}, print, util::logger::*};
+
+pub const SYSCALL_VECTOR: u32 = 0x80;
+
+pub const SYS_EXIT: u32 = 1;
+pub const SYS_WRITE: u32 = 2;
+pub const SYS_READ_KEY: u32 = 3;
+pub const SYS_OPEN: u32 = 4;
+pub const SYS_READ: u32 = 5;
+pub const SYS_CLOSE: u32 = 6;
+pub const SYS_SBRK: u32 = 7;
+pub const SYS_GET_TICKS: u32 = 8;
+
+const SYSCALL_MAX: usize = 9;
+
+// error codes
+pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const EBADF: i32 = 9;
+pub const EAGAIN: i32 = 11;
+pub const ENOMEM: i32 = 12;
+pub const EFAULT: i32 = 14;
+pub const EINVAL: i32 = 22;
+pub const EMFILE: i32 = 24;
+pub const ENOSYS: i32 = 38;
+
+// 0, 1, 2 are console, files opened by user start from here
+const FD_STDIN: u32 = 0;
+const FD_STDOUT: u32 = 1;
+const FD_STDERR: u32 = 2;
+const FD_FILE_BASE: u32 = 3;
+
+const PATH_MAX_LEN: u32 = 255;
+
+type SyscallHandler = fn(&SyscallArgs) -> Result<u32, i32>;
+
+const SYSCALL_TABLE: [Option<SyscallHandler>; SYSCALL_MAX] =
+[
+    None,
+    Some(sys_exit),
+    Some(sys_write),
+    Some(sys_read_key),
+    Some(sys_open),
+    Some(sys_read),
+    Some(sys_close),
+    Some(sys_sbrk),
+    Some(sys_get_ticks)
+];
+
+lazy_static!
+{
+    // key state of user programs is separated from system console
+    static ref USER_KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new(KeyLayout::AnsiUs104));
+    // VFS file descriptors opened by user program
+    static ref USER_FILES: Mutex<Vec<Option<usize>>> = Mutex::new(Vec::new());
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SyscallArgs
+{
+    pub num: u32,
+    pub arg1: u32,
+    pub arg2: u32,
+    pub arg3: u32,
+    pub arg4: u32,
+    pub arg5: u32
+}
+
+impl SyscallArgs
+{
+    fn from_frame(frame: &InterruptFrame) -> SyscallArgs
+    {
+        return SyscallArgs { num: frame.eax, arg1: frame.ebx, arg2: frame.ecx, arg3: frame.edx, arg4: frame.esi, arg5: frame.edi };
+    }
+}
+
+pub fn init()
+{
+    isr::register_handler(SYSCALL_VECTOR, syscall_handler);
+    sgm::set_user_gate(SYSCALL_VECTOR);
+    log_info("System call initialized");
+}
+
+/// close files which are left opened by user program
+pub fn release_user_files()
+{
+    let fds: Vec<usize> = USER_FILES.lock().drain(..).filter_map(|fd| fd).collect();
+
+    for fd in fds
+    {
+        let _ = VFS.lock().close(fd);
+    }
+}
+
+fn syscall_handler(frame: &mut InterruptFrame)
+{
+    if !frame.is_from_user()
+    {
+        frame.eax = (-EPERM) as u32;
+        return;
+    }
+
+    let args = SyscallArgs::from_frame(frame);
+
+    let result = match SYSCALL_TABLE.get(args.num as usize)
+    {
+        Some(Some(handler)) => handler(&args),
+        _ => Err(ENOSYS)
+    };
+
+    frame.eax = match result
+    {
+        Ok(value) => value,
+        Err(code) => (-code) as u32
+    };
+}
+
+// check that ring 3 can access whole buffer
+fn check_user_buf(addr: u32, len: u32, need_write: bool) -> Result<(), i32>
+{
+    if len == 0
+    {
+        return Ok(());
+    }
+
+    let end_addr = match addr.checked_add(len - 1)
+    {
+        Some(end_addr) => end_addr,
+        None => return Err(EFAULT)
+    };
+
+    let mut page_addr = addr & !0xfff;
+
+    loop
+    {
+        if !PAGING.lock().is_user_page(page_addr, need_write)
+        {
+            let is_present = PAGING.lock().translate(page_addr).is_some();
+            let err = PageFaultError(PF_ERR_U | if need_write { PF_ERR_W } else { 0 } | if is_present { PF_ERR_P } else { 0 });
+
+            // populate on-demand pages and copy shared pages like a fault from the program would do
+            if vm::handle_page_fault(page_addr, err).is_err() || !PAGING.lock().is_user_page(page_addr, need_write)
+            {
+                return Err(EFAULT);
+            }
+        }
+
+        if page_addr >= end_addr & !0xfff
+        {
+            break;
+        }
+
+        page_addr += 0x1000;
+    }
+
+    return Ok(());
+}
+
+fn user_slice(addr: u32, len: u32) -> Result<&'static [u8], i32>
+{
+    check_user_buf(addr, len, false)?;
+    return Ok(unsafe { slice::from_raw_parts(addr as *const u8, len as usize) });
+}
+
+fn user_slice_mut(addr: u32, len: u32) -> Result<&'static mut [u8], i32>
+{
+    check_user_buf(addr, len, true)?;
+    return Ok(unsafe { slice::from_raw_parts_mut(addr as *mut u8, len as usize) });
+}
+
+fn get_vfs_fd(fd: u32) -> Result<usize, i32>
+{
+    if fd < FD_FILE_BASE
+    {
+        return Err(EBADF);
+    }
+
+    match USER_FILES.lock().get((fd - FD_FILE_BASE) as usize)
+    {
+        Some(Some(vfs_fd)) => return Ok(*vfs_fd),
+        _ => return Err(EBADF)
+    }
+}
+
+/// exit(code)
+fn sys_exit(args: &SyscallArgs) -> Result<u32, i32>
+{
+    usermode::exit(args.arg1);
+}
+
+/// write(fd, buf, len) -> written size
+fn sys_write(args: &SyscallArgs) -> Result<u32, i32>
+{
+    if args.arg1 != FD_STDOUT && args.arg1 != FD_STDERR
+    {
+        return Err(EBADF);
+    }
+
+    let buf = user_slice(args.arg2, args.arg3)?;
+
+    for c in buf
+    {
+        print!("{}", *c as char);
+    }
+
+    return Ok(buf.len() as u32);
+}
+
+/// read_key() -> ascii code, doesn't block
+fn sys_read_key(_args: &SyscallArgs) -> Result<u32, i32>
+{
+    loop
+    {
+        let is_int_enabled = asm::disable_int();
+        let key = KEYBUF.lock().get();
+        asm::restore_int(is_int_enabled);
+
+        let key = match key
+        {
+            Ok(key) => key,
+            Err(_) => return Err(EAGAIN)
+        };
+
+        if let Some((event, modifier_keys_state)) = USER_KEYBOARD.lock().input(key)
+        {
+            if let Some(asc) = ascii::key_event_to_ascii_code(event, modifier_keys_state)
+            {
+                return Ok(asc as u32);
+            }
+        }
+    }
+}
+
+/// open(path, path_len) -> fd
+fn sys_open(args: &SyscallArgs) -> Result<u32, i32>
+{
+    if args.arg2 == 0 || args.arg2 > PATH_MAX_LEN
+    {
+        return Err(EINVAL);
+    }
+
+    let path = match core::str::from_utf8(user_slice(args.arg1, args.arg2)?)
+    {
+        Ok(path) => String::from(path),
+        Err(_) => return Err(EINVAL)
+    };
+
+    let vfs_fd = match VFS.lock().open(&path)
+    {
+        Ok(fd) => fd,
+        Err(VfsError::TooManyOpenFiles) => return Err(EMFILE),
+        Err(VfsError::OutOfMemory) => return Err(ENOMEM),
+        Err(_) => return Err(ENOENT)
+    };
+
+    let mut files = USER_FILES.lock();
+
+    if let Some(i) = files.iter().position(|fd| fd.is_none())
+    {
+        files[i] = Some(vfs_fd);
+        return Ok(i as u32 + FD_FILE_BASE);
+    }
+
+    files.push(Some(vfs_fd));
+    return Ok(files.len() as u32 - 1 + FD_FILE_BASE);
+}
+
+/// read(fd, buf, len) -> read size, 0 at end of file
+fn sys_read(args: &SyscallArgs) -> Result<u32, i32>
+{
+    if args.arg1 == FD_STDIN
+    {
+        // use read_key for console input
+        return Err(EINVAL);
+    }
+
+    let vfs_fd = get_vfs_fd(args.arg1)?;
+    let buf = user_slice_mut(args.arg2, args.arg3)?;
+
+    match VFS.lock().read(vfs_fd, buf)
+    {
+        Ok(size) => return Ok(size as u32),
+        Err(_) => return Err(EBADF)
+    }
+}
+
+/// close(fd)
+fn sys_close(args: &SyscallArgs) -> Result<u32, i32>
+{
+    let vfs_fd = get_vfs_fd(args.arg1)?;
+    USER_FILES.lock()[(args.arg1 - FD_FILE_BASE) as usize] = None;
+
+    match VFS.lock().close(vfs_fd)
+    {
+        Ok(()) => return Ok(0),
+        Err(_) => return Err(EBADF)
+    }
+}
+
+/// sbrk(increment) -> previous break
+fn sys_sbrk(args: &SyscallArgs) -> Result<u32, i32>
+{
+    match usermode::sbrk(args.arg1 as i32)
+    {
+        Ok(prev_brk) => return Ok(prev_brk),
+        Err(_) => return Err(ENOMEM)
+    }
+}
+
+/// get_ticks() -> lower 32 bits of PIT ticks, never fails
+fn sys_get_ticks(_args: &SyscallArgs) -> Result<u32, i32>
+{
+    return Ok(pit::get_ticks() as u32);
+}
@@ -1,5 +1,7 @@
 use multiboot2::{BootInformation, MemoryArea, ModuleTag};
 
+use crate::mem::virt_to_phys;
+
 pub fn get_total_mem_size(boot_info: &BootInformation) -> u64
 {
     return get_all_mem_areas(boot_info).map(|area| area.size() - 1).sum();
@@ -26,16 +28,27 @@ pub fn get_kernel_addr(boot_info: &BootInformation) -> (u64, u64)
     return (kernel_start, kernel_end);
 }
 
+/// (address, size) of the kernel ELF section loaded by bootloader
+/// address of sections not loaded by the ELF program headers (.symtab etc.) is physical
+pub fn get_elf_section(boot_info: &BootInformation, name: &str) -> Option<(u64, u64)>
+{
+    let elf_sections_tag = boot_info.elf_sections_tag()?;
+    let section = elf_sections_tag.sections().find(|s| s.name() == name)?;
+
+    return Some((section.start_address(), section.size()));
+}
+
 pub fn get_kernel_size(boot_info: &BootInformation) -> u64
 {
     let (start, end) = get_kernel_addr(boot_info);
     return end - start;
 }
 
+/// physical address range of multiboot information (loaded through direct map)
 pub fn get_multiboot_addr(boot_info: &BootInformation) -> (u64, u64)
 {
-    let multiboot_start = boot_info.start_address() as u64;
-    let multiboot_end = boot_info.end_address() as u64;
+    let multiboot_start = virt_to_phys(boot_info.start_address() as u32) as u64;
+    let multiboot_end = virt_to_phys(boot_info.end_address() as u32) as u64;
 
     return (multiboot_start, multiboot_end);
 }
@@ -50,4 +63,28 @@ pub fn get_module_tags(boot_info: &BootInformation) -> impl Iterator<Item = &Mod
 {
     let module_tag = boot_info.module_tags();
     return module_tag;
+}
+
+pub fn get_rsdt_addr(boot_info: &BootInformation) -> Option<u32>
+{
+    let rsdp_v1_tag = boot_info.rsdp_v1_tag()?;
+
+    if !rsdp_v1_tag.checksum_is_valid()
+    {
+        return None;
+    }
+
+    return Some(rsdp_v1_tag.rsdt_address() as u32);
+}
+
+pub fn get_xsdt_addr(boot_info: &BootInformation) -> Option<u64>
+{
+    let rsdp_v2_tag = boot_info.rsdp_v2_tag()?;
+
+    if !rsdp_v2_tag.checksum_is_valid()
+    {
+        return None;
+    }
+
+    return Some(rsdp_v2_tag.xsdt_address() as u64);
 }
\ No newline at end of file
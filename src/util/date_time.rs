@@ -0,0 +1,28 @@
+use core::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DateTime
+{
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8
+}
+
+impl DateTime
+{
+    pub fn new(year: u16, month: u8, day: u8, hour: u8, min: u8, sec: u8) -> DateTime
+    {
+        return DateTime { year, month, day, hour, min, sec };
+    }
+}
+
+impl fmt::Display for DateTime
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        return write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.min, self.sec);
+    }
+}
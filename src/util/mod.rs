@@ -1,4 +1,5 @@
 pub mod boot_info;
+pub mod date_time;
 pub mod size;
 pub mod logger;
 pub mod type_util;
\ No newline at end of file
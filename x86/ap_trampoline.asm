@@ -0,0 +1,110 @@
+; application processor startup code
+; copied to AP_TRAMPOLINE_ADDR (see src/arch/smp.rs) and started by SIPI in real mode
+; parameters at the end are written by BSP before each startup
+
+global ap_trampoline_start
+global ap_trampoline_end
+global ap_trampoline_cr3
+global ap_trampoline_stack
+global ap_trampoline_entry
+global ap_trampoline_cpu_index
+global ap_trampoline_nxe
+
+extern boot_cr3
+extern boot_cr4
+
+AP_TRAMPOLINE_ADDR equ 0x8000
+KERNEL_VIRT_BASE equ 0xc0000000 ; see src/mem/mod.rs
+
+%define REL(label) (AP_TRAMPOLINE_ADDR + (label - ap_trampoline_start))
+
+section .text
+
+bits 16
+ap_trampoline_start:
+    cli
+    cld
+    xor ax, ax
+    mov ds, ax
+
+    lgdt [REL(ap_trampoline_gdtr)]
+
+    mov eax, cr0
+    or eax, 0x1 ; protection enable
+    mov cr0, eax
+
+    jmp dword 0x08:REL(ap_trampoline_pm)
+
+bits 32
+ap_trampoline_pm:
+    mov ax, 0x10
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+    mov ss, ax
+
+    ; trampoline page tables of kernel.asm map this page at both addresses
+    ; paging mode (PSE or PAE) is the same as BSP
+    mov eax, cr4
+    or eax, [boot_cr4 - KERNEL_VIRT_BASE]
+    mov cr4, eax
+    mov eax, [boot_cr3 - KERNEL_VIRT_BASE]
+    mov cr3, eax
+    mov eax, cr0
+    or eax, 0x80010000 ; paging and write protect
+    mov cr0, eax
+
+    ; continue in higher half, then share page directory of BSP
+    mov eax, KERNEL_VIRT_BASE + REL(ap_trampoline_high)
+    jmp eax
+
+ap_trampoline_high:
+    ; page tables of BSP may have no-execute bit
+    cmp dword [KERNEL_VIRT_BASE + REL(ap_trampoline_nxe)], 0
+    je .load_cr3
+    mov ecx, 0xc0000080 ; EFER
+    rdmsr
+    or eax, 0x800 ; no-execute enable
+    wrmsr
+
+.load_cr3:
+    mov eax, [KERNEL_VIRT_BASE + REL(ap_trampoline_cr3)]
+    mov cr3, eax
+
+    mov esp, [KERNEL_VIRT_BASE + REL(ap_trampoline_stack)]
+    push dword 0 ; reset eflags
+    popf
+
+    xor ebp, ebp ; end of frame chain for backtrace
+    push dword [KERNEL_VIRT_BASE + REL(ap_trampoline_cpu_index)] ; 1st argument of entry
+    mov eax, [KERNEL_VIRT_BASE + REL(ap_trampoline_entry)]
+    call eax
+
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+align 8
+ap_trampoline_gdt:
+    dq 0x0000000000000000 ; null
+    dq 0x00cf9a000000ffff ; code
+    dq 0x00cf92000000ffff ; data
+
+ap_trampoline_gdtr:
+    dw 8 * 3 - 1
+    dd REL(ap_trampoline_gdt)
+
+align 4
+ap_trampoline_cr3:
+    dd 0
+ap_trampoline_stack:
+    dd 0
+ap_trampoline_entry:
+    dd 0
+ap_trampoline_cpu_index:
+    dd 0
+ap_trampoline_nxe:
+    dd 0
+ap_trampoline_end:
\ No newline at end of file
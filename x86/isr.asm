@@ -0,0 +1,115 @@
+; interrupt entry stubs
+; every stub builds the same frame (see InterruptFrame in src/arch/isr.rs)
+; and passes it to isr_handler
+
+global isr_stub_table
+global double_fault_task_entry
+
+extern isr_handler
+extern double_fault_task
+
+section .text
+bits 32
+
+; CPU doesn't push error code, push dummy
+%macro ISR_NO_ERR_CODE 1
+isr_stub_%1:
+    push dword 0
+    push dword %1
+    jmp isr_common
+%endmacro
+
+; CPU pushes error code
+%macro ISR_ERR_CODE 1
+isr_stub_%1:
+    push dword %1
+    jmp isr_common
+%endmacro
+
+isr_common:
+    pushad
+    push ds
+    push es
+    push fs
+    push gs
+
+    mov ax, 0x10 ; kernel data segment
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov ax, 0x18 ; per-CPU data segment
+    mov gs, ax
+    cld
+
+    push esp ; 1st argument of isr_handler (&mut InterruptFrame)
+    call isr_handler
+    add esp, 4
+
+    pop gs
+    pop fs
+    pop es
+    pop ds
+    popad
+    add esp, 8 ; vector and error code
+    iretd
+
+; double fault task (entered through task gate, see sgm::enable_double_fault_task)
+; CPU pushes error code on the stack of the double fault TSS
+double_fault_task_entry:
+    call double_fault_task ; 1st argument is the error code
+.halt:
+    cli
+    hlt
+    jmp .halt
+
+; exceptions
+ISR_NO_ERR_CODE 0
+ISR_NO_ERR_CODE 1
+ISR_NO_ERR_CODE 2
+ISR_NO_ERR_CODE 3
+ISR_NO_ERR_CODE 4
+ISR_NO_ERR_CODE 5
+ISR_NO_ERR_CODE 6
+ISR_NO_ERR_CODE 7
+ISR_ERR_CODE    8
+ISR_NO_ERR_CODE 9
+ISR_ERR_CODE    10
+ISR_ERR_CODE    11
+ISR_ERR_CODE    12
+ISR_ERR_CODE    13
+ISR_ERR_CODE    14
+ISR_NO_ERR_CODE 15
+ISR_NO_ERR_CODE 16
+ISR_ERR_CODE    17
+ISR_NO_ERR_CODE 18
+ISR_NO_ERR_CODE 19
+ISR_NO_ERR_CODE 20
+ISR_ERR_CODE    21
+ISR_NO_ERR_CODE 22
+ISR_NO_ERR_CODE 23
+ISR_NO_ERR_CODE 24
+ISR_NO_ERR_CODE 25
+ISR_NO_ERR_CODE 26
+ISR_NO_ERR_CODE 27
+ISR_NO_ERR_CODE 28
+ISR_ERR_CODE    29
+ISR_ERR_CODE    30
+ISR_NO_ERR_CODE 31
+
+; interrupts
+%assign i 32
+%rep 224
+isr_stub_%+i:
+    push dword 0
+    push dword i
+    jmp isr_common
+%assign i i+1
+%endrep
+
+section .rodata
+isr_stub_table:
+%assign i 0
+%rep 256
+    dd isr_stub_%+i
+%assign i i+1
+%endrep
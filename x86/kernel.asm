@@ -1,14 +1,184 @@
 global start
+global boot_cr3
+global boot_cr4
 
-section .text
+KERNEL_VIRT_BASE equ 0xc0000000 ; see src/mem/mod.rs
+BOOT_PDE_CNT equ 192 ; 768MiB (direct map) by 4MiB pages
+BOOT_PAE_PDE_CNT equ 384 ; 768MiB (direct map) by 2MiB pages
+BOOT_STACK_SIZE equ 0x10000
+
+CPUID_FEATURE_PAE equ 0x40 ; edx of leaf 1
+MB2_TAG_TYPE_END equ 0
+MB2_TAG_TYPE_CMDLINE equ 1
+CR4_PSE equ 0x10
+CR4_PAE equ 0x20
+
+%define PHYS(label) (label - KERNEL_VIRT_BASE)
+
+; entered by bootloader with paging disabled, linked at physical address
+section .boot.text
 bits 32
 start:
+    ; cpuid overwrites multiboot magic and boot info address
+    mov esi, eax
+    mov ebp, ebx
+
+    ; trampoline page tables
+    ; low memory is identity mapped until jumping to higher half, same memory is mapped at KERNEL_VIRT_BASE
+    ; paging mode is selected here, kernel keeps it (see src/mem/paging.rs)
+    ; PAE is used if CPU supports it, "nopae" on kernel command line keeps 2-level paging
+    lea edi, [ebp + 8] ; first tag of boot information
+
+.find_cmdline:
+    mov eax, [edi]
+    cmp eax, MB2_TAG_TYPE_END
+    je .check_pae
+    cmp eax, MB2_TAG_TYPE_CMDLINE
+    je .scan_cmdline
+    mov eax, [edi + 4] ; tag size, tags are 8 bytes aligned
+    add eax, 7
+    and eax, ~7
+    add edi, eax
+    jmp .find_cmdline
+
+.scan_cmdline:
+    add edi, 8 ; null terminated string
+    mov edx, edi
+
+.scan_next:
+    cmp byte [edi], 0
+    je .check_pae
+    ; option starts at beginning or after a space
+    cmp edi, edx
+    je .compare
+    cmp byte [edi - 1], ' '
+    jne .scan_skip
+
+.compare:
+    mov ebx, PHYS(nopae_option)
+    xor ecx, ecx
+
+.compare_char:
+    mov al, [ebx + ecx]
+    test al, al
+    jz .compare_end
+    cmp al, [edi + ecx]
+    jne .scan_skip
+    inc ecx
+    jmp .compare_char
+
+.compare_end:
+    ; and ends at end of string or a space
+    mov al, [edi + ecx]
+    test al, al
+    jz .legacy
+    cmp al, ' '
+    je .legacy
+
+.scan_skip:
+    inc edi
+    jmp .scan_next
+
+.check_pae:
+    mov eax, 1
+    cpuid
+    test edx, CPUID_FEATURE_PAE
+    jz .legacy
+
+    ; PAE: page directory per 1GiB, first one for low memory and last one for kernel
+    mov edi, PHYS(boot_page_directory)
+    mov ebx, PHYS(boot_pae_kernel_page_directory)
+    mov edx, 0x83 ; present, writable, 2MiB page
+    xor ecx, ecx
+
+.map_pae_pde:
+    mov [edi + ecx * 8], edx
+    mov [ebx + ecx * 8], edx
+    add edx, 0x200000
+    inc ecx
+    cmp ecx, BOOT_PAE_PDE_CNT
+    jne .map_pae_pde
+
+    ; PDPTE has no R/W and U/S flags
+    mov edx, PHYS(boot_pdpt)
+    mov dword [edx], PHYS(boot_page_directory) + 0x1
+    mov dword [edx + 3 * 8], PHYS(boot_pae_kernel_page_directory) + 0x1
+
+    mov dword [PHYS(boot_cr3)], PHYS(boot_pdpt)
+    mov dword [PHYS(boot_cr4)], CR4_PAE
+    jmp .enable_paging
+
+.legacy:
+    mov edi, PHYS(boot_page_directory)
+    mov edx, 0x83 ; present, writable, 4MiB page
+    xor ecx, ecx
+
+.map_pde:
+    mov [edi + ecx * 4], edx
+    mov [edi + ecx * 4 + (KERNEL_VIRT_BASE >> 22) * 4], edx
+    add edx, 0x400000
+    inc ecx
+    cmp ecx, BOOT_PDE_CNT
+    jne .map_pde
+
+    mov dword [PHYS(boot_cr3)], PHYS(boot_page_directory)
+    mov dword [PHYS(boot_cr4)], CR4_PSE
+
+.enable_paging:
+    mov ecx, cr4
+    or ecx, [PHYS(boot_cr4)]
+    mov cr4, ecx
+
+    mov ecx, [PHYS(boot_cr3)]
+    mov cr3, ecx
+
+    mov ecx, cr0
+    or ecx, 0x80000000 ; paging
+    mov cr0, ecx
+
+    mov ecx, higher_half
+    jmp ecx
+
+section .text
+higher_half:
+    mov esp, boot_stack_top
+
     ; reset eflags
     push $0 ; push 0x00000000
     popf
 
-    push ebx ; 1st argument of kernel_main
+    mov eax, esi
+    mov ebx, ebp
+    xor ebp, ebp ; end of frame chain for backtrace
+
+    push ebx ; 1st argument of kernel_main (physical address)
     push eax ; 2nd argument of kernel_main
     ; call rust code
     extern kernel_main
-    call kernel_main
\ No newline at end of file
+    call kernel_main
+
+section .data
+; trampoline page tables and paging mode bits of cr4, also used by x86/ap_trampoline.asm
+align 4
+boot_cr3:
+    dd 0
+boot_cr4:
+    dd 0
+nopae_option:
+    db "nopae", 0
+
+section .bss
+align 4096
+boot_page_directory:
+    resb 4096
+boot_pae_kernel_page_directory:
+    resb 4096
+
+align 32
+boot_pdpt:
+    resb 32
+
+align 16
+boot_stack:
+    resb BOOT_STACK_SIZE
+boot_stack_top:
@@ -0,0 +1,65 @@
+; switch between kernel and ring 3
+; see src/arch/usermode.rs
+
+global user_mode_enter
+global user_mode_exit
+
+section .text
+bits 32
+
+; u32 user_mode_enter(u32 entry, u32 user_stack, u32 *kernel_context)
+; returns the value passed to user_mode_exit
+user_mode_enter:
+    push ebp
+    mov ebp, esp
+    push ebx
+    push esi
+    push edi
+
+    ; save kernel stack to return to
+    mov eax, [ebp + 16]
+    mov [eax], esp
+
+    mov ecx, [ebp + 8]  ; entry
+    mov edx, [ebp + 12] ; user stack
+
+    mov ax, 0x33 ; user data segment
+    mov ds, ax
+    mov es, ax
+    mov fs, ax
+    mov gs, ax
+
+    push dword 0x33  ; ss
+    push edx         ; esp
+    push dword 0x202 ; eflags (IF)
+    push dword 0x2b  ; cs
+    push ecx         ; eip
+
+    xor eax, eax
+    xor ebx, ebx
+    xor ecx, ecx
+    xor edx, edx
+    xor esi, esi
+    xor edi, edi
+    xor ebp, ebp
+    iretd
+
+; void user_mode_exit(u32 *kernel_context, u32 value)
+; called in kernel mode, never returns
+user_mode_exit:
+    mov ecx, [esp + 4]
+    mov eax, [esp + 8]
+    mov esp, [ecx]
+
+    mov dx, 0x10 ; kernel data segment
+    mov ds, dx
+    mov es, dx
+    mov fs, dx
+    mov dx, 0x18 ; per-CPU data segment
+    mov gs, dx
+
+    pop edi
+    pop esi
+    pop ebx
+    pop ebp
+    ret
\ No newline at end of file